@@ -0,0 +1,361 @@
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use zeroize::Zeroizing;
+
+use crate::crypto;
+use crate::error::{Error, Result};
+use crate::keys::{KeyPair, MasterKey};
+
+// socket of the key agent, inside the data directory
+pub static SOCKET_FILE: &str = "agent.sock";
+// how long the agent keeps the key without being asked for anything, by default
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+// how long a client has to send its request, and to wait for the answer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/**
+ * path of the socket of the key agent for data_dir
+ */
+pub fn socket_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(SOCKET_FILE)
+}
+
+/**
+ * The master key held by a running key agent. The private key stays in the agent,
+ * which unwraps the data keys of the stores for this process
+ */
+pub struct AgentKey {
+    path: PathBuf,
+    public_key: [u8; 32],
+}
+
+impl AgentKey {
+    /**
+     * Connects to the agent of data_dir, None if there isn't one running
+     */
+    pub fn connect(data_dir: &Path) -> Result<Option<AgentKey>> {
+        let path = socket_path(data_dir);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let public_key = match request(&path, "public") {
+            Ok(answer) => crypto::decode_hex(&answer)
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| Error::Corrupt(format!("the key agent on {} sent an invalid public key", path.display())))?,
+            // a socket left behind by an agent that stopped
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Ok(Some(AgentKey { path, public_key }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /**
+     * Asks the agent to forget the key and exit
+     */
+    pub fn stop(&self) -> Result<()> {
+        request(&self.path, "stop").map(|_| ())
+    }
+}
+
+impl MasterKey for AgentKey {
+    fn public_key(&self) -> [u8; 32] {
+        self.public_key
+    }
+
+    fn unwrap_key(&self, wrapped: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        let answer = Zeroizing::new(request(&self.path, &format!("unwrap {}", crypto::encode_hex(wrapped)))?);
+        crypto::decode_hex(&answer)
+            .map(Zeroizing::new)
+            .map_err(|_| Error::Corrupt(format!("the key agent on {} sent an invalid key", self.path.display())))
+    }
+
+    fn keypair(&self) -> Option<&KeyPair> {
+        None
+    }
+}
+
+/**
+ * sends a request of one line to the agent on path, returning what follows `ok` in its answer
+ */
+#[cfg(unix)]
+fn request(path: &Path, line: &str) -> Result<String> {
+    let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.write_all(format!("{}\n", line).as_bytes())?;
+    let mut answer = String::new();
+    BufReader::new(&stream).read_line(&mut answer)?;
+    let answer = answer.trim_end();
+    match answer.split_once(' ').unwrap_or((answer, "")) {
+        ("ok", rest) => Ok(rest.to_string()),
+        ("err", message) => Err(Error::Crypto(format!("the key agent refused: {}", message))),
+        _ => Err(Error::Corrupt(format!("unexpected answer from the key agent on {}", path.display()))),
+    }
+}
+
+#[cfg(not(unix))]
+fn request(_path: &Path, _line: &str) -> Result<String> {
+    Err(Error::Config("the key agent needs unix domain sockets".to_string()))
+}
+
+#[cfg(unix)]
+pub use server::Agent;
+
+#[cfg(unix)]
+mod server {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::time::Instant;
+
+    use super::*;
+    use crate::secret::Locked;
+
+    /**
+     * The key agent, like ssh-agent: a process holding the unlocked private key, so the passphrase
+     * is typed once. It answers on a Unix domain socket only its user can connect to, one line
+     * per request: `public` gives the public key, `unwrap HEX` decrypts the data key of a store,
+     * and `stop` makes it exit. Answers are `ok`, followed by the result, or `err` and a message.
+     * After the idle timeout without requests it forgets the key and exits
+     */
+    pub struct Agent {
+        listener: UnixListener,
+        path: PathBuf,
+        keypair: Locked<KeyPair>,
+        idle_timeout: Duration,
+        // false in the process that started an agent in the background, the socket is not its own
+        serving: bool,
+    }
+
+    impl Agent {
+        /**
+         * Binds the socket of data_dir, copying keypair to locked memory. Another agent running
+         * there is a Lock error, the socket of one that stopped is replaced
+         */
+        pub fn bind(data_dir: &Path, keypair: &KeyPair, idle_timeout: Duration) -> Result<Agent> {
+            let path = socket_path(data_dir);
+            if path.exists() {
+                if UnixStream::connect(&path).is_ok() {
+                    return Err(Error::Lock(format!("a key agent is already running on {}", path.display())));
+                }
+                std::fs::remove_file(&path)?;
+            }
+            std::fs::create_dir_all(data_dir)?;
+            // only the user can connect, from the moment the socket exists: it's bound in a folder
+            // only the user can enter, and moved in place once others can't open it
+            let private = data_dir.join(format!(".agent-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&private);
+            std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+            let bound = private.join(SOCKET_FILE);
+            let listener = UnixListener::bind(&bound).and_then(|listener| {
+                std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(0o600))?;
+                std::fs::rename(&bound, &path)?;
+                Ok(listener)
+            });
+            let _ = std::fs::remove_dir_all(&private);
+            Ok(Agent {
+                listener: listener?,
+                path,
+                keypair: Locked::take(&mut keypair.clone()),
+                idle_timeout,
+                serving: true,
+            })
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.path
+        }
+
+        /**
+         * Serves in a child process detached from the terminal, returning its pid.
+         * The key is forgotten in this process
+         */
+        pub fn spawn(mut self) -> Result<u32> {
+            match unsafe { libc::fork() } {
+                -1 => Err(std::io::Error::last_os_error().into()),
+                0 => {
+                    unsafe {
+                        libc::setsid();
+                        let null = libc::open(c"/dev/null".as_ptr(), libc::O_RDWR);
+                        if null >= 0 {
+                            libc::dup2(null, 0);
+                            libc::dup2(null, 1);
+                            libc::dup2(null, 2);
+                        }
+                    }
+                    // locked pages aren't locked in a forked process anymore
+                    let mut keypair = (*self.keypair).clone();
+                    self.keypair = Locked::take(&mut keypair);
+                    let code = match self.serve() {
+                        Ok(()) => 0,
+                        Err(e) => e.exit_code(),
+                    };
+                    std::process::exit(code);
+                }
+                pid => {
+                    self.serving = false;
+                    Ok(pid as u32)
+                }
+            }
+        }
+
+        /**
+         * Answers requests until none came for the idle timeout, or one asks to stop
+         */
+        pub fn serve(self) -> Result<()> {
+            let mut last_request = Instant::now();
+            loop {
+                let remaining = self.idle_timeout.saturating_sub(last_request.elapsed());
+                if remaining.is_zero() {
+                    return Ok(());
+                }
+                let mut ready = libc::pollfd {
+                    fd: self.listener.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                let timeout = remaining.as_millis().clamp(1, i32::MAX as u128) as i32;
+                match unsafe { libc::poll(&mut ready, 1, timeout) } {
+                    -1 => {
+                        let e = std::io::Error::last_os_error();
+                        if e.kind() != std::io::ErrorKind::Interrupted {
+                            return Err(e.into());
+                        }
+                    }
+                    0 => {}
+                    _ => {
+                        let (stream, _) = self.listener.accept()?;
+                        // the permissions of the socket keep others out, unless they were changed
+                        if peer_uid(&stream).ok() != Some(unsafe { libc::geteuid() }) {
+                            continue;
+                        }
+                        last_request = Instant::now();
+                        // a client going away or sending garbage doesn't stop the agent
+                        if let Ok(true) = self.answer(stream) {
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+
+        /**
+         * answers the request on stream, true if it asked to stop
+         */
+        fn answer(&self, stream: UnixStream) -> Result<bool> {
+            stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+            stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+            let mut line = Zeroizing::new(String::new());
+            BufReader::new(&stream).read_line(&mut line)?;
+            let (command, argument) = line.trim_end().split_once(' ').unwrap_or((line.trim_end(), ""));
+            let answer = Zeroizing::new(match command {
+                "public" => format!("ok {}", crypto::encode_hex(&self.keypair.0)),
+                "unwrap" => match crypto::decode_hex(argument) {
+                    Ok(wrapped) => match crypto::decrypt(&self.keypair.1, &wrapped).map(Zeroizing::new) {
+                        Ok(unwrapped) => format!("ok {}", crypto::encode_hex(&unwrapped)),
+                        Err(_) => "err it can't be decrypted with this key".to_string(),
+                    },
+                    Err(_) => "err not hex encoded".to_string(),
+                },
+                "stop" => "ok".to_string(),
+                _ => format!("err unknown request '{}'", command),
+            });
+            (&stream).write_all(format!("{}\n", answer.as_str()).as_bytes())?;
+            Ok(command == "stop")
+        }
+    }
+
+    /**
+     * uid of the process on the other end of stream
+     */
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(super) fn peer_uid(stream: &UnixStream) -> Result<u32> {
+        let mut credentials = libc::ucred { pid: 0, uid: 0, gid: 0 };
+        let mut size = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                (&mut credentials as *mut libc::ucred).cast(),
+                &mut size,
+            )
+        };
+        match result {
+            0 => Ok(credentials.uid),
+            _ => Err(std::io::Error::last_os_error().into()),
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub(super) fn peer_uid(stream: &UnixStream) -> Result<u32> {
+        let (mut uid, mut gid) = (0, 0);
+        match unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } {
+            0 => Ok(uid),
+            _ => Err(std::io::Error::last_os_error().into()),
+        }
+    }
+
+    impl Drop for Agent {
+        fn drop(&mut self) {
+            if self.serving {
+                let _ = std::fs::remove_file(&self.path);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::db::Engine;
+    use crate::store::Store;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn agent_unwraps_data_keys() {
+        let data_dir = TempDir::new("agent");
+        let keypair = KeyPair::from(crypto::generate_key_pair().unwrap());
+        assert!(AgentKey::connect(&data_dir).unwrap().is_none());
+        let agent = Agent::bind(&data_dir, &keypair, Duration::from_secs(60)).unwrap();
+        // only the user can connect, and the agent checks who did
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(socket_path(&data_dir)).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let (peer, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        assert_eq!(server::peer_uid(&peer).unwrap(), unsafe { libc::geteuid() });
+        let server = std::thread::spawn(move || agent.serve());
+        assert!(matches!(
+            Agent::bind(&data_dir, &keypair, Duration::from_secs(60)),
+            Err(Error::Lock(_))
+        ));
+
+        // stores opened through the agent are the same as with the key itself
+        let key = AgentKey::connect(&data_dir).unwrap().unwrap();
+        assert_eq!(key.public_key(), keypair.0);
+        assert!(key.keypair().is_none());
+        let mut store = Store::open_with_master(&data_dir, "agent", Engine::File, false, &key).unwrap();
+        store.set("token", "secret").unwrap();
+        store.flush().unwrap();
+        drop(store);
+        let store = Store::open_with_keypair(&data_dir, "agent", Engine::File, false, &keypair).unwrap();
+        assert_eq!(store.get("token").unwrap(), Some("secret".to_string()));
+        drop(store);
+        assert!(matches!(key.unwrap_key(b"not wrapped for this key"), Err(Error::Crypto(_))));
+        key.stop().unwrap();
+        server.join().unwrap().unwrap();
+        assert!(AgentKey::connect(&data_dir).unwrap().is_none());
+
+        // an agent without requests forgets the key on its own
+        let agent = Agent::bind(&data_dir, &keypair, Duration::from_millis(100)).unwrap();
+        agent.serve().unwrap();
+        assert!(!socket_path(&data_dir).exists());
+    }
+}
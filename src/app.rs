@@ -1,26 +1,128 @@
-use std::{collections::HashMap, str::Split};
+use std::{cell::OnceCell, io::{BufRead, Write}, path::{Path, PathBuf}, time::{Duration, SystemTime}};
 use tabled::{Table, Tabled};
-#[path = "crypto.rs"]
-mod crypto;
-#[path = "db.rs"]
-mod db;
-#[path = "log.rs"]
-mod log;
+use gui_kvstore::diff::{self, Change, ChangeKind};
+use gui_kvstore::merge::{self, Conflict, Policy, Side};
+use gui_kvstore::passphrase::{self, PassphraseSource};
+use gui_kvstore::secret::{self, Locked, Zeroizing};
+use gui_kvstore::{agent, archive, audit, config, crypto, db, error, integrity, keys, log, recipients, rotate, shamir, Error, Eviction, Limits, Store};
 
-static DEFAULT_STORE: &str = "default";
+// borrows the decrypted values, so the table doesn't make copies of them
+#[derive(Tabled)]
+struct KeypairItem<'a> {
+    key: &'a str,
+    value: &'a str,
+}
+
+#[derive(Tabled)]
+struct StatsItem {
+    store: String,
+    keys: usize,
+    total_bytes: usize,
+    average_bytes: usize,
+    largest_keys: String,
+    encryption_overhead: usize,
+    hex_overhead: usize,
+    expired: usize,
+    // percent of the file a rewrite would reclaim
+    #[tabled(display_with = "format_percent")]
+    fragmentation: f64,
+}
+
+fn format_percent(percent: &f64) -> String {
+    format!("{:.1}%", percent)
+}
+
+impl StatsItem {
+    /**
+     * Breaks down the stored size of the values of one or more stores, named store together.
+     * Encrypted values are hex encoded and carry the encryption overhead, the ones of plaintext
+     * stores and the addresses of files are saved as they are. Keys are prefixed with their store in a total
+     */
+    fn from_stats(store: &str, all_stats: &[db::StoreStats]) -> StatsItem {
+        let mut value_sizes: Vec<(String, usize)> = vec![];
+        let (mut encrypted_keys, mut encoded_bytes, mut expired) = (0, 0, 0);
+        let (mut file_size, mut compacted_size) = (0, 0);
+        for stats in all_stats {
+            for (key, size, encrypted) in &stats.value_sizes {
+                let key = match stats.name == store {
+                    true => key.to_string(),
+                    false => format!("{}/{}", stats.name, key),
+                };
+                if *encrypted {
+                    encrypted_keys += 1;
+                    encoded_bytes += size / 2;
+                    value_sizes.push((key, (size / 2).saturating_sub(crypto::OVERHEAD)));
+                } else {
+                    value_sizes.push((key, *size));
+                }
+            }
+            expired += stats.expired;
+            file_size += stats.file_size;
+            compacted_size += stats.compacted_size;
+        }
+        value_sizes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        let keys = value_sizes.len();
+        let total_bytes: usize = value_sizes.iter().map(|(_, size)| size).sum();
+        let largest_keys: Vec<String> = value_sizes
+            .iter()
+            .take(3)
+            .map(|(key, size)| format!("{} ({}b)", key, size))
+            .collect();
+        let reclaimable = file_size.saturating_sub(compacted_size);
+        let fragmentation = if file_size > 0 {
+            reclaimable as f64 * 100.0 / file_size as f64
+        } else {
+            0.0
+        };
+        StatsItem {
+            store: store.to_string(),
+            keys,
+            total_bytes,
+            average_bytes: total_bytes.checked_div(keys).unwrap_or(0),
+            largest_keys: largest_keys.join(", "),
+            encryption_overhead: encrypted_keys * crypto::OVERHEAD,
+            hex_overhead: encoded_bytes,
+            expired,
+            fragmentation: (fragmentation * 10.0).round() / 10.0,
+        }
+    }
+}
 
 #[derive(Tabled)]
-struct KeypairItem {
+struct AuditItem {
+    time: String,
+    user: String,
+    operation: &'static str,
+    store: String,
     key: String,
-    value: String,
+}
+
+impl AuditItem {
+    fn from_entry(entry: &audit::Entry) -> AuditItem {
+        AuditItem {
+            time: App::format_timestamp(Some(entry.timestamp)),
+            user: entry.user.to_string(),
+            operation: entry.operation.name(),
+            store: entry.store.to_string(),
+            key: entry.key.to_string(),
+        }
+    }
 }
 
 pub struct App {
     logger: log::Logger,
     arguments: Vec<String>,
     options_arg: Vec<String>,
+    positional_args: Vec<String>,
     store_name: String,
-    keypair: ([u8; 32], [u8; 32]),
+    format: String,
+    engine: db::Engine,
+    data_dir: PathBuf,
+    // loaded by the first command that needs it, see keypair(). locked in memory and zeroed on drop,
+    // with the passphrase that unlocked it, if it's protected
+    key: OnceCell<(Locked<keys::KeyPair>, Option<Zeroizing<String>>)>,
+    // the key agent holding the key instead, when one is running
+    agent: Option<agent::AgentKey>,
 }
 
 impl App {
@@ -31,63 +133,97 @@ impl App {
             logger,
             arguments,
             options_arg: vec![],
+            positional_args: vec![],
             store_name: "default".to_string(),
-            keypair: crypto::generate_key_pair(),
+            format: "default".to_string(),
+            engine: db::Engine::File,
+            data_dir: PathBuf::new(),
+            key: OnceCell::new(),
+            agent: None,
         }
     }
 
-    pub fn init(mut self) {
+    /**
+     * Runs the command of the arguments, returning its exit status. Errors are displayed and return
+     * the code of their category, after the stores they held open are closed and unlocked
+     */
+    pub fn init(mut self) -> i32 {
+        match self.start() {
+            Ok(code) => code,
+            Err(e) => {
+                self.logger.display(format!("Error: {}", e));
+                e.exit_code()
+            }
+        }
+    }
+
+    fn start(&mut self) -> error::Result<i32> {
         let mut opts: Vec<String> = vec![];
-        // iterate over our arguments
-        for (_pos, e) in self.arguments.iter().enumerate() {
+        let mut positional: Vec<String> = vec![];
+        // iterate over our arguments, skipping the executable and the command
+        for e in self.arguments.iter().skip(1) {
             if e.starts_with("--") {
                 opts.push(e.to_owned());
+            } else {
+                positional.push(e.to_owned());
             }
         }
         //
         self.options_arg = opts;
-        let debug_opt = self.get_logger_debug_from_options(false);
-        self.store_name = self.get_store_from_options(DEFAULT_STORE.to_string());
+        self.positional_args = positional;
+        // the config file and environment give the defaults, flags override them
+        let mut config = config::Config::load(self.get_option_value("--data-dir="))?;
+        config.apply_flags(&self.options_arg)?;
+        let debug_opt = config.debug;
+        self.store_name = config.store;
+        self.format = config.format;
+        self.engine = config.engine;
+        self.data_dir = config.data_dir;
         self.logger.toggle_debug(debug_opt);
-        //
-        let kp: ([u8; 32], [u8; 32]);
-        let key_path = App::get_data_dir().join("kvstore.key");
-        let key_file_exists = key_path.exists();
-        //
-        if key_file_exists {
-            self.logger.debug("key file already exists".to_string());
-            let keys_file_contents: String = std::fs::read_to_string(key_path).unwrap();
-            let mut keys_split: Split<&str> = keys_file_contents.split("\t");
-            let public = &crypto::decode_hex(keys_split.next().unwrap()).unwrap()[..];
-            let private = &crypto::decode_hex(keys_split.next().unwrap()).unwrap()[..];
-
-            self.logger.debug(format!("Private bytes: {:?}", private));
-            self.logger.debug(format!("Public bytes: {:?}", public));
-
-            kp = (
-                public.try_into().expect("incorrect private length"),
-                private.try_into().expect("incorrect public length"),
-            );
-        } else {
+        if let Some(file) = config.file {
             self.logger
-                .debug("key doesn't exist, creating...".to_string());
-            kp = crypto::generate_key_pair();
-            //
-            self.logger.debug(format!("Public bytes: {:?}", &kp.0));
-            self.logger.debug(format!("Private bytes: {:?}", &kp.1));
-            //
-            let public = crypto::encode_hex(&kp.0);
-            let private = crypto::encode_hex(&kp.1);
-            //
-            self.logger.debug(format!("Private Hex: {}", private));
-            self.logger.debug(format!("Public Hex: {}", public));
-            //
-            let keypair_content = format!("{}\t{}", public, private);
-            let _ = std::fs::write(key_path, keypair_content);
-        }
-        self.keypair = kp;
+                .debug(format!("config file: {}", file.display()));
+        }
+        self.logger
+            .debug(format!("data dir: {}", self.data_dir.display()));
+        // every command runs in a process of its own, so with the memory engine writes are gone right after
+        if self.engine == db::Engine::Memory {
+            self.logger.display(
+                "Using the memory engine: stores start empty and nothing is saved when the command ends".to_string(),
+            );
+        }
         //
-        self.store_name = DEFAULT_STORE.to_string();
+        // the key, and the values decrypted with it, shouldn't end up in a core dump
+        if !secret::disable_core_dumps() {
+            self.logger
+                .debug("core dumps couldn't be disabled".to_string());
+        }
+        // an imported or rebuilt key replaces the key file, there's nothing to unlock before
+        if self.arguments.get(1).map(|a| a.as_str()) == Some("--key") {
+            match self.positional_args.first().map(|a| a.as_str()) {
+                Some("import") => {
+                    self.import_key()?;
+                    return Ok(0);
+                }
+                Some("combine") => {
+                    self.combine_key()?;
+                    return Ok(0);
+                }
+                _ => {}
+            }
+        }
+        // with a key agent running, the private key stays in the agent, unless the command needs it
+        if !self.needs_private_key() && !self.options_arg.iter().any(|o| o == "--no-agent") {
+            match agent::AgentKey::connect(&self.get_data_dir()) {
+                Ok(Some(key)) => {
+                    self.logger
+                        .debug(format!("using the key agent on {}", key.path().display()));
+                    self.agent = Some(key);
+                }
+                Ok(None) => {}
+                Err(e) => self.logger.debug(format!("the key agent can't be used: {}", e)),
+            }
+        }
         // another way to parse arguments is collecting them into a vector
         // here we use a more imperative approach to fine-tune the argument usage
         // we could use a cargo Crate to abstract this command line usage, but it's beyond the scope of this app
@@ -96,19 +232,21 @@ impl App {
             // if we dont pass at least 2 args we invoke help
             self.print_version();
             self.print_help();
+            Ok(0)
         } else {
             let key_arg = self.arguments[1].to_string();
             // if we're passing only 2 arguments, this is most likely a read on the default store, so let's invoke that
             if self.arguments.len() == 2 {
                 // but before that, lets see if we are actually passing a --store argument
                 if self.arguments[1].starts_with("--") {
-                    self.run(key_arg, "".to_string());
+                    self.run(key_arg, "".to_string())
                 } else {
-                    self.read(key_arg);
+                    self.read(key_arg)?;
+                    Ok(0)
                 }
             }
             // if we're passing 3 arguments
-            else if self.arguments.len() >= 3 {
+            else {
                 // creating a binding for the value argument and setting it to empty
                 let mut value_arg: String = String::from("");
                 // iterate over our arguments
@@ -123,280 +261,1835 @@ impl App {
                     }
                 }
                 //
-                self.run(key_arg, value_arg);
+                self.run(key_arg, value_arg)
             }
         }
     }
 
-    fn get_store_from_options(&self, default_store_name: String) -> String {
-        let mut store_name = default_store_name;
-        let store_name_option = "--store=";
-        for option in self.options_arg.iter() {
-            if option.starts_with(store_name_option) {
-                store_name = option
-                    .split("=") // splitting on the = sign
-                    .last() // getting the last split
-                    .unwrap_or(&store_name) //unwrapping the result setting the default
-                    .to_string() //cloning it so we can use it out of this scope
-            }
+    /**
+     * Commands that work with the private key itself, which the key agent doesn't give out
+     */
+    fn needs_private_key(&self) -> bool {
+        let action = self.positional_args.first().map(|a| a.as_str());
+        match self.arguments.get(1).map(|a| a.as_str()) {
+            Some("--passwd") | Some("--rotate-key") => true,
+            Some("--key") => matches!(action, Some("export") | Some("split")),
+            Some("--agent") => matches!(action, None | Some("start")),
+            _ => false,
         }
-        store_name
     }
 
     /**
-     * Reads the options vector and checks if the --debug=true flag is set
+     * The master key: the key agent when one is running, or the key pair loaded from the key file
      */
-    fn get_logger_debug_from_options(&self, default_value: bool) -> bool {
-        let mut debug_flag = default_value;
-        let debug_option = "--debug=";
-        for option in self.options_arg.iter() {
-            if option.starts_with(debug_option) {
-                debug_flag = option
-                    .split("=") // splitting on the = sign
-                    .last() // getting the last split
-                    .unwrap_or("false")
-                    .eq("true");
-            }
+    fn master(&self) -> error::Result<&dyn keys::MasterKey> {
+        match &self.agent {
+            Some(agent) => Ok(agent),
+            None => Ok(&**self.keypair()?),
         }
-        debug_flag
     }
 
     /**
-     * Reads the options vector and checks if the --debug=true flag is set
+     * The key pair of the data directory, loaded the first time it's needed. A protected key asks
+     * for its passphrase on the terminal when the environment has none, so commands that don't
+     * open a store, like --help, never ask for it
      */
-    fn get_formatting_from_options(&self, default_value: String) -> String {
-        let mut format_value = default_value;
-        let format_option = "--f=";
-        for option in self.options_arg.iter() {
-            if option.starts_with(format_option) {
-                format_value = option
-                    .split("=") // splitting on the = sign
-                    .last() // getting the last split
-                    .unwrap_or(&format_value) //unwrapping the result setting the default
-                    .to_string()
-            }
+    fn keypair(&self) -> error::Result<&Locked<keys::KeyPair>> {
+        Ok(&self.unlocked_key()?.0)
+    }
+
+    /**
+     * The passphrase that unlocked the key pair, if it's protected
+     */
+    fn passphrase(&self) -> error::Result<Option<&str>> {
+        Ok(self.unlocked_key()?.1.as_ref().map(|p| p.as_str()))
+    }
+
+    fn unlocked_key(&self) -> error::Result<&(Locked<keys::KeyPair>, Option<Zeroizing<String>>)> {
+        if let Some(key) = self.key.get() {
+            return Ok(key);
         }
-        format_value
+        let (mut keypair, passphrase) = keys::load_or_create_unlocked(&self.get_data_dir(), PassphraseSource::Terminal)?;
+        let keypair = Locked::take(&mut keypair);
+        if !keypair.is_locked() {
+            self.logger
+                .debug("the key couldn't be locked in memory, it may be written to swap".to_string());
+        }
+        Ok(self.key.get_or_init(|| (keypair, passphrase.map(Zeroizing::new))))
+    }
+
+    /**
+     * Returns the value of an option like --data-dir=PATH, if it was passed
+     */
+    fn get_option_value(&self, option_name: &str) -> Option<String> {
+        self.options_arg
+            .iter()
+            .rev()
+            .find_map(|option| option.strip_prefix(option_name))
+            .map(|value| value.to_string())
     }
 
     /**
-     * Runs the specified command invoking the corresponding function
+     * Runs the specified command invoking the corresponding function, returning its exit status
      */
-    fn run(self, key: String, value: String) {
+    fn run(&self, key: String, value: String) -> error::Result<i32> {
         match key.as_str() {
             "--help" => self.print_help(),
-            "--stores" => self.print_stores(),
-            "--print" => self.print_store(),
+            "--stores" => self.print_stores()?,
+            "--print" => self.print_store()?,
             "--version" => self.print_version(),
-            _ => self.handle_input(key, value),
+            "--create" => self.create_store()?,
+            "--drop" => self.drop_store()?,
+            "--rename" => self.rename_store()?,
+            "--copy" => self.copy_store()?,
+            "--info" => self.print_store_info()?,
+            "--stats" => self.print_stats()?,
+            "--limits" => self.store_limits()?,
+            "--blind-keys" => self.blind_keys()?,
+            "--seal" => self.seal_store()?,
+            "--plaintext" => self.plaintext_store()?,
+            "--recipients" => self.recipients()?,
+            "--public-key" => self.print_public_key()?,
+            "--key" => self.key()?,
+            "--agent" => self.key_agent()?,
+            "--audit" => self.audit_log()?,
+            "--merge" => self.merge_stores()?,
+            "--diff" => return self.diff_stores(),
+            "--passwd" => self.change_passphrase()?,
+            "--rotate-key" => self.rotate_key()?,
+            "--delete" => self.delete()?,
+            "--put-file" => self.put_file()?,
+            "--get-file" => self.get_file()?,
+            _ => self.handle_input(key, value)?,
         }
+        Ok(0)
     }
 
     /**
      * Handles the user input by checking the value parameter.
      * If it's not empty, insert the value, otherwise read the key
      */
-    fn handle_input(self, key: String, value: String) {
+    fn handle_input(&self, key: String, value: String) -> error::Result<()> {
         // if value is empty we want to read the value for the key
-        if value == "" {
-            self.read(key);
+        if value.is_empty() {
+            self.read(key)
         } else {
             // if value is not empty, we insert a new key
-            self.insert(key, value);
+            self.insert(key, value)
         }
     }
 
     /**
      * Reads the value for a key in a store
      */
-    fn read(&self, key: String) {
-        let mut db = self.create_db();
-        let value: String = db.read(key.to_string());
-        let formatting = self.get_formatting_from_options("default".to_string());
-        if value != "" {
-            self.print_keypair_formatted(
-                &key,
-                crypto::decrypt_string(&self.keypair.1, value), //decrypting value with private key
-                formatting,
-            );
+    fn read(&self, key: String) -> error::Result<()> {
+        let store = self.create_store_handle()?;
+        let formatting = self.format.to_string();
+        // the store decrypts the value with the private key
+        if let Some(value) = store.get(&key)?.map(Zeroizing::new) {
+            self.audit(store.name(), &store.logged_key(&key), audit::Operation::Read)?;
+            self.print_keypair_formatted(&key, &value, formatting);
         } else {
             self.logger.display(format!(
                 "Key not found: '{}' on store: '{}'",
                 key, self.store_name
             ));
         }
+        Ok(())
     }
 
     /**
      * Inserts a new key-pair in the selected store
      */
-    fn insert(&self, key: String, value: String) {
-        let mut db = self.create_db();
-        self.logger.debug(format!("using store: '{}'", db.name));
-        // inserting key-pair into the db
-        db.insert(
-            key.to_owned(), // here we use to_owned to 'clone' the key and value since our db struct wants an owned string.
-            crypto::encrypt_string(&self.keypair.0, value.to_owned()), //encrypting the value
-        );
+    fn insert(&self, key: String, value: String) -> error::Result<()> {
+        let mut store = self.open_store_locked(&self.store_name)?;
+        self.logger.debug(format!("using store: '{}'", store.name()));
+        // inserting key-pair into the store, which encrypts the value
+        // flushing here instead of on drop, so errors like a locked store are reported
+        store.set(&key, &value).and_then(|_| store.flush())?;
+        self.audit(store.name(), &store.logged_key(&key), audit::Operation::Insert)?;
+        self.logger.display(format!("Saved '{}'", key));
+        Ok(())
+    }
+
+    /**
+     * Deletes a key from the selected store: gui-kvstore --delete KEY
+     */
+    fn delete(&self) -> error::Result<()> {
+        let key = match self.positional_args.first() {
+            Some(key) => key,
+            None => {
+                self.logger
+                    .display("Usage: gui-kvstore --delete KEY --store=STORE_NAME".to_string());
+                return Ok(());
+            }
+        };
+        let mut store = self.open_store_locked(&self.store_name)?;
+        if store.delete(key) {
+            store.flush()?;
+            self.audit(store.name(), &store.logged_key(key), audit::Operation::Delete)?;
+            self.logger.display(format!("Deleted '{}'", key));
+        } else {
+            self.logger.display(format!(
+                "Key not found: '{}' on store: '{}'",
+                key, self.store_name
+            ));
+        }
+        Ok(())
+    }
+
+    /**
+     * Saves the contents of a file under a key: gui-kvstore --put-file KEY PATH
+     */
+    fn put_file(&self) -> error::Result<()> {
+        if self.positional_args.len() < 2 {
+            self.logger
+                .display("Usage: gui-kvstore --put-file KEY PATH --store=STORE_NAME".to_string());
+            return Ok(());
+        }
+        let (key, path) = (&self.positional_args[0], &self.positional_args[1]);
+        let mut file = std::fs::File::open(path)?;
+        let mut store = self.open_store_locked(&self.store_name)?;
+        let size = store
+            .put_file(key, &mut file)
+            .and_then(|size| store.flush().map(|_| size))?;
+        self.audit(store.name(), &store.logged_key(key), audit::Operation::Insert)?;
         self.logger
-            .display(format!("Saved '{}' with value '{}'", key, value)); //here we can use the key and value binds again since we used to_owned above
+            .display(format!("Saved '{}' as '{}' ({} bytes)", path, key, size));
+        Ok(())
+    }
+
+    /**
+     * Writes a file saved with --put-file to --out=PATH, or to stdout: gui-kvstore --get-file KEY --out=PATH
+     */
+    fn get_file(&self) -> error::Result<()> {
+        let key = match self.positional_args.first() {
+            Some(key) => key,
+            None => {
+                self.logger
+                    .display("Usage: gui-kvstore --get-file KEY --out=PATH --store=STORE_NAME".to_string());
+                return Ok(());
+            }
+        };
+        let store = self.create_store_handle()?;
+        let mut reader = match store.get_file(key)? {
+            Some(reader) => reader,
+            None => {
+                self.logger.display(format!(
+                    "File not found: '{}' on store: '{}'",
+                    key, self.store_name
+                ));
+                return Ok(());
+            }
+        };
+        self.audit(store.name(), &store.logged_key(key), audit::Operation::Read)?;
+        // the file is copied a chunk at a time, never loaded whole
+        let result = match self.get_option_value("--out=") {
+            Some(out) => std::fs::File::create(&out).and_then(|mut file| std::io::copy(&mut reader, &mut file)),
+            None => std::io::copy(&mut reader, &mut std::io::stdout().lock()),
+        };
+        result?;
+        if let Some(out) = self.get_option_value("--out=") {
+            self.logger
+                .display(format!("Wrote '{}' to '{}' ({} bytes)", key, out, reader.size()));
+        }
+        Ok(())
     }
 
     /**
      * Displays the different stores (dbs) created with the app
      */
-    fn print_stores(&self) {
-        let db = self.create_db();
-        db.print_stores();
+    fn print_stores(&self) -> error::Result<()> {
+        let store = self.create_store_handle()?;
+        store.database().print_stores()?;
+        Ok(())
     }
 
     /**
      * Display all key-pairs within a store
      */
-    fn print_store(&self) {
-        let formatting = self.get_formatting_from_options("default".to_string());
+    fn print_store(&self) -> error::Result<()> {
+        let formatting = self.format.to_string();
         self.logger.display(format!(
             "Displaying Store '{}' with formatting '{}'",
             self.store_name, formatting
         ));
-        let db = self.create_db();
-        let items = db.get_stores();
-        self.print_store_formatted(items, formatting);
+        let store = self.create_store_handle()?;
+        let items: Vec<(String, Zeroizing<String>)> = store
+            .iter()
+            .map(|pair| pair.map(|(key, value)| (key, Zeroizing::new(value))))
+            .collect::<Result<_, Error>>()?;
+        let logged_keys: Vec<String> = items.iter().map(|(key, _)| store.logged_key(key)).collect();
+        let reads: Vec<_> = logged_keys
+            .iter()
+            .map(|key| (store.name(), key.as_str(), audit::Operation::Read))
+            .collect();
+        self.audit_all(&reads)?;
+        self.print_store_formatted(&items, formatting);
+        Ok(())
+    }
+
+    /**
+     * Shortcut function to always open the selected store in the same way
+     */
+    fn create_store_handle(&self) -> error::Result<Store> {
+        self.open_store(&self.store_name)
     }
 
     /**
-     * Shortcut function to always create db in the same way
+     * Opens (or creates) a store by name in the data directory
      */
-    fn create_db(&self) -> db::Database {
-        // lets create a binding for where we want our db files to be stored
-        let store_path = App::get_data_dir() //getting home directory
-            .join("data") //joining the path for data
-            .display() //converting to a displayable object
-            .to_string(); //that has a to_string method
-                          // return a new db instance with our store name, a valid path and if we`re debugging
-        db::Database::new(
-            self.store_name.to_string(),
-            store_path,
+    fn open_store(&self, store_name: &str) -> error::Result<Store> {
+        // return a new store with our store name, the data dir, the engine, if we`re debugging and our keys
+        let store = Store::open_with_master(
+            &self.get_data_dir(),
+            store_name,
+            self.engine,
             self.logger.is_debug,
-        )
-        .unwrap()
+            self.master()?,
+        )?;
+        self.report_signed(&store);
+        Ok(store)
     }
 
-    fn print_version(&self) {
-        //showing the version information from Cargo
-        const VERSION: &str = env!("CARGO_PKG_VERSION");
+    /**
+     * Opens a store to change it, locked until it's dropped so the changes of another
+     * command running at the same time aren't lost
+     */
+    fn open_store_locked(&self, store_name: &str) -> error::Result<Store> {
+        let store = Store::open_locked(
+            &self.get_data_dir(),
+            store_name,
+            self.engine,
+            self.logger.is_debug,
+            self.master()?,
+        )?;
+        self.report_signed(&store);
+        Ok(store)
+    }
+
+    /**
+     * Tells the user when a store from before MACs got its first one, it happens once per store
+     */
+    fn report_signed(&self, store: &Store) {
+        if store.was_signed() {
+            self.logger.display(format!(
+                "Store '{}' had no MAC and was signed: from now on, changes made to it outside of gui-kvstore are detected",
+                store.name()
+            ));
+        }
+    }
+
+    /**
+     * Where the db files are stored
+     */
+    fn get_store_path(&self) -> String {
+        Store::store_path(&self.get_data_dir())
+    }
+
+    /**
+     * Returns the positional argument at pos, or the --store option if it wasn't passed
+     */
+    fn get_store_arg(&self, pos: usize) -> String {
+        self.positional_args
+            .get(pos)
+            .unwrap_or(&self.store_name)
+            .to_string()
+    }
+
+    /**
+     * Asks the user a yes/no question. --yes answers it upfront
+     */
+    fn confirm(&self, question: String) -> bool {
+        self.confirm_from(question, &mut std::io::stdin().lock())
+    }
+
+    /**
+     * Same as confirm, for commands that read their input from the standard input:
+     * the answer is read from the terminal, and without one only --yes confirms
+     */
+    fn confirm_on_terminal(&self, question: String) -> error::Result<bool> {
+        if self.options_arg.iter().any(|o| o == "--yes") {
+            return Ok(true);
+        }
+        match std::fs::File::open("/dev/tty") {
+            Ok(terminal) => Ok(self.confirm_from(question, &mut std::io::BufReader::new(terminal))),
+            Err(_) => Err(Error::Config(
+                "the standard input was read already and there's no terminal to answer from, pass --yes".to_string(),
+            )),
+        }
+    }
+
+    fn confirm_from(&self, question: String, input: &mut impl BufRead) -> bool {
+        if self.options_arg.iter().any(|o| o == "--yes") {
+            return true;
+        }
+        print!("{} [y/N] ", question);
+        let _ = std::io::stdout().flush();
+        let mut answer = String::new();
+        if input.read_line(&mut answer).is_err() {
+            return false;
+        }
+        matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+    }
+
+    /**
+     * Creates a new empty store, sealed with --sealed, with unencrypted values with --plaintext
+     */
+    fn create_store(&self) -> error::Result<()> {
+        let store_name = self.get_store_arg(0);
+        if db::Database::exists(&self.get_store_path(), &store_name) {
+            self.logger
+                .display(format!("Store '{}' already exists", store_name));
+            return Ok(());
+        }
+        let mut store = self.open_store_locked(&store_name)?;
+        let sealed = self.options_arg.iter().any(|o| o == "--sealed");
+        let plaintext = self.options_arg.iter().any(|o| o == "--plaintext");
+        store.set_sealed(sealed);
+        store
+            .set_plaintext(plaintext)
+            .and_then(|_| store.flush())?;
+        self.logger.display(format!(
+            "Created {}{}store '{}'",
+            if sealed { "sealed " } else { "" },
+            if plaintext { "plaintext " } else { "" },
+            store.name()
+        ));
+        Ok(())
+    }
+
+    /**
+     * Deletes a store after asking for confirmation
+     */
+    fn drop_store(&self) -> error::Result<()> {
+        let store_name = self.get_store_arg(0);
+        let store_path = self.get_store_path();
+        if !db::Database::exists(&store_path, &store_name) {
+            self.logger
+                .display(format!("Store not found: '{}'", store_name));
+            return Ok(());
+        }
+        // a store that doesn't open, like a tampered one, can still be dropped. it's only read,
+        // a store about to be deleted isn't stamped or signed
+        let question = match Store::open_read_only(&self.get_data_dir(), &store_name, self.logger.is_debug, self.master()?) {
+            Ok(store) => format!(
+                "Drop store '{}' with {} keys?",
+                store_name,
+                store.database().info().map(|i| i.keys).unwrap_or(0)
+            ),
+            Err(e) => format!("Drop store '{}', which can't be opened ({})?", store_name, e),
+        };
+        if !self.confirm(question) {
+            self.logger.display("Aborted".to_string());
+            return Ok(());
+        }
+        // a new store with the same name starts its generations over
+        db::Database::drop_store(&store_path, &store_name)?;
+        self.audit(&store_name, audit::NO_KEY, audit::Operation::Drop)?;
+        integrity::forget(&self.get_data_dir(), &store_name)?;
+        self.logger.display(format!("Dropped store '{}'", store_name));
+        Ok(())
+    }
+
+    /**
+     * Renames a store: gui-kvstore --rename OLD NEW
+     */
+    fn rename_store(&self) -> error::Result<()> {
+        if self.positional_args.len() < 2 {
+            self.logger
+                .display("Usage: gui-kvstore --rename OLD_STORE NEW_STORE".to_string());
+            return Ok(());
+        }
+        let (from, to) = (&self.positional_args[0], &self.positional_args[1]);
+        let store_path = self.get_store_path();
+        if !db::Database::exists(&store_path, from) {
+            self.logger.display(format!("Store not found: '{}'", from));
+            return Ok(());
+        }
+        db::Database::rename_store(&store_path, from, to)?;
+        integrity::forget(&self.get_data_dir(), from)?;
         self.logger
-            .display(format!("gui-kvstore version: {}", VERSION));
+            .display(format!("Renamed store '{}' to '{}'", from, to));
+        Ok(())
     }
 
     /**
-     * Display command usage
+     * Clones a store into a new one: gui-kvstore --copy SRC DST
      */
-    fn print_help(&self) {
-        let logger = &self.logger;
-        logger.display(format!("Usage:"));
-        logger.display(format!(
-        "\tgui-kvstore KEY VALUE --debug=true|false --f=default|csv|json|short|table --store=STORE_NAME"
+    fn copy_store(&self) -> error::Result<()> {
+        if self.positional_args.len() < 2 {
+            self.logger
+                .display("Usage: gui-kvstore --copy SRC_STORE DST_STORE".to_string());
+            return Ok(());
+        }
+        let (from, to) = (&self.positional_args[0], &self.positional_args[1]);
+        let store_path = self.get_store_path();
+        if !db::Database::exists(&store_path, from) {
+            self.logger.display(format!("Store not found: '{}'", from));
+            return Ok(());
+        }
+        db::Database::copy_store(&store_path, from, to)?;
+        self.audit(to, from, audit::Operation::Copy)?;
+        self.logger
+            .display(format!("Copied store '{}' to '{}'", from, to));
+        Ok(())
+    }
+
+    /**
+     * Displays key count, file size, last modification, format version and key fingerprint of a store
+     */
+    fn print_store_info(&self) -> error::Result<()> {
+        let store_name = self.get_store_arg(0);
+        if !db::Database::exists(&self.get_store_path(), &store_name) {
+            self.logger
+                .display(format!("Store not found: '{}'", store_name));
+            return Ok(());
+        }
+        let store = self.open_store(&store_name)?;
+        let info = store.database().info()?;
+        self.logger.display(format!("Store:         {}", info.name));
+        self.logger.display(format!("Keys:          {}", info.keys));
+        self.logger
+            .display(format!("File size:     {} bytes", info.file_size));
+        self.logger.display(format!(
+            "Last modified: {}",
+            App::format_time(info.modified)
         ));
-        logger.display(format!("Saves a VALUE string with a key with name of KEY"));
-        logger.display(format!("\nOptions:"));
-        logger.display(format!(
-            "\t--debug=true|false               - toggles debug output"
+        self.logger
+            .display(format!("Format:        v{}", info.version));
+        self.logger.display(format!(
+            "Fingerprint:   {}",
+            info.fingerprint.unwrap_or_else(|| "unknown".to_string())
         ));
-        logger.display(format!(
-            "\t--f=default|csv|json|short|table - specifies the format to read"
+        self.logger
+            .display(format!("Data key:      {}", store.data_key_fingerprint()));
+        self.logger.display(format!(
+            "Values:        {}",
+            if store.plaintext() { "plaintext" } else { "encrypted" }
         ));
-        logger.display(format!(
-            "\t--store=STORE_NAME               - reads/writes value in a specific db store file"
+        self.logger.display(format!(
+            "Key names:     {}",
+            if store.blind_keys() { "blinded" } else { "plain" }
         ));
-        //
-        logger.display(format!("\nOther Commands:"));
-        logger.display(format!("\tgui-kvstore --stores"));
-        logger.display(format!("Prints all the stores created"));
-        logger.display(format!("\n\tgui-kvstore KEY VALUE --store=STORE_NAME"));
-        logger.display(format!(
-            "Saves a VALUE string with a key with name of KEY in the store STORE_NAME"
+        self.logger.display(format!(
+            "Store file:    {}",
+            if store.sealed() { "sealed" } else { "plain" }
+        ));
+        self.logger
+            .display(format!("Limits:        {}", App::format_limits(&info.limits)));
+        Ok(())
+    }
+
+    /**
+     * Changes the passphrase protecting the private key. The key pair stays the same,
+     * so the stores don't need to be re-encrypted. An empty passphrase removes the protection
+     */
+    fn change_passphrase(&self) -> error::Result<()> {
+        let path = keys::key_path(&self.get_data_dir());
+        // the current passphrase is asked first
+        let keypair = self.keypair()?;
+        let new_passphrase = PassphraseSource::Terminal
+            .replacement()?
+            .map(Zeroizing::new)
+            .ok_or_else(|| {
+                Error::Passphrase(format!(
+                    "no new passphrase, set {} or run in a terminal",
+                    passphrase::ENV_NEW_PASSPHRASE
+                ))
+            })?;
+        if new_passphrase.is_empty() {
+            keys::save(&path, keypair)?;
+            self.logger.display(format!(
+                "Removed the passphrase of {}, anyone who can read it can decrypt the stores",
+                path.display()
+            ));
+        } else {
+            keys::save_protected(&path, keypair, &new_passphrase, keys::KdfParams::default())?;
+            self.logger
+                .display(format!("Changed the passphrase of {}", path.display()));
+        }
+        Ok(())
+    }
+
+    /**
+     * Replaces the key pair, re-encrypting every store with the new one. The new key keeps the
+     * passphrase of the old one. Running it again after an interruption finishes the rotation
+     */
+    fn rotate_key(&self) -> error::Result<()> {
+        let (keypair, rotation) = rotate::rotate_key(&self.get_data_dir(), self.keypair()?, self.passphrase()?)?;
+        self.stop_agent();
+        // the key of the audit log is wrapped with the new key now
+        audit::AuditLog::open(&self.get_data_dir(), &keypair)?.record_all(
+            &audit::current_user(),
+            &[(audit::NO_STORE, &rotation.old_fingerprint, audit::Operation::RotateKey)],
+        )?;
+        for name in &rotation.resumed {
+            self.logger
+                .display(format!("Store '{}' was already rotated", name));
+        }
+        for name in &rotation.rotated {
+            self.logger.display(format!("Rotated store '{}'", name));
+        }
+        self.logger.display(format!(
+            "Rotated the key from {} to {}, the old key was archived in {}",
+            rotation.old_fingerprint,
+            rotation.new_fingerprint,
+            rotation.archived.display()
         ));
-        logger.display(format!(""));
-        logger.display(format!(
-            "gui-kvstore --print --debug=true|false --f=default|csv|json|short|table --store=STORE_NAME"
+        let verification = &rotation.verification;
+        self.logger.display(format!(
+            "Verified {} values and {} files in {} stores",
+            verification.values, verification.files, verification.stores
         ));
-        logger.display(format!("Prints all key-pairs saved in the store"));
-        logger.display(format!(""));
+        Ok(())
     }
 
-    fn print_keypair_formatted(&self, key: &String, value: String, formatting: String) {
-        match formatting.as_str() {
-            "short" => self.logger.display(format!("{}", value)),
-            "csv" => {
-                let headers = "key,value";
-                let pair = format!("{},{}", key, value);
-                self.logger.display(format!("{}\n{}\n", headers, pair));
+    /**
+     * Merges a store into another: gui-kvstore --merge SRC DST --policy=ours|theirs|newest|interactive --dry-run
+     */
+    fn merge_stores(&self) -> error::Result<()> {
+        if self.positional_args.len() < 2 {
+            self.logger.display(
+                "Usage: gui-kvstore --merge SRC_STORE DST_STORE --policy=ours|theirs|newest|interactive --dry-run"
+                    .to_string(),
+            );
+            return Ok(());
+        }
+        let (from, to) = (&self.positional_args[0], &self.positional_args[1]);
+        let store_path = self.get_store_path();
+        for name in [from, to] {
+            if !db::Database::exists(&store_path, name) {
+                self.logger.display(format!("Store not found: '{}'", name));
+                return Ok(());
             }
-            "table" => {
-                let table = Table::new([KeypairItem {
-                    key: key.to_string(),
-                    value,
-                }])
-                .to_string();
-                self.logger.display(table);
+        }
+        let policy_name = self.get_option_value("--policy=").unwrap_or_else(|| "ours".to_string());
+        let policy = Policy::from_name(&policy_name).ok_or_else(|| {
+            Error::Config(format!(
+                "unknown merge policy '{}', use ours, theirs, newest or interactive",
+                policy_name
+            ))
+        })?;
+        let dry_run = self.options_arg.iter().any(|o| o == "--dry-run");
+        let src = self.open_store(from)?;
+        let mut dst = self.open_store_locked(to)?;
+        let summary = merge::merge(&src, &mut dst, policy, dry_run, |conflict| self.ask_side(conflict))?;
+        if !dry_run {
+            dst.flush()?;
+            self.audit(dst.name(), src.name(), audit::Operation::Merge)?;
+        }
+        if dry_run {
+            for key in &summary.added {
+                self.logger.display(format!("Add:      '{}'", key));
+            }
+            for conflict in &summary.conflicts {
+                let resolution = match conflict.resolution {
+                    Some(Side::Ours) => "keep ours",
+                    Some(Side::Theirs) => "take theirs",
+                    None => "ask",
+                };
+                self.logger.display(format!(
+                    "Conflict: '{}' ours modified {}, theirs modified {} - {}",
+                    conflict.key,
+                    App::format_timestamp(conflict.ours_modified),
+                    App::format_timestamp(conflict.theirs_modified),
+                    resolution
+                ));
+            }
+        }
+        self.logger.display(format!(
+            "{} '{}' into '{}': {} added, {} updated, {} conflicts, {} unchanged",
+            if dry_run { "Would merge" } else { "Merged" },
+            from,
+            to,
+            summary.added.len(),
+            summary.updated.len(),
+            summary.conflicts.len(),
+            summary.unchanged
+        ));
+        Ok(())
+    }
+
+    /**
+     * Compares two stores, store files, snapshots or backup archives:
+     * gui-kvstore --diff A B --f=text|json|unified --reveal
+     * Returns 1 when they differ, like diff does
+     */
+    fn diff_stores(&self) -> error::Result<i32> {
+        if self.positional_args.len() < 2 {
+            self.logger.display(
+                "Usage: gui-kvstore --diff STORE_FILE_SNAPSHOT_OR_ARCHIVE[:STORE] STORE_FILE_SNAPSHOT_OR_ARCHIVE[:STORE] --f=text|json|unified --reveal".to_string(),
+            );
+            return Ok(0);
+        }
+        let (a, b) = (&self.positional_args[0], &self.positional_args[1]);
+        let ((old, old_archive), (new, new_archive)) = match (self.open_diff_operand(a), self.open_diff_operand(b)) {
+            (Ok(old), Ok(new)) => (old, new),
+            // a store is closed before the copy of the archive it was extracted from is deleted
+            (Err(e), other) | (other, Err(e)) => {
+                drop(other);
+                return Err(e);
             }
+        };
+        let changes = match diff::diff(&old, &new) {
+            Ok(changes) => changes,
+            Err(e) => {
+                drop((old, new, old_archive, new_archive));
+                return Err(e);
+            }
+        };
+        // values are secrets, they are only shown with --reveal
+        let reveal = self.options_arg.iter().any(|o| o == "--reveal");
+        if reveal {
+            let mut logged_keys = vec![];
+            for change in &changes {
+                if change.old.is_some() {
+                    logged_keys.push((old.name(), old.logged_key(&change.key)));
+                }
+                if change.new.is_some() {
+                    logged_keys.push((new.name(), new.logged_key(&change.key)));
+                }
+            }
+            let reads: Vec<_> = logged_keys
+                .iter()
+                .map(|(store, key)| (*store, key.as_str(), audit::Operation::Read))
+                .collect();
+            self.audit_all(&reads)?;
+        }
+        let show = |value: &Option<String>| -> String {
+            match value {
+                Some(value) if reveal => value.to_string(),
+                Some(_) => "********".to_string(),
+                None => "".to_string(),
+            }
+        };
+        match self.format.as_str() {
             "json" => {
-                let mut json_object: HashMap<String, String> = HashMap::new();
-                json_object.insert(key.to_string(), value);
-                let json_string = json::stringify_pretty(json_object, 4);
-                self.logger.display(json_string);
+                let mut json_changes = json::JsonValue::new_array();
+                for change in &changes {
+                    let _ = json_changes.push(json::object! {
+                        key: change.key.to_string(),
+                        change: App::change_name(change),
+                        old: change.old.as_ref().map(|_| show(&change.old)),
+                        new: change.new.as_ref().map(|_| show(&change.new)),
+                    });
+                }
+                self.logger.display(json::stringify_pretty(json_changes, 4));
+            }
+            "unified" => {
+                self.logger.display(format!("--- {}", a));
+                self.logger.display(format!("+++ {}", b));
+                for change in &changes {
+                    if change.old.is_some() {
+                        self.logger.display(format!("-{}={}", change.key, show(&change.old)));
+                    }
+                    if change.new.is_some() {
+                        self.logger.display(format!("+{}={}", change.key, show(&change.new)));
+                    }
+                }
+            }
+            _ => {
+                for change in &changes {
+                    let line = match change.kind {
+                        ChangeKind::Added => format!("+ {}={}", change.key, show(&change.new)),
+                        ChangeKind::Removed => format!("- {}={}", change.key, show(&change.old)),
+                        ChangeKind::Changed => format!(
+                            "~ {}: {} -> {}",
+                            change.key,
+                            show(&change.old),
+                            show(&change.new)
+                        ),
+                    };
+                    self.logger.display(line);
+                }
+                let count = |kind: ChangeKind| changes.iter().filter(|c| c.kind == kind).count();
+                self.logger.display(format!(
+                    "{} added, {} removed, {} changed",
+                    count(ChangeKind::Added),
+                    count(ChangeKind::Removed),
+                    count(ChangeKind::Changed)
+                ));
             }
-            _ => self.logger.display(format!("{}={}", key, value)),
+        }
+        // the stores are closed before the archives they were extracted from are deleted
+        drop((old, new));
+        drop((old_archive, new_archive));
+        match changes.is_empty() {
+            true => Ok(0),
+            false => Ok(error::EXIT_DIFFERENCES),
         }
     }
 
-    fn print_store_formatted(&self, db: HashMap<String, String>, formatting: String) {
-        match formatting.as_str() {
-            "short" => {
-                for (_key, value) in db {
-                    let dec_value = crypto::decrypt_string(&self.keypair.1, value);
-                    self.logger.display(format!("{}", dec_value));
+    /**
+     * A diff operand is the name of a store, or a path to a store file, like a backup copy, to a snapshot
+     * of a data directory or to a backup archive of one. In the last two, the store is picked with
+     * PATH:STORE, or --store. Stores in archives are read from a temporary copy, deleted with the archive
+     */
+    fn open_diff_operand(&self, operand: &str) -> Result<(Store, Option<archive::Extracted>), Error> {
+        let (path, store_name) = match operand.rsplit_once(':') {
+            Some((path, store_name)) if Path::new(path).exists() => (Path::new(path), store_name),
+            _ => (Path::new(operand), self.store_name.as_str()),
+        };
+        let open_file = |path: &Path| Store::open_file_with_master(path, self.logger.is_debug, self.master()?);
+        let in_data_dir = |data_dir: &Path| data_dir.join("data").join(format!("{}.db", store_name));
+        if path.is_file() && archive::is_archive(path) {
+            let extracted = archive::Extracted::open(path)?;
+            let store_file = in_data_dir(extracted.data_dir());
+            if !store_file.is_file() {
+                return Err(Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("no store named '{}' in '{}'", store_name, path.display()),
+                )));
+            }
+            return Ok((open_file(&store_file)?, Some(extracted)));
+        }
+        if operand.ends_with(".db") || path.is_file() {
+            return Ok((open_file(path)?, None));
+        }
+        // a copy of a data directory, like a file system snapshot
+        if path.join("data").is_dir() {
+            return Ok((open_file(&in_data_dir(path))?, None));
+        }
+        if !db::Database::exists(&self.get_store_path(), operand) {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no store, store file, snapshot or archive named '{}'", operand),
+            )));
+        }
+        Ok((self.open_store(operand)?, None))
+    }
+
+    fn change_name(change: &Change) -> &'static str {
+        match change.kind {
+            ChangeKind::Added => "added",
+            ChangeKind::Removed => "removed",
+            ChangeKind::Changed => "changed",
+        }
+    }
+
+    /**
+     * Asks which value of a merge conflict to keep, ours unless theirs is picked
+     */
+    fn ask_side(&self, conflict: &Conflict) -> Side {
+        self.logger.display(format!("Conflict on '{}':", conflict.key));
+        self.logger.display(format!(
+            "  ours:   {} (modified {})",
+            conflict.ours,
+            App::format_timestamp(conflict.ours_modified)
+        ));
+        self.logger.display(format!(
+            "  theirs: {} (modified {})",
+            conflict.theirs,
+            App::format_timestamp(conflict.theirs_modified)
+        ));
+        print!("Keep [o]urs or take [t]heirs? [o/t] ");
+        let _ = std::io::stdout().flush();
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer).is_err() {
+            return Side::Ours;
+        }
+        match answer.trim().to_lowercase().as_str() {
+            "t" | "theirs" => Side::Theirs,
+            _ => Side::Ours,
+        }
+    }
+
+    /**
+     * Shows or changes the limits of a store:
+     * gui-kvstore --limits STORE_NAME --max-keys=N --max-value-bytes=N --max-bytes=N --eviction=lru|lfu|ttl-first
+     */
+    fn store_limits(&self) -> error::Result<()> {
+        let store_name = self.get_store_arg(0);
+        let mut store = self.open_store_locked(&store_name)?;
+        let mut limits = store.limits();
+        let mut changed = false;
+        // none removes a limit
+        let parse_limit = |option: &str| -> error::Result<Option<Option<usize>>> {
+            let value = match self.get_option_value(option) {
+                Some(value) => value,
+                None => return Ok(None),
+            };
+            if value == "none" {
+                return Ok(Some(None));
+            }
+            match value.parse() {
+                Ok(n) => Ok(Some(Some(n))),
+                Err(_) => Err(Error::Config(format!("invalid {}{}, use a number or none", option, value))),
+            }
+        };
+        for (option, limit) in [
+            ("--max-keys=", &mut limits.max_keys),
+            ("--max-value-bytes=", &mut limits.max_value_bytes),
+            ("--max-bytes=", &mut limits.max_bytes),
+        ] {
+            if let Some(value) = parse_limit(option)? {
+                *limit = value;
+                changed = true;
+            }
+        }
+        if let Some(name) = self.get_option_value("--eviction=") {
+            limits.eviction = match name.as_str() {
+                "none" => None,
+                _ => Some(Eviction::from_name(&name).ok_or_else(|| {
+                    Error::Config(format!(
+                        "unknown eviction policy '{}', use lru, lfu, ttl-first or none",
+                        name
+                    ))
+                })?),
+            };
+            changed = true;
+        }
+        if changed {
+            store.set_limits(limits).and_then(|_| store.flush())?;
+            self.logger.display(format!("Updated limits of store '{}'", store.name()));
+        }
+        self.logger
+            .display(format!("Limits of '{}': {}", store.name(), App::format_limits(&store.limits())));
+        Ok(())
+    }
+
+    /**
+     * Shows or changes if a store blinds its key names: gui-kvstore --blind-keys STORE_NAME on|off
+     */
+    fn blind_keys(&self) -> error::Result<()> {
+        let store_name = self.get_store_arg(0);
+        let mut store = self.open_store_locked(&store_name)?;
+        if let Some(setting) = self.positional_args.get(1) {
+            let blind = match setting.as_str() {
+                "on" => true,
+                "off" => false,
+                _ => return Err(Error::Config(format!("invalid setting '{}', use on or off", setting))),
+            };
+            store.set_blind_keys(blind).and_then(|_| store.flush())?;
+        }
+        self.logger.display(format!(
+            "Key names of '{}' are {}",
+            store.name(),
+            if store.blind_keys() { "blinded" } else { "saved as they are" }
+        ));
+        Ok(())
+    }
+
+    /**
+     * Shows or changes if a store file is encrypted whole: gui-kvstore --seal STORE_NAME on|off
+     */
+    fn seal_store(&self) -> error::Result<()> {
+        let store_name = self.get_store_arg(0);
+        let mut store = self.open_store_locked(&store_name)?;
+        if let Some(setting) = self.positional_args.get(1) {
+            let sealed = match setting.as_str() {
+                "on" => true,
+                "off" => false,
+                _ => return Err(Error::Config(format!("invalid setting '{}', use on or off", setting))),
+            };
+            store.set_sealed(sealed);
+            store.flush()?;
+        }
+        self.logger.display(format!(
+            "Store '{}' is {}",
+            store.name(),
+            if store.sealed() { "sealed" } else { "not sealed" }
+        ));
+        Ok(())
+    }
+
+    /**
+     * Shows or changes if the values of a store are saved as they are, unencrypted:
+     * gui-kvstore --plaintext STORE_NAME on|off
+     */
+    fn plaintext_store(&self) -> error::Result<()> {
+        let store_name = self.get_store_arg(0);
+        let mut store = self.open_store_locked(&store_name)?;
+        if let Some(setting) = self.positional_args.get(1) {
+            let plaintext = match setting.as_str() {
+                "on" => true,
+                "off" => false,
+                _ => return Err(Error::Config(format!("invalid setting '{}', use on or off", setting))),
+            };
+            if plaintext
+                && !store.plaintext()
+                && !store.is_empty()
+                && !self.confirm(format!(
+                    "The {} values of store '{}' will be saved unencrypted. Continue?",
+                    store.len(),
+                    store.name()
+                ))
+            {
+                self.logger.display("Aborted".to_string());
+                return Ok(());
+            }
+            store.set_plaintext(plaintext).and_then(|_| store.flush())?;
+        }
+        self.logger.display(format!(
+            "Values of '{}' are {}",
+            store.name(),
+            if store.plaintext() { "saved as plaintext" } else { "encrypted" }
+        ));
+        Ok(())
+    }
+
+    /**
+     * Lists, adds or removes the people a store is shared with:
+     * gui-kvstore --recipients STORE_NAME [list | add PUBLIC_KEY NAME | remove NAME_OR_FINGERPRINT]
+     */
+    fn recipients(&self) -> error::Result<()> {
+        let store_name = self.get_store_arg(0);
+        let mut store = self.open_store_locked(&store_name)?;
+        let action = self.positional_args.get(1).map(|a| a.as_str()).unwrap_or("list");
+        let arg = |index: usize, what: &str| -> error::Result<String> {
+            self.positional_args.get(index).cloned().ok_or_else(|| {
+                Error::Config(format!("--recipients {} needs {}", action, what))
+            })
+        };
+        match action {
+            "list" => {}
+            "add" => {
+                let public_key = recipients::parse_public_key(&arg(2, "the public key of the recipient")?)?;
+                let recipient = store
+                    .add_recipient(&arg(3, "a name for the recipient")?, public_key)
+                    .and_then(|r| store.flush().map(|_| r))?;
+                self.logger.display(format!(
+                    "Shared '{}' with {} ({})",
+                    store.name(),
+                    recipient.label,
+                    recipient.fingerprint()
+                ));
+            }
+            "remove" => {
+                let recipient = store
+                    .remove_recipient(&arg(2, "the name or fingerprint of the recipient")?, self.master()?)
+                    .and_then(|r| store.flush().map(|_| r))?;
+                self.logger.display(format!(
+                    "Removed {} ({}) from '{}', its values were re-encrypted with a new data key",
+                    recipient.label,
+                    recipient.fingerprint(),
+                    store.name()
+                ));
+                return Ok(());
+            }
+            _ => return Err(Error::Config(format!("unknown action '{}', use list, add or remove", action))),
+        }
+        let recipients = store.recipients()?;
+        self.logger.display(format!(
+            "Owner of '{}': {}",
+            store.name(),
+            store.database().get_meta("fingerprint").map(|f| f.as_str()).unwrap_or("unknown")
+        ));
+        for recipient in recipients {
+            self.logger
+                .display(format!("Recipient:     {} ({})", recipient.label, recipient.fingerprint()));
+        }
+        Ok(())
+    }
+
+    /**
+     * Prints the public key, for others to share their stores with this key
+     */
+    fn print_public_key(&self) -> error::Result<()> {
+        self.logger.display(crypto::encode_hex(&self.master()?.public_key()));
+        self.logger
+            .debug(format!("fingerprint: {}", crypto::fingerprint(&self.master()?.public_key())));
+        Ok(())
+    }
+
+    /**
+     * Shows, exports or imports the key pair:
+     * gui-kvstore --key [show | fingerprint [STORE_NAME] | export [PATH] --armor | import PATH
+     *                    | split --shares=N --threshold=N | combine [PATH...]]
+     */
+    fn key(&self) -> error::Result<()> {
+        let path = keys::key_path(&self.get_data_dir());
+        let action = self.positional_args.first().map(|a| a.as_str()).unwrap_or("show");
+        match action {
+            "show" => {
+                let protected = keys::is_protected(&path)?;
+                self.logger.display(format!("Key file:    {}", path.display()));
+                self.logger.display(format!(
+                    "Protected:   {}",
+                    if protected { "yes, by a passphrase" } else { "no" }
+                ));
+                self.logger
+                    .display(format!("Fingerprint: {}", crypto::fingerprint(&self.master()?.public_key())));
+                self.logger
+                    .display(format!("Public key:  {}", crypto::encode_hex(&self.master()?.public_key())));
+            }
+            "fingerprint" => match self.positional_args.get(1) {
+                // the key that wrote a store is in its header, readable without decrypting anything
+                Some(store_name) => {
+                    if !db::Database::exists(&self.get_store_path(), store_name) {
+                        return Err(Error::Config(format!("Store not found: '{}'", store_name)));
+                    }
+                    let database =
+                        db::Database::open(store_name.to_string(), self.get_store_path(), false, db::Engine::File)?;
+                    self.logger.display(
+                        database
+                            .get_meta("fingerprint")
+                            .cloned()
+                            .unwrap_or_else(|| "unknown".to_string()),
+                    );
+                }
+                None => self.logger.display(crypto::fingerprint(&self.master()?.public_key())),
+            },
+            "export" => {
+                let passphrase = PassphraseSource::Terminal
+                    .replacement()?
+                    .map(Zeroizing::new)
+                    .ok_or_else(|| {
+                        Error::Passphrase(format!(
+                            "exported keys need a passphrase, set {} or run in a terminal",
+                            passphrase::ENV_NEW_PASSPHRASE
+                        ))
+                    })?;
+                let armor = self.options_arg.iter().any(|o| o == "--armor");
+                // the wrapped private key, zeroed once it's written or printed
+                let exported = Zeroizing::new(keys::export(self.keypair()?, &passphrase, keys::KdfParams::default(), armor)?);
+                self.audit(audit::NO_STORE, &crypto::fingerprint(&self.master()?.public_key()), audit::Operation::Export)?;
+                match self.positional_args.get(1) {
+                    Some(out) => {
+                        keys::write_key_file(std::path::Path::new(out), &exported)?;
+                        self.logger.display(format!(
+                            "Exported key {} to {}",
+                            crypto::fingerprint(&self.master()?.public_key()),
+                            out
+                        ));
+                    }
+                    // protected by the passphrase, but still the private key: printed as it is, like shares
+                    None => self.logger.display_secret(&exported),
                 }
             }
-            "csv" => {
-                let headers = "key,value";
-                let mut lines: Vec<String> = vec![];
-                for (key, value) in db {
-                    let dec_value = crypto::decrypt_string(&self.keypair.1, value);
-                    lines.push(format!("{},{}\n", key, dec_value));
+            "split" => {
+                let count = |option: &str, default: u8| -> error::Result<u8> {
+                    match self.get_option_value(option) {
+                        Some(value) => value.parse().map_err(|_| {
+                            Error::Config(format!("{}{} is not a number from 1 to 255", option, value))
+                        }),
+                        None => Ok(default),
+                    }
+                };
+                let (shares, threshold) = (count("--shares=", 5)?, count("--threshold=", 3)?);
+                let split = shamir::split(self.keypair()?, shares, threshold)?;
+                self.audit(audit::NO_STORE, &crypto::fingerprint(&self.master()?.public_key()), audit::Operation::Export)?;
+                self.logger.display(format!(
+                    "Split key {} in {} shares, any {} of them rebuild it with --key combine:",
+                    crypto::fingerprint(&self.master()?.public_key()),
+                    shares,
+                    threshold
+                ));
+                // the shares are the private key, printed as they are and zeroed after
+                for share in split {
+                    self.logger
+                        .display_secret(&Zeroizing::new(format!("Share {}: {}\n", share.index, share)));
+                }
+            }
+            _ => return Err(Error::Config(format!(
+                "unknown action '{}', use show, fingerprint, export, import, split or combine",
+                action
+            ))),
+        }
+        Ok(())
+    }
+
+    /**
+     * Starts, stops or shows the key agent, which keeps the unlocked key so the passphrase is typed once:
+     * gui-kvstore --agent [start --timeout=SECONDS --foreground | stop | status]
+     */
+    #[cfg(unix)]
+    fn key_agent(&self) -> error::Result<()> {
+        let action = self.positional_args.first().map(|a| a.as_str()).unwrap_or("start");
+        match action {
+            "start" => {
+                let idle_timeout = match self.get_option_value("--timeout=") {
+                    Some(seconds) => Duration::from_secs(seconds.parse().map_err(|_| {
+                        Error::Config(format!("--timeout={} is not a number of seconds", seconds))
+                    })?),
+                    None => agent::DEFAULT_IDLE_TIMEOUT,
+                };
+                let agent = agent::Agent::bind(&self.get_data_dir(), self.keypair()?, idle_timeout)?;
+                let path = agent.path().display().to_string();
+                if self.options_arg.iter().any(|o| o == "--foreground") {
+                    self.logger.display(format!(
+                        "Key agent listening on {}, it stops after {} seconds without requests",
+                        path,
+                        idle_timeout.as_secs()
+                    ));
+                    agent.serve()?;
+                    return Ok(());
+                }
+                let pid = agent.spawn()?;
+                self.logger.display(format!(
+                    "Started the key agent (pid {}) on {}, it stops after {} seconds without requests",
+                    pid,
+                    path,
+                    idle_timeout.as_secs()
+                ));
+            }
+            "stop" => match &self.agent {
+                Some(agent) => {
+                    agent.stop()?;
+                    self.logger.display("Stopped the key agent".to_string());
                 }
+                None => self.logger.display("No key agent is running".to_string()),
+            },
+            "status" => match &self.agent {
+                Some(agent) => self.logger.display(format!(
+                    "Key agent running on {} with key {}",
+                    agent.path().display(),
+                    crypto::fingerprint(&self.master()?.public_key())
+                )),
+                None => self.logger.display("No key agent is running".to_string()),
+            },
+            _ => return Err(Error::Config(format!("unknown action '{}', use start, stop or status", action))),
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn key_agent(&self) -> error::Result<()> {
+        Err(Error::Config("the key agent needs unix domain sockets".to_string()))
+    }
+
+    /**
+     * Stops the key agent after the key file changed, it would keep handing out the old key
+     */
+    fn stop_agent(&self) {
+        if let Ok(Some(agent)) = agent::AgentKey::connect(&self.get_data_dir()) {
+            if agent.stop().is_ok() {
                 self.logger
-                    .display(format!("{}\n{}\n", headers, lines.join("")));
+                    .display("Stopped the key agent, it held the previous key".to_string());
             }
-            "json" => {
-                let json_string = json::stringify_pretty(db, 4);
-                self.logger.display(json_string);
+        }
+    }
+
+    /**
+     * Logs an operation on a key in the audit log. The command fails if it can't be logged
+     */
+    fn audit(&self, store_name: &str, logged_key: &str, operation: audit::Operation) -> error::Result<()> {
+        self.audit_all(&[(store_name, logged_key, operation)])
+    }
+
+    /**
+     * Logs the operations of a command, as (store, key, operation), at once. Commands log a change
+     * once it's written, so a rejected one leaves no entry, and a read before showing the value,
+     * so nothing is shown that isn't logged
+     */
+    fn audit_all(&self, operations: &[(&str, &str, audit::Operation)]) -> error::Result<()> {
+        self.open_audit_log()?
+            .record_all(&audit::current_user(), operations)?;
+        Ok(())
+    }
+
+    /**
+     * The audit log of the data directory, its chain keyed by a key wrapped with the master key
+     */
+    fn open_audit_log(&self) -> error::Result<audit::AuditLog> {
+        audit::AuditLog::open(&self.get_data_dir(), self.master()?)
+    }
+
+    /**
+     * Checks or searches the log of who read, inserted, deleted or exported which key:
+     * gui-kvstore --audit [verify | query --store=STORE_NAME --key=KEY --user=USER --op=OPERATION --since=DATE --until=DATE]
+     */
+    fn audit_log(&self) -> error::Result<()> {
+        let log = self.open_audit_log()?;
+        let action = self.positional_args.first().map(|a| a.as_str()).unwrap_or("verify");
+        match action {
+            "verify" => {
+                let count = log.verify()?;
+                self.logger
+                    .display(format!("The audit log is intact, {} entries", count));
             }
+            "query" => {
+                let entries = log.query(&self.audit_filter()?)?;
+                self.print_audit_formatted(&entries, self.format.to_string());
+            }
+            _ => return Err(Error::Config(format!(
+                "unknown action '{}', use verify or query",
+                action
+            ))),
+        }
+        Ok(())
+    }
+
+    /**
+     * the filter of --audit query, from its options
+     */
+    fn audit_filter(&self) -> error::Result<audit::Filter> {
+        let time = |option: &str| -> error::Result<Option<u64>> {
+            self.get_option_value(option)
+                .map(|value| {
+                    audit::parse_time(&value).ok_or_else(|| {
+                        Error::Config(format!(
+                            "{}{} is not a date like 2022-03-01 or unix seconds",
+                            option, value
+                        ))
+                    })
+                })
+                .transpose()
+        };
+        let mut filter = audit::Filter {
+            user: self.get_option_value("--user="),
+            store: self.get_option_value("--store="),
+            keys: vec![],
+            operation: self
+                .get_option_value("--op=")
+                .map(|name| {
+                    audit::Operation::from_name(&name).ok_or_else(|| {
+                        Error::Config(format!(
+                            "unknown operation '{}', use read, insert, delete, export, merge, copy, drop or rotate-key",
+                            name
+                        ))
+                    })
+                })
+                .transpose()?,
+            since: time("--since=")?,
+            // a date ends the day after it starts
+            until: time("--until=")?.map(|until| match self.get_option_value("--until=") {
+                Some(value) if value.contains('-') => until + 86399,
+                _ => until,
+            }),
+        };
+        if let Some(key) = self.get_option_value("--key=") {
+            // stores with blind keys or sealed log the keyed hash of the name, which only their key finds
+            let store_path = self.get_store_path();
+            let store_names = match &filter.store {
+                Some(store_name) => vec![store_name.to_string()],
+                None => db::Database::list_stores(&store_path).unwrap_or_default(),
+            };
+            for store_name in store_names {
+                if db::Database::exists(&store_path, &store_name) {
+                    let logged_key = self.open_store(&store_name)?.logged_key(&key);
+                    if !filter.keys.contains(&logged_key) {
+                        filter.keys.push(logged_key);
+                    }
+                }
+            }
+            if !filter.keys.contains(&key) {
+                filter.keys.push(key);
+            }
+        }
+        Ok(filter)
+    }
+
+    fn print_audit_formatted(&self, entries: &[audit::Entry], formatting: String) {
+        match formatting.as_str() {
             "table" => {
-                let mut table_data: Vec<KeypairItem> = vec![];
-                for (key, value) in db {
-                    let dec_value = crypto::decrypt_string(&self.keypair.1, value);
-                    table_data.push(KeypairItem {
-                        key: key,
-                        value: dec_value,
+                let items: Vec<AuditItem> = entries.iter().map(AuditItem::from_entry).collect();
+                self.logger.display(Table::new(&items).to_string());
+            }
+            "csv" => {
+                let mut lines: Vec<String> = vec!["time,user,operation,store,key".to_string()];
+                for entry in entries {
+                    lines.push(format!(
+                        "{},{},{},{},\"{}\"",
+                        entry.timestamp,
+                        entry.user,
+                        entry.operation.name(),
+                        entry.store,
+                        entry.key.replace('"', "\"\"")
+                    ));
+                }
+                self.logger.display(lines.join("\n"));
+            }
+            "json" => {
+                let mut json_entries = json::JsonValue::new_array();
+                for entry in entries {
+                    let _ = json_entries.push(json::object! {
+                        time: entry.timestamp,
+                        user: entry.user.to_string(),
+                        operation: entry.operation.name(),
+                        store: entry.store.to_string(),
+                        key: entry.key.to_string(),
+                        hash: entry.hash.to_string(),
                     });
                 }
-                let table = Table::new(&table_data).to_string();
-                self.logger.display(table);
+                self.logger.display(json::stringify_pretty(json_entries, 4));
             }
             _ => {
-                for (key, value) in db {
-                    let dec_value = crypto::decrypt_string(&self.keypair.1, value);
-                    self.logger.display(format!("{}={}", key, dec_value));
+                for entry in entries {
+                    self.logger.display(format!(
+                        "{}  {}  {}  {}/{}",
+                        App::format_timestamp(Some(entry.timestamp)),
+                        entry.user,
+                        entry.operation.name(),
+                        entry.store,
+                        entry.key
+                    ));
+                }
+            }
+        }
+    }
+
+    /**
+     * Saves a key made by --key export as the key file, protected by the passphrase it was exported with
+     */
+    fn import_key(&self) -> error::Result<()> {
+        let source = self
+            .positional_args
+            .get(1)
+            .ok_or_else(|| Error::Config("--key import needs the path of an exported key".to_string()))?;
+        let contents = match source.as_str() {
+            "-" => std::io::read_to_string(std::io::stdin()).map_err(Error::from),
+            _ => std::fs::read_to_string(source).map_err(Error::from),
+        }?;
+        let passphrase = PassphraseSource::Terminal
+            .current("Passphrase of the exported key: ")?
+            .map(Zeroizing::new)
+            .ok_or_else(|| {
+                Error::Passphrase(format!(
+                    "the exported key is protected by a passphrase, set {} or run in a terminal",
+                    passphrase::ENV_PASSPHRASE
+                ))
+            })?;
+        let keypair = Locked::take(&mut keys::import(&contents, &passphrase)?);
+        if self.install_key(&keypair, &passphrase, source == "-")? {
+            self.logger.display(format!(
+                "Imported key {} into {}",
+                crypto::fingerprint(&keypair.0),
+                keys::key_path(&self.get_data_dir()).display()
+            ));
+        }
+        Ok(())
+    }
+
+    /**
+     * Rebuilds the key from the shares made by --key split, read from files or the standard input,
+     * one per line, and saves it as the key file with a new passphrase
+     */
+    fn combine_key(&self) -> error::Result<()> {
+        let mut paths: Vec<&str> = self.positional_args.iter().skip(1).map(|p| p.as_str()).collect();
+        if paths.is_empty() {
+            paths.push("-");
+        }
+        let from_stdin = paths.contains(&"-");
+        let mut shares = vec![];
+        for path in paths {
+            let contents = match path {
+                "-" => std::io::read_to_string(std::io::stdin()).map_err(Error::from),
+                _ => std::fs::read_to_string(path).map_err(Error::from),
+            }?;
+            for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+                shares.push(shamir::Share::parse(line)?);
+            }
+        }
+        let keypair = Locked::take(&mut shamir::combine(&shares)?);
+        let passphrase = Zeroizing::new(
+            PassphraseSource::Terminal
+                .new_key()?
+                .unwrap_or_default(),
+        );
+        if self.install_key(&keypair, &passphrase, from_stdin)? {
+            self.logger.display(format!(
+                "Rebuilt key {} from {} shares into {}",
+                crypto::fingerprint(&keypair.0),
+                shares.len(),
+                keys::key_path(&self.get_data_dir()).display()
+            ));
+        }
+        Ok(())
+    }
+
+    /**
+     * Saves keypair as the key file, protected by passphrase unless it's empty. A different key
+     * already there is archived next to the keys rotated out, after a confirmation.
+     * Returns false if nothing was saved. With from_stdin the confirmation is read from the terminal
+     */
+    fn install_key(&self, keypair: &keys::KeyPair, passphrase: &str, from_stdin: bool) -> error::Result<bool> {
+        let data_dir = self.get_data_dir();
+        let path = keys::key_path(&data_dir);
+        let fingerprint = crypto::fingerprint(&keypair.0);
+        if path.exists() {
+            let current = crypto::fingerprint(&keys::public_key(&path)?);
+            if current == fingerprint {
+                self.logger
+                    .display(format!("Key {} is already in {}", fingerprint, path.display()));
+                return Ok(false);
+            }
+            let question = format!(
+                "Replace key {} in {} with key {}? The stores written with it can only be opened with its archived copy",
+                current,
+                path.display(),
+                fingerprint
+            );
+            let confirmed = match from_stdin {
+                true => self.confirm_on_terminal(question)?,
+                false => self.confirm(question),
+            };
+            if !confirmed {
+                self.logger.display("Aborted".to_string());
+                return Ok(false);
+            }
+            let archive = data_dir.join(rotate::ARCHIVE_DIR);
+            std::fs::create_dir_all(&archive)
+                .and_then(|_| std::fs::copy(&path, archive.join(format!("{}.key", current))))?;
+        }
+        std::fs::create_dir_all(&data_dir)?;
+        match passphrase.is_empty() {
+            true => keys::save(&path, keypair),
+            false => keys::save_protected(&path, keypair, passphrase, keys::KdfParams::default()),
+        }?;
+        self.stop_agent();
+        Ok(true)
+    }
+
+    /**
+     * Describes store limits in one line
+     */
+    fn format_limits(limits: &Limits) -> String {
+        let mut parts = vec![];
+        if let Some(max) = limits.max_keys {
+            parts.push(format!("{} keys", max));
+        }
+        if let Some(max) = limits.max_value_bytes {
+            parts.push(format!("{} bytes per value", max));
+        }
+        if let Some(max) = limits.max_bytes {
+            parts.push(format!("{} bytes in total", max));
+        }
+        if let Some(eviction) = limits.eviction {
+            parts.push(format!("cache evicting {}", eviction.name()));
+        }
+        if parts.is_empty() {
+            return "none".to_string();
+        }
+        parts.join(", ")
+    }
+
+    /**
+     * Displays size analytics for the selected store, or for every store with --all
+     */
+    fn print_stats(&self) -> error::Result<()> {
+        let formatting = self.format.to_string();
+        let all_stores = self.options_arg.iter().any(|o| o == "--all");
+        let store_path = self.get_store_path();
+        let store_names = if all_stores {
+            db::Database::list_stores(&store_path).unwrap_or_default()
+        } else {
+            vec![self.get_store_arg(0)]
+        };
+        let mut items: Vec<StatsItem> = vec![];
+        let mut all_stats: Vec<db::StoreStats> = vec![];
+        for store_name in store_names {
+            if !db::Database::exists(&store_path, &store_name) {
+                self.logger
+                    .display(format!("Store not found: '{}'", store_name));
+                return Ok(());
+            }
+            let stats = self.open_store(&store_name)?.stats()?;
+            items.push(StatsItem::from_stats(&store_name, std::slice::from_ref(&stats)));
+            all_stats.push(stats);
+        }
+        // the total across stores, keys get prefixed with their store
+        if all_stores {
+            items.push(StatsItem::from_stats("total", &all_stats));
+        }
+        self.print_stats_formatted(items, formatting);
+        Ok(())
+    }
+
+    fn print_stats_formatted(&self, items: Vec<StatsItem>, formatting: String) {
+        match formatting.as_str() {
+            "table" => self.logger.display(Table::new(&items).to_string()),
+            "csv" => {
+                let mut lines: Vec<String> = vec![
+                    "store,keys,total_bytes,average_bytes,largest_keys,encryption_overhead,hex_overhead,expired,fragmentation".to_string(),
+                ];
+                for item in items {
+                    lines.push(format!(
+                        "{},{},{},{},\"{}\",{},{},{},{}",
+                        item.store,
+                        item.keys,
+                        item.total_bytes,
+                        item.average_bytes,
+                        item.largest_keys,
+                        item.encryption_overhead,
+                        item.hex_overhead,
+                        item.expired,
+                        item.fragmentation
+                    ));
                 }
+                self.logger.display(lines.join("\n"));
             }
+            "json" => {
+                let mut json_items = json::JsonValue::new_array();
+                for item in items {
+                    let _ = json_items.push(json::object! {
+                        store: item.store,
+                        keys: item.keys,
+                        total_bytes: item.total_bytes,
+                        average_bytes: item.average_bytes,
+                        largest_keys: item.largest_keys,
+                        encryption_overhead: item.encryption_overhead,
+                        hex_overhead: item.hex_overhead,
+                        expired: item.expired,
+                        fragmentation: item.fragmentation,
+                    });
+                }
+                // a single store is printed as an object, --all as an array
+                if json_items.len() == 1 {
+                    json_items = json_items.array_remove(0);
+                }
+                self.logger.display(json::stringify_pretty(json_items, 4));
+            }
+            _ => {
+                for item in items {
+                    self.logger.display(format!("Store:               {}", item.store));
+                    self.logger.display(format!("Keys:                {}", item.keys));
+                    self.logger
+                        .display(format!("Total value size:    {} bytes", item.total_bytes));
+                    self.logger
+                        .display(format!("Average value size:  {} bytes", item.average_bytes));
+                    self.logger
+                        .display(format!("Largest keys:        {}", item.largest_keys));
+                    self.logger.display(format!(
+                        "Encryption overhead: {} bytes",
+                        item.encryption_overhead
+                    ));
+                    self.logger
+                        .display(format!("Hex overhead:        {} bytes", item.hex_overhead));
+                    self.logger.display(format!("Expired entries:     {}", item.expired));
+                    self.logger
+                        .display(format!("Fragmentation:       {}", format_percent(&item.fragmentation)));
+                    self.logger.display("".to_string());
+                }
+            }
+        }
+    }
+
+    /**
+     * Formats unix seconds like format_time, unknown when missing
+     */
+    fn format_timestamp(secs: Option<u64>) -> String {
+        match secs {
+            Some(secs) => App::format_time(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+            None => "unknown".to_string(),
+        }
+    }
+
+    /**
+     * Formats a timestamp as UTC, like 2022-03-01 18:30:00 UTC
+     */
+    fn format_time(time: SystemTime) -> String {
+        let secs = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        // civil date from days since epoch, see http://howardhinnant.github.io/date_algorithms.html
+        let days = (secs / 86400) as i64 + 719468;
+        let era = days / 146097;
+        let doe = days - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            year,
+            month,
+            day,
+            secs % 86400 / 3600,
+            secs % 3600 / 60,
+            secs % 60
+        )
+    }
+
+    fn print_version(&self) {
+        //showing the version information from Cargo
+        const VERSION: &str = env!("CARGO_PKG_VERSION");
+        self.logger
+            .display(format!("gui-kvstore version: {}", VERSION));
+    }
+
+    /**
+     * Display command usage
+     */
+    fn print_help(&self) {
+        let logger = &self.logger;
+        logger.display("Usage:".to_string());
+        logger.display("\tgui-kvstore KEY VALUE --debug=true|false --f=default|csv|json|short|table --store=STORE_NAME".to_string());
+        logger.display("Saves a VALUE string with a key with name of KEY".to_string());
+        logger.display("\nOptions:".to_string());
+        logger.display("\t--debug=true|false               - toggles debug output".to_string());
+        logger.display("\t--f=default|csv|json|short|table - specifies the format to read".to_string());
+        logger.display("\t--store=STORE_NAME               - reads/writes value in a specific db store file".to_string());
+        logger.display("\t--data-dir=PATH                  - directory holding the key and the stores".to_string());
+        logger.display("\t--engine=file|memory             - memory keeps the store in memory only".to_string());
+        logger.display("\t--no-agent                       - loads the key file even when the key agent is running".to_string());
+        //
+        logger.display("\nOther Commands:".to_string());
+        logger.display("\tgui-kvstore --delete KEY --store=STORE_NAME".to_string());
+        logger.display("Deletes KEY from the store".to_string());
+        logger.display("\n\tgui-kvstore --put-file KEY PATH --store=STORE_NAME".to_string());
+        logger.display("Saves the contents of the file at PATH as KEY, encrypted in chunks in the blob directory".to_string());
+        logger.display("\n\tgui-kvstore --get-file KEY --out=PATH --store=STORE_NAME".to_string());
+        logger.display("Writes a file saved with --put-file to PATH, or to the standard output without --out".to_string());
+        logger.display("\n\tgui-kvstore --stores".to_string());
+        logger.display("Prints all the stores created".to_string());
+        logger.display("\n\tgui-kvstore KEY VALUE --store=STORE_NAME".to_string());
+        logger.display("Saves a VALUE string with a key with name of KEY in the store STORE_NAME".to_string());
+        logger.display("".to_string());
+        logger.display("gui-kvstore --print --debug=true|false --f=default|csv|json|short|table --store=STORE_NAME".to_string());
+        logger.display("Prints all key-pairs saved in the store".to_string());
+        logger.display("".to_string());
+        logger.display("\nStore Commands:".to_string());
+        logger.display("\tgui-kvstore --create STORE_NAME           - creates an empty store, encrypted whole with --sealed, unencrypted with --plaintext".to_string());
+        logger.display("\tgui-kvstore --drop STORE_NAME --yes       - deletes a store, --yes skips the confirmation".to_string());
+        logger.display("\tgui-kvstore --rename OLD_STORE NEW_STORE  - renames a store".to_string());
+        logger.display("\tgui-kvstore --copy SRC_STORE DST_STORE    - clones a store".to_string());
+        logger.display("\tgui-kvstore --info STORE_NAME             - shows key count, size, last modification, format and key fingerprint".to_string());
+        logger.display("\tgui-kvstore --stats STORE_NAME --all      - shows size analytics of a store, or of every store with --all".to_string());
+        logger.display("\tgui-kvstore --merge SRC_STORE DST_STORE --policy=ours|theirs|newest|interactive --dry-run".to_string());
+        logger.display("\t                                          - merges SRC into DST, the policy picks the value of keys that differ".to_string());
+        logger.display("\tgui-kvstore --diff A B --f=text|json|unified --reveal".to_string());
+        logger.display("\t                                          - lists keys added, removed and changed from A to B: stores, store files,".to_string());
+        logger.display("\t                                            data directory snapshots or tar archives (PATH:STORE). --reveal shows values".to_string());
+        logger.display("\tgui-kvstore --passwd                      - sets, changes or removes (when empty) the passphrase protecting the private key".to_string());
+        logger.display("\tgui-kvstore --rotate-key                  - re-encrypts every store with a new key pair and archives the old one".to_string());
+        logger.display("\tgui-kvstore --blind-keys STORE_NAME on|off - saves key names as a keyed hash, with the names encrypted with the values".to_string());
+        logger.display("\tgui-kvstore --seal STORE_NAME on|off     - encrypts the whole store file, hiding its keys and their sizes".to_string());
+        logger.display("\tgui-kvstore --plaintext STORE_NAME on|off - saves the values unencrypted, for settings that aren't secret, or encrypts them again".to_string());
+        logger.display("\tgui-kvstore --recipients STORE_NAME [list | add PUBLIC_KEY NAME | remove NAME] - shares a store with the holders of other keys".to_string());
+        logger.display("\tgui-kvstore --public-key                  - prints the public key, for others to share stores with it".to_string());
+        logger.display("\tgui-kvstore --key show | fingerprint [STORE_NAME] - shows the key file, or the fingerprint of the key, or of the key that wrote a store".to_string());
+        logger.display("\tgui-kvstore --key export [PATH] --armor  - copies the key protected by a passphrase, as a printable text block with --armor".to_string());
+        logger.display("\tgui-kvstore --key import PATH            - replaces the key with an exported one, archiving the current key".to_string());
+        logger.display("\tgui-kvstore --key split --shares=5 --threshold=3 - prints shares of the private key, any threshold of them rebuild it".to_string());
+        logger.display("\tgui-kvstore --key combine [PATH...]      - rebuilds the key from shares, one per line in the files or the standard input".to_string());
+        logger.display("\tgui-kvstore --agent start --timeout=SECONDS --foreground | stop | status".to_string());
+        logger.display("\t                                          - keeps the unlocked key in a background agent, until SECONDS go by without requests".to_string());
+        logger.display("\tgui-kvstore --audit verify                - checks that no entry of the audit log was edited or removed".to_string());
+        logger.display("\tgui-kvstore --audit query --store=STORE_NAME --key=KEY --user=USER --op=read|insert|delete|export|merge|copy|drop|rotate-key --since=DATE --until=DATE".to_string());
+        logger.display("\t                                          - lists who read, inserted, deleted or exported which key, changed which store, and when".to_string());
+        logger.display("\tgui-kvstore --limits STORE_NAME --max-keys=N --max-value-bytes=N --max-bytes=N --eviction=lru|lfu|ttl-first".to_string());
+        logger.display("\t                                          - shows or sets the limits of a store, none removes one. an eviction policy makes it a cache".to_string());
+        logger.display("".to_string());
+    }
+
+    fn print_keypair_formatted(&self, key: &str, value: &str, formatting: String) {
+        // a single pair is followed by one blank line in csv, a whole store by two
+        if formatting == "csv" {
+            self.logger.display_secret(&Zeroizing::new(format!("key,value\n{},{}\n\n", key, value)));
+            return;
+        }
+        self.print_store_formatted(&[(key.to_string(), Zeroizing::new(value.to_string()))], formatting);
+    }
+
+    /**
+     * Prints the decrypted key-pairs of a store in the selected format
+     */
+    fn print_store_formatted(&self, pairs: &[(String, Zeroizing<String>)], formatting: String) {
+        // what's printed holds the values too, it's zeroed once it's out
+        let output = Zeroizing::new(match formatting.as_str() {
+            "short" => pairs.iter().map(|(_, value)| format!("{}\n", value.as_str())).collect(),
+            "csv" => {
+                let lines: String = pairs
+                    .iter()
+                    .map(|(key, value)| format!("{},{}\n", key, value.as_str()))
+                    .collect();
+                format!("key,value\n{}\n\n", lines)
+            }
+            "json" => {
+                let mut json_object = json::JsonValue::new_object();
+                for (key, value) in pairs {
+                    json_object[key.as_str()] = value.as_str().into();
+                }
+                let json_string = Zeroizing::new(json::stringify_pretty(json_object, 4));
+                format!("{}\n", json_string.as_str())
+            }
+            "table" => {
+                let table_data: Vec<KeypairItem> = pairs
+                    .iter()
+                    .map(|(key, value)| KeypairItem { key, value })
+                    .collect();
+                format!("{}\n", Table::new(&table_data))
+            }
+            _ => pairs
+                .iter()
+                .map(|(key, value)| format!("{}={}\n", key, value.as_str()))
+                .collect(),
+        });
+        self.logger.display_secret(&output);
+    }
+
+    /**
+     * The data directory resolved from --data-dir, GUI_KVSTORE_HOME, the config file or the XDG paths
+     */
+    fn get_data_dir(&self) -> PathBuf {
+        self.data_dir.to_path_buf()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn run(data_dir: &Path, arguments: &[&str]) -> i32 {
+        let mut all = vec!["gui-kvstore".to_string()];
+        all.extend(arguments.iter().map(|a| a.to_string()));
+        all.push(format!("--data-dir={}", data_dir.display()));
+        App::new(all).init()
+    }
+
+    #[test]
+    fn commands_are_audited() {
+        let data_dir = TempDir::new("app-audit");
+        std::fs::create_dir_all(&data_dir).unwrap();
+        // a key without a passphrase, so nothing asks for one
+        let master = keys::KeyPair::from(crypto::generate_key_pair().unwrap());
+        keys::save(&keys::key_path(&data_dir), &master).unwrap();
+        for name in ["a", "b"] {
+            let mut store = Store::open_with_keypair(&data_dir, name, db::Engine::File, false, &master).unwrap();
+            store.set("key", name).unwrap();
+        }
+        let entries = || {
+            let master = keys::load(&keys::key_path(&data_dir)).unwrap();
+            let log = audit::AuditLog::open(&data_dir, &master).unwrap();
+            log.verify().unwrap();
+            log.entries().unwrap()
+        };
+        // every command changing a whole store logs one entry
+        let commands: [(&[&str], &str, &str, audit::Operation); 4] = [
+            (&["--merge", "a", "b", "--policy=theirs"], "b", "a", audit::Operation::Merge),
+            (&["--copy", "a", "c"], "c", "a", audit::Operation::Copy),
+            (&["--drop", "c", "--yes"], "c", audit::NO_KEY, audit::Operation::Drop),
+            (&["--rotate-key"], audit::NO_STORE, &crypto::fingerprint(&master.0), audit::Operation::RotateKey),
+        ];
+        for (arguments, store, key, operation) in commands {
+            let before = entries().len();
+            assert_eq!(run(&data_dir, arguments), 0);
+            let after = entries();
+            assert_eq!(after.len(), before + 1, "{:?}", arguments);
+            let last = after.last().unwrap();
+            assert_eq!((last.store.as_str(), last.key.as_str(), last.operation), (store, key, operation));
         }
+        // the names of sealed stores are hidden in their file, and in the log
+        assert_eq!(run(&data_dir, &["--create", "sealed", "--sealed"]), 0);
+        assert_eq!(run(&data_dir, &["token", "value", "--store=sealed"]), 0);
+        let last = entries().pop().unwrap();
+        assert_eq!((last.store.as_str(), last.operation), ("sealed", audit::Operation::Insert));
+        assert_ne!(last.key, "token");
     }
 
-    fn get_data_dir() -> std::path::PathBuf {
-        std::env::home_dir()
-            .unwrap_or_default()
-            .join(".gui-kvstore") //joining with app directory
+    #[test]
+    fn failed_writes_unlock_the_store() {
+        let data_dir = TempDir::new("app-unlock");
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let master = keys::KeyPair::from(crypto::generate_key_pair().unwrap());
+        keys::save(&keys::key_path(&data_dir), &master).unwrap();
+        let operations = || -> Vec<audit::Operation> {
+            let log = audit::AuditLog::open(&data_dir, &master).unwrap();
+            log.entries().unwrap().iter().map(|entry| entry.operation).collect()
+        };
+        assert_eq!(run(&data_dir, &["--limits", "default", "--max-keys=1"]), 0);
+        assert_eq!(run(&data_dir, &["one", "value"]), 0);
+        // the error comes back as the exit code, once the store is closed
+        assert_eq!(run(&data_dir, &["two", "value"]), error::EXIT_QUOTA);
+        assert!(!data_dir.join("data").join("default.db.lock").exists());
+        assert_eq!(run(&data_dir, &["--delete", "one"]), 0);
+        // the rejected insert wasn't logged
+        assert_eq!(operations(), vec![audit::Operation::Insert, audit::Operation::Delete]);
     }
 }
@@ -0,0 +1,269 @@
+use std::io::{ErrorKind, Read};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::error::{Error, Result};
+
+// tar files are made of 512 byte blocks: a header, then the contents padded to a whole block
+const BLOCK: usize = 512;
+
+// names the extraction folders of a process apart
+static EXTRACTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/**
+ * A backup archive, a tar file of a data directory like the one made by
+ * `tar cf backup.tar -C ~/.local/share gui-kvstore`, extracted in a temporary folder
+ * only its user can read. The folder is deleted when it's dropped.
+ * Only what a data directory holds is read back: folders and regular files, without compression
+ */
+pub struct Extracted {
+    root: PathBuf,
+    data_dir: PathBuf,
+}
+
+impl Extracted {
+    /**
+     * Extracts archive and finds the data directory in it, at its root or in a folder of its root
+     */
+    pub fn open(archive: &Path) -> Result<Extracted> {
+        let root = std::env::temp_dir().join(format!(
+            "gui-kvstore-archive-{}-{}",
+            std::process::id(),
+            EXTRACTIONS.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::DirBuilder::new().mode(0o700).create(&root)?;
+        // the folder goes away on any error from here on
+        let mut extracted = Extracted {
+            root: root.clone(),
+            data_dir: root.clone(),
+        };
+        extract(&mut std::fs::File::open(archive)?, &root)?;
+        extracted.data_dir = find_data_dir(&root).ok_or_else(|| {
+            Error::Format(format!("{} doesn't hold a data directory", archive.display()))
+        })?;
+        Ok(extracted)
+    }
+
+    /**
+     * The data directory of the archive, holding the data and blobs folders
+     */
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+}
+
+impl Drop for Extracted {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+/**
+ * If the file at path is a tar archive, rather than a store file
+ */
+pub fn is_archive(path: &Path) -> bool {
+    let mut header = [0u8; BLOCK];
+    std::fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut header))
+        .map(|_| header[257..262] == *b"ustar" && checksum_matches(&header))
+        .unwrap_or(false)
+}
+
+/**
+ * writes the folders and files of the tar archive read from reader into dir.
+ * entries leaving dir, like absolute paths or ones going up with .., are an error
+ */
+fn extract(reader: &mut impl Read, dir: &Path) -> Result<()> {
+    // set by the entries giving the name of the next one when it doesn't fit in its header
+    let mut long_name: Option<String> = None;
+    let mut header = [0u8; BLOCK];
+    loop {
+        if !read_block(reader, &mut header)? || header.iter().all(|b| *b == 0) {
+            return Ok(());
+        }
+        if !checksum_matches(&header) {
+            return Err(Error::Format("the archive is not a tar file, or it's damaged".to_string()));
+        }
+        let size = octal(&header[124..136])?;
+        let name = match long_name.take() {
+            Some(name) => name,
+            None => header_name(&header),
+        };
+        match header[156] {
+            // GNU long name, the contents are the name of the next entry
+            b'L' => long_name = Some(text(&read_contents(reader, size)?)),
+            // pax extended header, only the path is used
+            b'x' => {
+                long_name = pax_path(&read_contents(reader, size)?);
+            }
+            b'0' | 0 => {
+                let path = dir.join(relative(&name)?);
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let mut file = std::fs::File::create(&path)?;
+                std::io::copy(&mut reader.take(size), &mut file)?;
+                skip_padding(reader, size)?;
+            }
+            b'5' => std::fs::create_dir_all(dir.join(relative(&name)?))?,
+            // links, devices and global headers aren't part of a data directory
+            _ => {
+                read_contents(reader, size)?;
+            }
+        }
+    }
+}
+
+/**
+ * the folder holding a data folder, at the root or one level below
+ */
+fn find_data_dir(root: &Path) -> Option<PathBuf> {
+    if root.join("data").is_dir() {
+        return Some(root.to_path_buf());
+    }
+    std::fs::read_dir(root)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .find(|path| path.join("data").is_dir())
+}
+
+/**
+ * reads a whole block, false at the end of the archive
+ */
+fn read_block(reader: &mut impl Read, block: &mut [u8; BLOCK]) -> Result<bool> {
+    match reader.read_exact(block) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn read_contents(reader: &mut impl Read, size: u64) -> Result<Vec<u8>> {
+    let mut contents = vec![];
+    reader.take(size).read_to_end(&mut contents)?;
+    if (contents.len() as u64) < size {
+        return Err(Error::Format("the archive is cut off".to_string()));
+    }
+    skip_padding(reader, size)?;
+    Ok(contents)
+}
+
+fn skip_padding(reader: &mut impl Read, size: u64) -> Result<()> {
+    let padding = (BLOCK as u64 - size % BLOCK as u64) % BLOCK as u64;
+    std::io::copy(&mut reader.take(padding), &mut std::io::sink())?;
+    Ok(())
+}
+
+/**
+ * the checksum of a header is the sum of its bytes, with its own field counted as spaces
+ */
+fn checksum_matches(header: &[u8; BLOCK]) -> bool {
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, b)| if (148..156).contains(&i) { b' ' as u64 } else { *b as u64 })
+        .sum();
+    octal(&header[148..156]).map(|checksum| checksum == sum).unwrap_or(false)
+}
+
+fn octal(field: &[u8]) -> Result<u64> {
+    let digits = text(field);
+    let digits = digits.trim_matches(|c: char| c == ' ' || c == '\0');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| Error::Format("the archive has an invalid header".to_string()))
+}
+
+/**
+ * text up to the first nul byte
+ */
+fn text(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).to_string()
+}
+
+/**
+ * the name of an entry, with the prefix ustar keeps longer paths in
+ */
+fn header_name(header: &[u8; BLOCK]) -> String {
+    let (name, prefix) = (text(&header[..100]), text(&header[345..500]));
+    match prefix.is_empty() {
+        true => name,
+        false => format!("{}/{}", prefix, name),
+    }
+}
+
+/**
+ * the path of a pax extended header, made of "length key=value\n" records
+ */
+fn pax_path(contents: &[u8]) -> Option<String> {
+    text(contents)
+        .lines()
+        .filter_map(|record| record.split_once(' ').map(|(_, field)| field))
+        .find_map(|field| field.strip_prefix("path=").map(|path| path.to_string()))
+}
+
+fn relative(name: &str) -> Result<PathBuf> {
+    let path = Path::new(name);
+    let inside = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    match inside {
+        true => Ok(path.to_path_buf()),
+        false => Err(Error::Format(format!("the archive has an entry outside of it: {}", name))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn entry(archive: &mut Vec<u8>, name: &str, typeflag: u8, contents: &[u8]) {
+        let mut header = [0u8; BLOCK];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(b"0000644");
+        header[124..135].copy_from_slice(format!("{:011o}", contents.len()).as_bytes());
+        header[156] = typeflag;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[148..156].copy_from_slice(b"        ");
+        let sum: u64 = header.iter().map(|b| *b as u64).sum();
+        header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(contents);
+        archive.resize(archive.len().div_ceil(BLOCK) * BLOCK, 0);
+    }
+
+    #[test]
+    fn backup_archives() {
+        let dir = TempDir::new("archive-test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut archive = vec![];
+        entry(&mut archive, "gui-kvstore/", b'5', b"");
+        entry(&mut archive, "gui-kvstore/data/staging.db", b'0', b"#version=2\n");
+        let long_name = format!("gui-kvstore/blobs/ab/{}", "c".repeat(100));
+        entry(&mut archive, "././@LongLink", b'L', long_name.as_bytes());
+        entry(&mut archive, "ignored", b'0', &[7u8; 600]);
+        archive.extend_from_slice(&[0u8; BLOCK * 2]);
+        let path = dir.join("backup.tar");
+        std::fs::write(&path, &archive).unwrap();
+        assert!(is_archive(&path));
+        let extracted = Extracted::open(&path).unwrap();
+        let data_dir = extracted.data_dir().to_path_buf();
+        assert_eq!(std::fs::read(data_dir.join("data/staging.db")).unwrap(), b"#version=2\n");
+        assert_eq!(std::fs::read(data_dir.join("blobs/ab").join("c".repeat(100))).unwrap(), vec![7u8; 600]);
+        drop(extracted);
+        assert!(!data_dir.exists());
+        // entries can't be written outside of the folder
+        let mut archive = vec![];
+        entry(&mut archive, "../data/staging.db", b'0', b"x");
+        std::fs::write(&path, &archive).unwrap();
+        assert!(matches!(Extracted::open(&path), Err(Error::Format(_))));
+        assert!(!dir.parent().unwrap().join("data/staging.db").exists());
+        // store files aren't archives
+        std::fs::write(&path, "#version=2\n").unwrap();
+        assert!(!is_archive(&path));
+    }
+}
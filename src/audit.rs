@@ -0,0 +1,571 @@
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::crypto;
+use crate::db::{self, StoreLock};
+use crate::error::{Error, Result};
+use crate::keys::{self, KeyPair, MasterKey};
+
+// the audit log, inside the data directory
+pub static AUDIT_FILE: &str = "audit.log";
+// count and hash of the last entry of the audit log, so entries removed at its end are noticed
+pub static HEAD_FILE: &str = "audit.head";
+// key of the hashes chaining the entries, wrapped with the master key
+pub static KEY_FILE: &str = "audit.key";
+// store of the entries about the key itself, like its exports
+pub static NO_STORE: &str = "-";
+// key of the entries about a whole store, like dropping it
+pub static NO_KEY: &str = "-";
+// hash the first entry is chained to
+static GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+// how long to wait for another process appending to the log
+const LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/**
+ * What was done to a key
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Operation {
+    Read,
+    Insert,
+    Delete,
+    // the private key leaving the data directory, exported or split in shares
+    Export,
+    // a store merged into another one, or copied to a new one, the key is the source store
+    Merge,
+    Copy,
+    // a whole store deleted
+    Drop,
+    // the master key replaced, the key is the fingerprint of the old one
+    RotateKey,
+}
+
+impl Operation {
+    pub fn from_name(name: &str) -> Option<Operation> {
+        match name {
+            "read" => Some(Operation::Read),
+            "insert" => Some(Operation::Insert),
+            "delete" => Some(Operation::Delete),
+            "export" => Some(Operation::Export),
+            "merge" => Some(Operation::Merge),
+            "copy" => Some(Operation::Copy),
+            "drop" => Some(Operation::Drop),
+            "rotate-key" => Some(Operation::RotateKey),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operation::Read => "read",
+            Operation::Insert => "insert",
+            Operation::Delete => "delete",
+            Operation::Export => "export",
+            Operation::Merge => "merge",
+            Operation::Copy => "copy",
+            Operation::Drop => "drop",
+            Operation::RotateKey => "rotate-key",
+        }
+    }
+}
+
+/**
+ * One line of the audit log
+ */
+#[derive(Clone, PartialEq, Debug)]
+pub struct Entry {
+    // unix timestamp in seconds
+    pub timestamp: u64,
+    pub user: String,
+    pub store: String,
+    pub key: String,
+    pub operation: Operation,
+    // hash of this entry and the one before it, hex encoded
+    pub hash: String,
+}
+
+impl Entry {
+    /**
+     * the line of the entry in the log, without its hash
+     */
+    fn fields(&self) -> String {
+        [
+            self.timestamp.to_string(),
+            db::escape(&self.user),
+            db::escape(&self.store),
+            db::escape(&self.key),
+            self.operation.name().to_string(),
+        ]
+        .join("\t")
+    }
+
+    /**
+     * HMAC of the entry chained to the hash of the one before it, keyed by the key of the log
+     */
+    fn chained_hash(&self, key: &[u8; 32], previous: &str) -> String {
+        crypto::encode_hex(&crypto::keyed_hash(key, format!("{}\t{}", previous, self.fields()).as_bytes()))
+    }
+
+    fn parse(line: &str) -> Option<Entry> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields[..] {
+            [timestamp, user, store, key, operation, hash] => Some(Entry {
+                timestamp: timestamp.parse().ok()?,
+                user: db::unescape(user),
+                store: db::unescape(store),
+                key: db::unescape(key),
+                operation: Operation::from_name(operation)?,
+                hash: hash.to_string(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Which entries a query returns, every field set has to match
+ */
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct Filter {
+    pub user: Option<String>,
+    pub store: Option<String>,
+    // any of these names, a store with blind keys or sealed logs the keyed hash of a name
+    pub keys: Vec<String>,
+    pub operation: Option<Operation>,
+    // unix timestamps, both included
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+}
+
+impl Filter {
+    pub fn matches(&self, entry: &Entry) -> bool {
+        self.user.as_ref().is_none_or(|user| *user == entry.user)
+            && self.store.as_ref().is_none_or(|store| *store == entry.store)
+            && (self.keys.is_empty() || self.keys.contains(&entry.key))
+            && self.operation.is_none_or(|operation| operation == entry.operation)
+            && self.since.is_none_or(|since| entry.timestamp >= since)
+            && self.until.is_none_or(|until| entry.timestamp <= until)
+    }
+}
+
+/**
+ * Append-only log of who read, inserted, deleted or exported what, and when. Every entry holds
+ * an HMAC of itself and of the hash of the entry before it, so editing or removing one breaks the
+ * chain from there on. The key of the HMAC is random, saved in another file wrapped with the master
+ * key, so the chain can't be rebuilt without the master key. The count and hash of the last entry
+ * are kept in another file too, to tell entries removed at the end. It's tamper evident, not tamper
+ * proof: whoever holds the master key can rebuild the whole chain, so copies of it have to be kept
+ * elsewhere for that
+ */
+pub struct AuditLog {
+    path: PathBuf,
+    head_path: PathBuf,
+    key_path: PathBuf,
+    key: [u8; 32],
+}
+
+impl AuditLog {
+    /**
+     * The audit log of data_dir, with its key unwrapped by master. The key is made the first
+     * time, the other files of the log are created with its first entry
+     */
+    pub fn open(data_dir: &Path, master: &dyn MasterKey) -> Result<AuditLog> {
+        let mut log = AuditLog::at(data_dir);
+        if !log.key_path.exists() {
+            std::fs::create_dir_all(data_dir)?;
+            let _lock = log.lock()?;
+            // another process may have made it while this one waited
+            if !log.key_path.exists() {
+                if !log.entries()?.is_empty() {
+                    return Err(Error::Corrupt(format!(
+                        "{} is missing, the entries of the audit log can't be checked",
+                        log.key_path.display()
+                    )));
+                }
+                let key = Zeroizing::new(crypto::random_bytes::<32>()?);
+                log.write_key(&master.public_key(), &key[..])?;
+            }
+        }
+        let key = log.read_key(master)?;
+        log.key.copy_from_slice(&key);
+        Ok(log)
+    }
+
+    fn at(data_dir: &Path) -> AuditLog {
+        AuditLog {
+            path: data_dir.join(AUDIT_FILE),
+            head_path: data_dir.join(HEAD_FILE),
+            key_path: data_dir.join(KEY_FILE),
+            key: [0u8; 32],
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /**
+     * Appends an entry for an operation of user on key of store, done now
+     */
+    pub fn record(&self, user: &str, store: &str, key: &str, operation: Operation) -> Result<Entry> {
+        let mut entries = self.record_all(user, &[(store, key, operation)])?;
+        Ok(entries.remove(0))
+    }
+
+    /**
+     * Appends the entries of the operations of user, as (store, key, operation), at once:
+     * a command reading a whole store writes the log and syncs it a single time
+     */
+    pub fn record_all(&self, user: &str, operations: &[(&str, &str, Operation)]) -> Result<Vec<Entry>> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let _lock = self.lock()?;
+        let (count, previous) = match self.read_head()? {
+            Some(head) => head,
+            // a log from before the head file, or a new one
+            None => {
+                let entries = self.entries()?;
+                match entries.last() {
+                    Some(last) => (entries.len() as u64, last.hash.to_string()),
+                    None => (0, GENESIS.to_string()),
+                }
+            }
+        };
+        let (mut entries, mut previous, mut lines) = (vec![], previous, String::new());
+        for (store, key, operation) in operations {
+            let mut entry = Entry {
+                timestamp: db::now(),
+                user: user.to_string(),
+                store: store.to_string(),
+                key: key.to_string(),
+                operation: *operation,
+                hash: String::new(),
+            };
+            entry.hash = entry.chained_hash(&self.key, &previous);
+            lines.push_str(&format!("{}\t{}\n", entry.fields(), entry.hash));
+            previous = entry.hash.to_string();
+            entries.push(entry);
+        }
+        if entries.is_empty() {
+            return Ok(entries);
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(lines.as_bytes())?;
+        file.sync_data()?;
+        // writing next to it and renaming, like store files
+        let tmp_path = self.head_path.with_extension("tmp");
+        std::fs::write(&tmp_path, format!("{}\t{}\n", count + entries.len() as u64, previous))?;
+        std::fs::rename(&tmp_path, &self.head_path)?;
+        Ok(entries)
+    }
+
+    /**
+     * Every entry of the log, oldest first, without checking the chain
+     */
+    pub fn entries(&self) -> Result<Vec<Entry>> {
+        let file = match std::fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        BufReader::new(file)
+            .lines()
+            .enumerate()
+            .map(|(number, line)| {
+                Entry::parse(&line?).ok_or_else(|| {
+                    Error::Corrupt(format!("entry {} of {} is malformed", number + 1, self.path.display()))
+                })
+            })
+            .collect()
+    }
+
+    /**
+     * The entries matching filter, oldest first
+     */
+    pub fn query(&self, filter: &Filter) -> Result<Vec<Entry>> {
+        Ok(self.entries()?.into_iter().filter(|entry| filter.matches(entry)).collect())
+    }
+
+    /**
+     * Checks the chain of hashes, returning how many entries there are. An entry edited, removed
+     * or inserted is a Corrupt error naming the first one that doesn't match
+     */
+    pub fn verify(&self) -> Result<u64> {
+        let entries = self.entries()?;
+        let mut previous = GENESIS.to_string();
+        for (number, entry) in entries.iter().enumerate() {
+            if entry.chained_hash(&self.key, &previous) != entry.hash {
+                return Err(Error::Corrupt(format!(
+                    "entry {} of the audit log was edited, or an entry before it was removed",
+                    number + 1
+                )));
+            }
+            previous = entry.hash.to_string();
+        }
+        let count = entries.len() as u64;
+        match self.read_head()? {
+            Some((head_count, _)) if head_count > count => Err(Error::Corrupt(format!(
+                "entries were removed from the end of the audit log, it ends at entry {} of {}",
+                count, head_count
+            ))),
+            Some((head_count, hash)) if head_count != count || hash != previous => Err(Error::Corrupt(format!(
+                "the last entry of the audit log doesn't match {}, entries were replaced",
+                self.head_path.display()
+            ))),
+            None if count > 0 => Err(Error::Corrupt(format!(
+                "{} is missing, entries at the end of the audit log can't be checked",
+                self.head_path.display()
+            ))),
+            _ => Ok(count),
+        }
+    }
+
+    fn read_head(&self) -> Result<Option<(u64, String)>> {
+        let contents = match std::fs::read_to_string(&self.head_path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        match contents.trim_end().split_once('\t') {
+            Some((count, hash)) => match count.parse() {
+                Ok(count) => Ok(Some((count, hash.to_string()))),
+                Err(_) => Err(Error::Corrupt(format!("invalid {}", self.head_path.display()))),
+            },
+            None => Err(Error::Corrupt(format!("invalid {}", self.head_path.display()))),
+        }
+    }
+
+    /**
+     * the key of the log, unwrapped with master
+     */
+    fn read_key(&self, master: &dyn MasterKey) -> Result<Zeroizing<Vec<u8>>> {
+        let invalid = || Error::Corrupt(format!("invalid {}", self.key_path.display()));
+        let wrapped = crypto::decode_hex(std::fs::read_to_string(&self.key_path)?.trim()).map_err(|_| invalid())?;
+        let key = master.unwrap_key(&wrapped).map_err(|e| match e {
+            Error::Crypto(_) => Error::Crypto(format!(
+                "the key of the audit log can't be decrypted with key {}",
+                crypto::fingerprint(&master.public_key())
+            )),
+            e => e,
+        })?;
+        match key.len() {
+            32 => Ok(key),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn write_key(&self, public_key: &[u8; 32], key: &[u8]) -> Result<()> {
+        let wrapped = crypto::encrypt(public_key, key)?;
+        keys::write_key_file(&self.key_path, &format!("{}\n", crypto::encode_hex(&wrapped)))
+    }
+
+    /**
+     * only one process appends at a time, the others wait their turn
+     */
+    fn lock(&self) -> Result<StoreLock> {
+        StoreLock::wait(&self.path.to_string_lossy(), LOCK_TIMEOUT)
+    }
+}
+
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+/**
+ * Wraps the key of the audit log of data_dir with the new master key, when the master key is
+ * rotated. Nothing to do when it's wrapped with it already, like when a rotation is resumed
+ */
+pub fn rewrap_key(data_dir: &Path, old: &dyn MasterKey, new: &KeyPair) -> Result<()> {
+    let log = AuditLog::at(data_dir);
+    if !log.key_path.exists() {
+        return Ok(());
+    }
+    let _lock = log.lock()?;
+    let key = match log.read_key(old) {
+        Ok(key) => key,
+        Err(Error::Crypto(_)) if log.read_key(new).is_ok() => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    log.write_key(&new.0, &key)
+}
+
+/**
+ * The user running this process, for the entries it logs
+ */
+pub fn current_user() -> String {
+    ["USER", "LOGNAME", "USERNAME"]
+        .iter()
+        .find_map(|name| std::env::var(name).ok().filter(|user| !user.is_empty()))
+        .unwrap_or_else(uid)
+}
+
+#[cfg(unix)]
+fn uid() -> String {
+    format!("uid {}", unsafe { libc::getuid() })
+}
+
+#[cfg(not(unix))]
+fn uid() -> String {
+    "unknown".to_string()
+}
+
+/**
+ * Reads a time given to a query, unix seconds or a UTC date like 2022-03-01
+ */
+pub fn parse_time(text: &str) -> Option<u64> {
+    if let Ok(secs) = text.parse() {
+        return Some(secs);
+    }
+    let mut parts = text.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    // days since epoch from a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    u64::try_from(days * 86400).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    /**
+     * a log with four entries: an insert, two reads by another user in one record and a delete
+     */
+    fn test_log(name: &str) -> (TempDir, KeyPair, AuditLog) {
+        let data_dir = TempDir::new(&format!("audit-{}", name));
+        let master = KeyPair::from(crypto::generate_key_pair().unwrap());
+        let log = AuditLog::open(&data_dir, &master).unwrap();
+        log.record("alice", "default", "token", Operation::Insert).unwrap();
+        log.record_all(
+            "bob",
+            &[("default", "token", Operation::Read), ("other", "tab\tin name", Operation::Read)],
+        )
+        .unwrap();
+        log.record("alice", "default", "token", Operation::Delete).unwrap();
+        (data_dir, master, log)
+    }
+
+    #[test]
+    fn empty_logs_verify() {
+        let data_dir = TempDir::new("audit-empty");
+        let master = KeyPair::from(crypto::generate_key_pair().unwrap());
+        assert_eq!(AuditLog::open(&data_dir, &master).unwrap().verify().unwrap(), 0);
+    }
+
+    #[test]
+    fn chained_entries() {
+        let (_data_dir, _, log) = test_log("chained");
+        assert_eq!(log.verify().unwrap(), 4);
+        assert_eq!(log.entries().unwrap()[2].key, "tab\tin name");
+    }
+
+    #[test]
+    fn queries_match_every_field() {
+        let (_data_dir, _, log) = test_log("query");
+        let filter = Filter {
+            user: Some("bob".to_string()),
+            ..Filter::default()
+        };
+        assert_eq!(log.query(&filter).unwrap().len(), 2);
+        let filter = Filter {
+            store: Some("default".to_string()),
+            keys: vec!["token".to_string()],
+            operation: Some(Operation::Delete),
+            since: Some(db::now() - 60),
+            ..Filter::default()
+        };
+        let entries = log.query(&filter).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].user, "alice");
+    }
+
+    #[test]
+    fn times_are_dates_or_seconds() {
+        assert_eq!(parse_time("2022-03-01"), Some(1646092800));
+        assert_eq!(parse_time("1646092800"), Some(1646092800));
+        assert_eq!(parse_time("2022-13-01"), None);
+    }
+
+    #[test]
+    fn edited_entries_are_caught() {
+        let (_data_dir, _, log) = test_log("edited");
+        let original = std::fs::read_to_string(log.path()).unwrap();
+        std::fs::write(log.path(), original.replacen("\tbob\t", "\tcarol\t", 1)).unwrap();
+        assert!(matches!(log.verify(), Err(Error::Corrupt(m)) if m.contains("entry 2")));
+    }
+
+    #[test]
+    fn removed_entries_are_caught() {
+        let (_data_dir, _, log) = test_log("removed");
+        let original = std::fs::read_to_string(log.path()).unwrap();
+        let lines: Vec<&str> = original.lines().collect();
+        std::fs::write(log.path(), format!("{}\n{}\n{}\n", lines[0], lines[2], lines[3])).unwrap();
+        assert!(matches!(log.verify(), Err(Error::Corrupt(m)) if m.contains("entry 2")));
+    }
+
+    #[test]
+    fn truncated_logs_are_caught() {
+        let (_data_dir, _, log) = test_log("truncated");
+        let original = std::fs::read_to_string(log.path()).unwrap();
+        let lines: Vec<&str> = original.lines().collect();
+        std::fs::write(log.path(), format!("{}\n{}\n{}\n", lines[0], lines[1], lines[2])).unwrap();
+        assert!(matches!(log.verify(), Err(Error::Corrupt(m)) if m.contains("entry 3 of 4")));
+    }
+
+    #[test]
+    fn the_chain_takes_the_master_key() {
+        let (data_dir, _, log) = test_log("forged");
+        // the master key wraps the key of the log
+        let other = KeyPair::from(crypto::generate_key_pair().unwrap());
+        assert!(matches!(AuditLog::open(&data_dir, &other), Err(Error::Crypto(_))));
+        // so a chain rebuilt with another key doesn't verify
+        let mut forged = AuditLog::at(&data_dir);
+        forged.key = crypto::random_bytes().unwrap();
+        let mut previous = GENESIS.to_string();
+        let mut lines = String::new();
+        for mut entry in log.entries().unwrap() {
+            entry.user = "carol".to_string();
+            entry.hash = entry.chained_hash(&forged.key, &previous);
+            lines.push_str(&format!("{}\t{}\n", entry.fields(), entry.hash));
+            previous = entry.hash.to_string();
+        }
+        std::fs::write(log.path(), lines).unwrap();
+        std::fs::write(data_dir.join(HEAD_FILE), format!("4\t{}\n", previous)).unwrap();
+        assert!(matches!(log.verify(), Err(Error::Corrupt(m)) if m.contains("entry 1")));
+    }
+
+    #[test]
+    fn rotation_rewraps_the_key() {
+        let (data_dir, master, _log) = test_log("rotated");
+        let other = KeyPair::from(crypto::generate_key_pair().unwrap());
+        rewrap_key(&data_dir, &master, &other).unwrap();
+        // running it again after an interruption is fine
+        rewrap_key(&data_dir, &master, &other).unwrap();
+        assert!(matches!(AuditLog::open(&data_dir, &master), Err(Error::Crypto(_))));
+        let rotated = AuditLog::open(&data_dir, &other).unwrap();
+        rotated.record("alice", "default", "token", Operation::Read).unwrap();
+        assert_eq!(rotated.verify().unwrap(), 5);
+    }
+
+    #[test]
+    fn logs_without_their_key_are_rejected() {
+        let (data_dir, master, _log) = test_log("keyless");
+        std::fs::remove_file(data_dir.join(KEY_FILE)).unwrap();
+        assert!(matches!(AuditLog::open(&data_dir, &master), Err(Error::Corrupt(_))));
+    }
+}
@@ -0,0 +1,323 @@
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use crate::crypto;
+use crate::db::{self, Database, Record, META_PLAINTEXT};
+use crate::error::{Error, Result};
+use crate::keys::KeyPair;
+
+// header field set on stores that blind their key names
+pub static META_BLIND_KEYS: &str = "blind_keys";
+
+/**
+ * How the entries of a store are written: values are encrypted with the data key, and when
+ * key names are blinded, entries are saved under a keyed hash of the name (a blind index)
+ * with the real name encrypted along with the value. Lookups hash the name, so they stay a
+ * single map access, and only the holder of the data key can list the names.
+ * Plaintext stores save their values as they are, escaped to fit on their line
+ */
+#[derive(Clone)]
+pub(crate) struct EntryKeys {
+    pub keypair: KeyPair,
+    // key of the blind index, None when names are saved as they are
+    index_key: Option<[u8; 32]>,
+    plaintext: bool,
+}
+
+impl EntryKeys {
+    pub fn new(keypair: &KeyPair, blind: bool) -> EntryKeys {
+        EntryKeys {
+            keypair: keypair.clone(),
+            index_key: Some(index_key(keypair)).filter(|_| blind),
+            plaintext: false,
+        }
+    }
+
+    /**
+     * the keys of the entries of db, as its header says they are written
+     */
+    pub fn of(keypair: &KeyPair, db: &Database) -> EntryKeys {
+        EntryKeys::new(keypair, db.get_meta(META_BLIND_KEYS).is_some()).with_plaintext(db.get_meta(META_PLAINTEXT).is_some())
+    }
+
+    /**
+     * the same, with values saved as they are or encrypted
+     */
+    pub fn with_plaintext(&self, plaintext: bool) -> EntryKeys {
+        let mut keys = self.clone();
+        keys.plaintext = plaintext;
+        keys
+    }
+
+    /**
+     * the same, with another data key
+     */
+    pub fn rekeyed(&self, keypair: &KeyPair) -> EntryKeys {
+        EntryKeys::new(keypair, self.is_blind()).with_plaintext(self.plaintext)
+    }
+
+    pub fn is_blind(&self) -> bool {
+        self.index_key.is_some()
+    }
+
+    pub fn is_plaintext(&self) -> bool {
+        self.plaintext
+    }
+
+    /**
+     * if the value of record is saved encrypted and hex encoded. files are saved as the address of
+     * their manifest, encrypted only along with their name when names are blinded
+     */
+    pub fn is_encrypted(&self, record: &Record) -> bool {
+        !self.plaintext && (record.blob.is_none() || self.index_key.is_some())
+    }
+
+    /**
+     * name of the entry holding key in the store file
+     */
+    pub fn entry_key(&self, key: &str) -> String {
+        match &self.index_key {
+            Some(index_key) => crypto::encode_hex(&crypto::keyed_hash(index_key, key.as_bytes())),
+            None => key.to_string(),
+        }
+    }
+
+    /**
+     * the keyed hash of the name of key, the name of its entry when names are blinded
+     */
+    pub fn hashed_key(&self, key: &str) -> String {
+        let index_key = Zeroizing::new(self.index_key.unwrap_or_else(|| index_key(&self.keypair)));
+        crypto::encode_hex(&crypto::keyed_hash(&*index_key, key.as_bytes()))
+    }
+
+    /**
+     * encrypts the value of key as saved in the store. blinded entries carry the name too
+     */
+    pub fn seal(&self, key: &str, value: &str) -> Result<String> {
+        // plaintext stores don't blind their names
+        if self.plaintext {
+            return Ok(db::escape(value));
+        }
+        let payload = match self.index_key {
+            Some(_) => format!("{}\n{}", key, value),
+            None => value.to_string(),
+        };
+        // bound to the entry it's saved under, so it can't be moved to another key
+        crypto::encrypt_string_bound(&self.keypair.0, &payload, self.entry_key(key).as_bytes())
+    }
+
+    /**
+     * decrypts a value saved under entry_key, returning the name of the key and the value
+     */
+    pub fn open(&self, entry_key: &str, value: &str) -> Result<(String, String)> {
+        if self.plaintext {
+            return Ok((entry_key.to_string(), db::unescape(value)));
+        }
+        let payload = crypto::decrypt_string_bound(&self.keypair.1, value, entry_key.as_bytes()).map_err(|e| match e {
+            Error::Crypto(message) => Error::Crypto(format!("{} (key '{}')", message, entry_key)),
+            Error::Corrupt(message) => Error::Corrupt(format!("{} (key '{}')", message, entry_key)),
+            other => other,
+        })?;
+        if self.index_key.is_none() {
+            return Ok((entry_key.to_string(), payload));
+        }
+        let (key, value) = payload
+            .split_once('\n')
+            .ok_or_else(|| Error::Corrupt(format!("blinded entry {} has no key name", entry_key)))?;
+        // values from before they were bound to their entry would decrypt under another one, so the name is checked
+        if self.entry_key(key) != entry_key {
+            return Err(Error::Corrupt(format!("blinded entry {} holds the value of another key", entry_key)));
+        }
+        Ok((key.to_string(), value.to_string()))
+    }
+
+    /**
+     * the value saved for a file: the address of its manifest, encrypted with the name when blinded
+     */
+    pub fn seal_file(&self, key: &str, address: &str) -> Result<String> {
+        match self.index_key {
+            Some(_) => self.seal(key, address),
+            None => Ok(address.to_string()),
+        }
+    }
+
+    /**
+     * the name of the key and the address of the manifest of a file record
+     */
+    pub fn open_file(&self, entry_key: &str, record: &Record) -> Result<(String, String)> {
+        match self.index_key {
+            Some(_) => self.open(entry_key, &record.value),
+            None => Ok((entry_key.to_string(), record.value.to_string())),
+        }
+    }
+}
+
+fn index_key(keypair: &KeyPair) -> [u8; 32] {
+    crypto::keyed_hash(&keypair.1, b"gui-kvstore key index")
+}
+
+// the key pair zeroes itself, the index key is derived from its private key
+impl Drop for EntryKeys {
+    fn drop(&mut self) {
+        self.index_key.zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for EntryKeys {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Engine;
+    use crate::store::Store;
+    use crate::testing::TempDir;
+
+    /**
+     * a store with two values and a file, its names blinded and flushed
+     */
+    fn blinded_store(name: &str) -> (TempDir, Store) {
+        let data_dir = TempDir::new(&format!("blind-{}", name));
+        let mut store = Store::open_with(&data_dir, "blind", Engine::File, false).unwrap();
+        store.set("prod_stripe_secret", "sk_live").unwrap();
+        store.set("a_token", "value").unwrap();
+        store.put_file("certificate", &mut &b"contents"[..]).unwrap();
+        store.set_blind_keys(true).unwrap();
+        store.flush().unwrap();
+        (data_dir, store)
+    }
+
+    /**
+     * a plaintext store with values that need escaping, flushed
+     */
+    fn plaintext_store(name: &str) -> (TempDir, Store) {
+        let data_dir = TempDir::new(&format!("plaintext-{}", name));
+        let mut store = Store::open_with(&data_dir, "flags", Engine::File, false).unwrap();
+        store.set("feature.dark_mode", "on").unwrap();
+        store.set("motd", "two\tlines\nand a \\").unwrap();
+        store.set("empty", "").unwrap();
+        store.set_plaintext(true).unwrap();
+        store.flush().unwrap();
+        (data_dir, store)
+    }
+
+    #[test]
+    fn blinded_names_are_not_in_the_file() {
+        let (data_dir, _store) = blinded_store("file");
+        let contents = std::fs::read_to_string(data_dir.join("data").join("blind.db")).unwrap();
+        assert!(!contents.contains("prod_stripe_secret"));
+        assert!(!contents.contains("a_token"));
+        assert!(!contents.contains("certificate"));
+    }
+
+    #[test]
+    fn blinded_entries_read_back() {
+        let (data_dir, store) = blinded_store("read");
+        drop(store);
+        let store = Store::open_with(&data_dir, "blind", Engine::File, false).unwrap();
+        assert!(store.blind_keys());
+        assert_eq!(store.get("prod_stripe_secret").unwrap(), Some("sk_live".to_string()));
+        assert_eq!(store.get("missing").unwrap(), None);
+        let mut file = vec![];
+        std::io::Read::read_to_end(&mut store.get_file("certificate").unwrap().unwrap(), &mut file).unwrap();
+        assert_eq!(file, b"contents");
+    }
+
+    #[test]
+    fn blinded_names_are_listed() {
+        let (_data_dir, mut store) = blinded_store("list");
+        // listing decrypts the names, sorted like any other store
+        assert_eq!(
+            store.snapshot().try_keys().unwrap(),
+            vec!["a_token", "certificate", "prod_stripe_secret"]
+        );
+        assert_eq!(store.snapshot().keys(), store.snapshot().try_keys().unwrap());
+        assert!(store.delete("a_token"));
+        assert_eq!(store.snapshot().keys(), vec!["certificate", "prod_stripe_secret"]);
+    }
+
+    #[test]
+    fn moved_values_are_caught() {
+        // a value moved under the hash of another name
+        let keys = EntryKeys::new(&KeyPair::from(crypto::generate_key_pair().unwrap()), true);
+        let sealed = keys.seal("one", "value").unwrap();
+        assert!(matches!(keys.open(&keys.entry_key("two"), &sealed), Err(Error::Crypto(_))));
+    }
+
+    #[test]
+    fn unblinded_names_are_saved_again() {
+        let (_data_dir, mut store) = blinded_store("unblind");
+        store.set_blind_keys(false).unwrap();
+        assert_eq!(store.database().get_record("prod_stripe_secret").map(|r| r.blob), Some(None));
+        assert_eq!(store.get("prod_stripe_secret").unwrap(), Some("sk_live".to_string()));
+        assert!(store.is_file("certificate"));
+    }
+
+    #[test]
+    fn plaintext_values_are_saved_as_they_are() {
+        let (data_dir, _store) = plaintext_store("file");
+        let contents = std::fs::read_to_string(data_dir.join("data").join("flags.db")).unwrap();
+        assert!(contents.contains("feature.dark_mode\ton"));
+        // escaped, one line for the header and one for each value
+        assert_eq!(contents.lines().count(), 4);
+    }
+
+    #[test]
+    fn plaintext_values_read_back() {
+        let (data_dir, store) = plaintext_store("read");
+        drop(store);
+        // the header keeps the mode
+        let mut store = Store::open_with(&data_dir, "flags", Engine::File, false).unwrap();
+        assert!(store.plaintext());
+        assert_eq!(store.get("motd").unwrap(), Some("two\tlines\nand a \\".to_string()));
+        assert_eq!(store.get("empty").unwrap(), Some("".to_string()));
+        assert_eq!(store.get("missing").unwrap(), None);
+        store.set("host", "db1.internal").unwrap();
+        assert_eq!(store.database().get_record("host").unwrap().value, "db1.internal");
+    }
+
+    #[test]
+    fn plaintext_stores_dont_blind_names() {
+        let (_data_dir, mut store) = plaintext_store("blind");
+        assert!(matches!(store.set_blind_keys(true), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn plaintext_values_are_covered_by_the_mac() {
+        let (data_dir, store) = plaintext_store("mac");
+        drop(store);
+        let file = data_dir.join("data").join("flags.db");
+        let contents = std::fs::read_to_string(&file).unwrap();
+        std::fs::write(&file, contents.replace("feature.dark_mode\ton", "feature.dark_mode\toff")).unwrap();
+        assert!(matches!(Store::open_with(&data_dir, "flags", Engine::File, false), Err(Error::Corrupt(_))));
+    }
+
+    #[test]
+    fn plaintext_values_are_encrypted_again() {
+        let (data_dir, mut store) = plaintext_store("encrypt");
+        store.set_plaintext(false).unwrap();
+        store.flush().unwrap();
+        assert!(!std::fs::read_to_string(data_dir.join("data").join("flags.db")).unwrap().contains("lines"));
+        assert_eq!(store.iter().count(), 3);
+        assert_eq!(store.get("motd").unwrap(), Some("two\tlines\nand a \\".to_string()));
+    }
+
+    #[test]
+    fn stats_tell_encrypted_values() {
+        let data_dir = TempDir::new("blind-stats");
+        let mut store = Store::open_with(&data_dir, "stats", Engine::File, false).unwrap();
+        store.set("token", "value").unwrap();
+        store.put_file("certificate", &mut &b"contents"[..]).unwrap();
+        let encrypted = |store: &Store| -> Vec<(String, bool)> {
+            let mut kinds: Vec<_> = store.stats().unwrap().value_sizes.into_iter().map(|(key, _, e)| (key, e)).collect();
+            kinds.sort();
+            kinds
+        };
+        // files are saved as the address of their manifest
+        assert_eq!(encrypted(&store), vec![("certificate".to_string(), false), ("token".to_string(), true)]);
+        store.set_plaintext(true).unwrap();
+        assert!(encrypted(&store).iter().all(|(_, encrypted)| !encrypted));
+        // blinded entries are encrypted along with their name
+        store.set_plaintext(false).unwrap();
+        store.set_blind_keys(true).unwrap();
+        assert!(encrypted(&store).iter().all(|(_, encrypted)| *encrypted));
+    }
+}
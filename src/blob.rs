@@ -0,0 +1,341 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+use zeroize::Zeroize;
+
+use crate::crypto;
+use crate::error::{Error, Result};
+use crate::keys::KeyPair;
+
+// files are split in chunks of this size, each one encrypted and saved on its own
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+// first line of a manifest, the object listing the chunks of a file
+static MANIFEST_HEADER: &str = "#gui-kvstore-blob\tversion=1";
+// file next to a store file listing the objects it references, one per line
+pub static REFS_SUFFIX: &str = ".blobs";
+
+/**
+ * Content addressed, encrypted storage for large values.
+ * A file is saved as chunks plus a manifest listing them, every object named after
+ * a keyed hash of its plain text, so equal chunks are only saved once.
+ * Objects live in data_dir/blobs, spread in folders by the first two characters of their name.
+ */
+#[derive(Clone)]
+pub struct BlobStore {
+    dir: PathBuf,
+    keypair: KeyPair,
+    // key of the hash naming objects. derived from the private key so names don't reveal the contents
+    address_key: [u8; 32],
+    chunk_size: usize,
+}
+
+impl BlobStore {
+    pub fn new(dir: PathBuf, keypair: &KeyPair) -> BlobStore {
+        BlobStore {
+            dir,
+            keypair: keypair.clone(),
+            address_key: crypto::keyed_hash(&keypair.1, b"gui-kvstore blob address"),
+            chunk_size: CHUNK_SIZE,
+        }
+    }
+
+    /**
+     * the same directory, with objects encrypted for another key pair
+     */
+    pub fn with_keypair(&self, keypair: &KeyPair) -> BlobStore {
+        let mut blobs = BlobStore::new(self.dir.clone(), keypair);
+        blobs.chunk_size = self.chunk_size;
+        blobs
+    }
+
+    /**
+     * saves everything read from reader, one chunk at a time.
+     * returns the address of the manifest and the size of the file
+     */
+    pub fn put(&self, reader: &mut impl Read) -> Result<(String, u64)> {
+        let mut chunks = vec![];
+        let mut size = 0u64;
+        let mut buffer = vec![0u8; self.chunk_size];
+        loop {
+            let filled = read_chunk(reader, &mut buffer)?;
+            if filled == 0 {
+                break;
+            }
+            chunks.push(self.write_object(&buffer[..filled])?);
+            size += filled as u64;
+        }
+        let mut manifest = format!("{}\nsize={}\n", MANIFEST_HEADER, size);
+        for chunk in &chunks {
+            manifest.push_str(chunk);
+            manifest.push('\n');
+        }
+        Ok((self.write_object(manifest.as_bytes())?, size))
+    }
+
+    /**
+     * the directory holding the objects
+     */
+    pub(crate) fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /**
+     * the objects the file with the manifest at address is made of, the manifest included
+     */
+    pub fn references(&self, address: &str) -> Result<Vec<String>> {
+        let mut objects = vec![address.to_string()];
+        objects.extend(self.open(address)?.chunks.iter().cloned());
+        Ok(objects)
+    }
+
+    /**
+     * opens the file with the manifest at address for streaming
+     */
+    pub fn open(&self, address: &str) -> Result<BlobReader> {
+        let manifest = String::from_utf8(self.read_object(address)?)
+            .map_err(|_| Error::Corrupt(format!("blob manifest {} is not valid utf-8", address)))?;
+        let mut lines = manifest.lines();
+        if lines.next() != Some(MANIFEST_HEADER) {
+            return Err(Error::Format(format!("blob {} is not a manifest this version reads", address)));
+        }
+        let size = lines
+            .next()
+            .and_then(|line| line.strip_prefix("size="))
+            .and_then(|size| size.parse().ok())
+            .ok_or_else(|| Error::Corrupt(format!("blob manifest {} has no size", address)))?;
+        Ok(BlobReader {
+            blobs: self.clone(),
+            chunks: lines.map(|line| line.to_string()).collect(),
+            size,
+            buffer: vec![],
+            position: 0,
+        })
+    }
+
+    /**
+     * encrypts and saves data, unless an object with the same contents is already there
+     */
+    fn write_object(&self, data: &[u8]) -> Result<String> {
+        let address = crypto::encode_hex(&crypto::keyed_hash(&self.address_key, data));
+        let path = self.object_path(&address)?;
+        if path.exists() {
+            return Ok(address);
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let encrypted = crypto::encrypt(&self.keypair.0, data)?;
+        // renaming a finished file into place, so a crash never leaves a truncated object
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, encrypted)?;
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(address)
+    }
+
+    /**
+     * reads and decrypts an object, checking it still matches its address
+     */
+    fn read_object(&self, address: &str) -> Result<Vec<u8>> {
+        let path = self.object_path(address)?;
+        let encrypted = std::fs::read(&path).map_err(|e| match e.kind() {
+            ErrorKind::NotFound => Error::Corrupt(format!("blob {} is missing", address)),
+            _ => Error::Io(e),
+        })?;
+        let data = crypto::decrypt(&self.keypair.1, &encrypted)?;
+        if crypto::encode_hex(&crypto::keyed_hash(&self.address_key, &data)) != address {
+            return Err(Error::Corrupt(format!("blob {} doesn't match its contents", address)));
+        }
+        Ok(data)
+    }
+
+    fn object_path(&self, address: &str) -> Result<PathBuf> {
+        // addresses come from the store file, so they're checked before being used as a path
+        if address.len() != 64 || !address.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::Corrupt(format!("invalid blob address '{}'", address)));
+        }
+        Ok(self.dir.join(&address[..2]).join(&address[2..]))
+    }
+}
+
+/**
+ * the objects the store file at db_file_path references, None for stores that never listed them,
+ * written by older versions
+ */
+pub(crate) fn read_refs(db_file_path: &str) -> Result<Option<BTreeSet<String>>> {
+    match std::fs::read_to_string(format!("{}{}", db_file_path, REFS_SUFFIX)) {
+        Ok(contents) => Ok(Some(contents.lines().map(|line| line.to_string()).collect())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub(crate) fn write_refs(db_file_path: &str, refs: &BTreeSet<String>) -> Result<()> {
+    let path = format!("{}{}", db_file_path, REFS_SUFFIX);
+    let contents: String = refs.iter().map(|address| format!("{}\n", address)).collect();
+    let tmp_path = format!("{}.tmp", path);
+    std::fs::write(&tmp_path, contents)?;
+    std::fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+/**
+ * the addresses of every object in blob_dir
+ */
+pub(crate) fn list_objects(blob_dir: &Path) -> Result<BTreeSet<String>> {
+    let mut objects = BTreeSet::new();
+    let folders = match std::fs::read_dir(blob_dir) {
+        Ok(folders) => folders,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(objects),
+        Err(e) => return Err(e.into()),
+    };
+    for folder in folders {
+        let folder = folder?;
+        for object in std::fs::read_dir(folder.path())? {
+            let name = object?.file_name();
+            objects.insert(format!("{}{}", folder.file_name().to_string_lossy(), name.to_string_lossy()));
+        }
+    }
+    Ok(objects)
+}
+
+/**
+ * deletes the objects of candidates that no store in store_path references anymore.
+ * a store that never listed its references could be using any of them, nothing is deleted then
+ */
+pub(crate) fn release(blob_dir: &Path, store_path: &Path, candidates: BTreeSet<String>) -> Result<()> {
+    if candidates.is_empty() || !store_path.is_dir() {
+        return Ok(());
+    }
+    let mut candidates = candidates;
+    for entry in std::fs::read_dir(store_path)? {
+        let path = entry?.path();
+        if path.extension().map(|e| e == "db").unwrap_or(false) {
+            match read_refs(&path.display().to_string())? {
+                Some(refs) => candidates.retain(|address| !refs.contains(address)),
+                None => return Ok(()),
+            }
+        }
+    }
+    for address in candidates {
+        // addresses are checked before being used as a path, like the ones read from a store
+        if address.len() == 64 && address.chars().all(|c| c.is_ascii_hexdigit()) {
+            match std::fs::remove_file(blob_dir.join(&address[..2]).join(&address[2..])) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Drop for BlobStore {
+    fn drop(&mut self) {
+        self.keypair.zeroize();
+        self.address_key.zeroize();
+    }
+}
+
+/**
+ * Reads a file from the blob store, decrypting one chunk at a time
+ */
+pub struct BlobReader {
+    blobs: BlobStore,
+    chunks: VecDeque<String>,
+    size: u64,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl BlobReader {
+    /**
+     * size of the whole file
+     */
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.buffer.len() {
+            match self.chunks.pop_front() {
+                Some(address) => {
+                    // the chunk read is plain text, it's zeroed before the next one replaces it
+                    self.buffer.zeroize();
+                    self.buffer = self
+                        .blobs
+                        .read_object(&address)
+                        .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+                    self.position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let count = out.len().min(self.buffer.len() - self.position);
+        out[..count].copy_from_slice(&self.buffer[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+impl Drop for BlobReader {
+    fn drop(&mut self) {
+        self.buffer.zeroize();
+    }
+}
+
+/**
+ * reads until buffer is full or the reader ends, returning how much was read
+ */
+pub(crate) fn read_chunk(reader: &mut impl Read, buffer: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            // errors of the blob store reading a file come back as they were
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn count_objects(dir: &std::path::Path) -> usize {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|fanout| std::fs::read_dir(fanout.unwrap().path()).unwrap().count())
+            .sum()
+    }
+
+    #[test]
+    fn chunked_round_trip() {
+        let dir = TempDir::new("blobs");
+        let mut blobs = BlobStore::new(dir.to_path_buf(), &KeyPair::from(crypto::generate_key_pair().unwrap()));
+        blobs.chunk_size = 16;
+        // 3 different chunks, the fourth repeats the first one, plus a shorter last one
+        let mut data = vec![];
+        for byte in [1u8, 2, 3, 1] {
+            data.extend_from_slice(&[byte; 16]);
+        }
+        data.extend_from_slice(b"tail");
+        let (address, size) = blobs.put(&mut data.as_slice()).unwrap();
+        assert_eq!(size, data.len() as u64);
+        // the repeated chunk is saved once, next to the manifest
+        assert_eq!(count_objects(&dir), 5);
+        let mut reader = blobs.open(&address).unwrap();
+        let mut read = vec![];
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+        // saving the same file again doesn't add anything
+        assert_eq!(blobs.put(&mut data.as_slice()).unwrap().0, address);
+        assert_eq!(count_objects(&dir), 5);
+        assert!(matches!(blobs.open("../../etc/passwd"), Err(Error::Corrupt(_))));
+    }
+}
@@ -0,0 +1,225 @@
+use std::path::{Path, PathBuf};
+
+use crate::db::Engine;
+use crate::error::{Error, Result};
+
+// environment variables, they override the config file
+static ENV_HOME: &str = "GUI_KVSTORE_HOME";
+static ENV_STORE: &str = "GUI_KVSTORE_STORE";
+static ENV_FORMAT: &str = "GUI_KVSTORE_FORMAT";
+static ENV_DEBUG: &str = "GUI_KVSTORE_DEBUG";
+static ENV_ENGINE: &str = "GUI_KVSTORE_ENGINE";
+
+static APP_DIR: &str = "gui-kvstore";
+static CONFIG_FILE: &str = "config.toml";
+
+/**
+ * Settings resolved from the config file and the environment.
+ * Command line flags are applied on top of these by the App.
+ */
+pub struct Config {
+    pub data_dir: PathBuf,
+    pub store: String,
+    pub format: String,
+    pub debug: bool,
+    pub engine: Engine,
+    // the config file that was read, if any
+    pub file: Option<PathBuf>,
+}
+
+impl Config {
+    /**
+     * Loads the config with flag > env > file precedence.
+     * data_dir_flag is the value of --data-dir, the only flag needed before the data dir exists
+     */
+    pub fn load(data_dir_flag: Option<String>) -> Result<Config> {
+        let config_path = Config::config_path();
+        let file = if config_path.exists() {
+            let contents = std::fs::read_to_string(&config_path)
+                .map_err(|e| Error::Config(format!("could not read {}: {}", config_path.display(), e)))?;
+            Some((config_path, contents))
+        } else {
+            None
+        };
+        let env = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        Config::resolve(file, &env, data_dir_flag)
+    }
+
+    /**
+     * builds the config from the contents of the config file, if there is one, and the environment
+     * variables returned by env. the file gives the defaults, env overrides them
+     */
+    fn resolve(
+        file: Option<(PathBuf, String)>,
+        env: &dyn Fn(&str) -> Option<String>,
+        data_dir_flag: Option<String>,
+    ) -> Result<Config> {
+        let table = match &file {
+            Some((path, contents)) => contents.parse::<toml::Value>().map_err(|e| {
+                Error::Config(format!("invalid config file {}: {}", path.display(), e))
+            })?,
+            None => toml::Value::Table(toml::value::Table::new()),
+        };
+        let setting = |env_name: &str, file_key: &str| -> Result<Option<String>> {
+            if let Some(value) = env(env_name) {
+                return Ok(Some(value));
+            }
+            match table.get(file_key) {
+                Some(toml::Value::String(s)) => Ok(Some(s.to_string())),
+                Some(toml::Value::Boolean(b)) => Ok(Some(b.to_string())),
+                Some(other) => Err(Error::Config(format!(
+                    "invalid {} in the config file: {}, expected a {}",
+                    file_key,
+                    other,
+                    if file_key == "debug" { "boolean" } else { "string" }
+                ))),
+                None => Ok(None),
+            }
+        };
+        //
+        let data_dir = match data_dir_flag.or(setting(ENV_HOME, "data_dir")?) {
+            Some(dir) => Config::expand_home(&dir),
+            None => Config::default_data_dir(),
+        };
+        let engine = match setting(ENV_ENGINE, "engine")? {
+            Some(name) => Config::parse_engine(&name)?,
+            None => Engine::File,
+        };
+        let debug = match setting(ENV_DEBUG, "debug")? {
+            Some(debug) => Config::parse_debug(&debug)?,
+            None => false,
+        };
+        Ok(Config {
+            data_dir,
+            store: setting(ENV_STORE, "store")?.unwrap_or_else(|| "default".to_string()),
+            format: setting(ENV_FORMAT, "format")?.unwrap_or_else(|| "default".to_string()),
+            debug,
+            engine,
+            file: file.map(|(path, _)| path),
+        })
+    }
+
+    /**
+     * Applies the command line flags on top of the file and the environment:
+     * --store=, --f=, --debug= and --engine=. The last one of each wins
+     */
+    pub fn apply_flags(&mut self, flags: &[String]) -> Result<()> {
+        for flag in flags {
+            if let Some(store) = flag.strip_prefix("--store=") {
+                self.store = store.to_string();
+            } else if let Some(format) = flag.strip_prefix("--f=") {
+                self.format = format.to_string();
+            } else if let Some(debug) = flag.strip_prefix("--debug=") {
+                self.debug = Config::parse_debug(debug)?;
+            } else if let Some(engine) = flag.strip_prefix("--engine=") {
+                self.engine = Config::parse_engine(engine)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_engine(name: &str) -> Result<Engine> {
+        Engine::from_name(name)
+            .ok_or_else(|| Error::Config(format!("unknown engine '{}', use file or memory", name)))
+    }
+
+    fn parse_debug(debug: &str) -> Result<bool> {
+        match debug {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            _ => Err(Error::Config(format!("invalid debug setting '{}', use true or false", debug))),
+        }
+    }
+
+    /**
+     * $XDG_CONFIG_HOME/gui-kvstore/config.toml, defaulting to ~/.config/gui-kvstore/config.toml
+     */
+    pub fn config_path() -> PathBuf {
+        Config::xdg_dir("XDG_CONFIG_HOME", ".config").join(CONFIG_FILE)
+    }
+
+    /**
+     * ~/.gui-kvstore if it is already there from older versions, otherwise
+     * $XDG_DATA_HOME/gui-kvstore, defaulting to ~/.local/share/gui-kvstore
+     */
+    fn default_data_dir() -> PathBuf {
+        let legacy_dir = Config::home_dir().join(".gui-kvstore");
+        if legacy_dir.exists() {
+            return legacy_dir;
+        }
+        Config::xdg_dir("XDG_DATA_HOME", ".local/share")
+    }
+
+    fn xdg_dir(env_name: &str, home_fallback: &str) -> PathBuf {
+        match std::env::var(env_name) {
+            // the spec says relative paths must be ignored
+            Ok(dir) if Path::new(&dir).is_absolute() => PathBuf::from(dir).join(APP_DIR),
+            _ => Config::home_dir().join(home_fallback).join(APP_DIR),
+        }
+    }
+
+    fn home_dir() -> PathBuf {
+        std::env::var("HOME")
+            .or_else(|_| std::env::var("USERPROFILE"))
+            .map(PathBuf::from)
+            .unwrap_or_default()
+    }
+
+    fn expand_home(dir: &str) -> PathBuf {
+        match dir.strip_prefix("~/") {
+            Some(rest) => Config::home_dir().join(rest),
+            None => PathBuf::from(dir),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env<'a>(vars: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |name: &str| vars.iter().find(|(var, _)| *var == name).map(|(_, value)| value.to_string())
+    }
+
+    fn file(contents: &str) -> Option<(PathBuf, String)> {
+        Some((PathBuf::from("config.toml"), contents.to_string()))
+    }
+
+    #[test]
+    fn precedence() {
+        let contents = "data_dir = \"/from/file\"\nstore = \"file\"\nformat = \"csv\"\ndebug = true\nengine = \"memory\"";
+        // defaults, without a file or environment
+        let config = Config::resolve(None, &env(&[]), None).unwrap();
+        assert_eq!((config.store.as_str(), config.format.as_str()), ("default", "default"));
+        assert!(!config.debug && config.engine == Engine::File && config.file.is_none());
+        // the file overrides the defaults
+        let config = Config::resolve(file(contents), &env(&[]), None).unwrap();
+        assert_eq!(config.data_dir, PathBuf::from("/from/file"));
+        assert_eq!((config.store.as_str(), config.format.as_str()), ("file", "csv"));
+        assert!(config.debug && config.engine == Engine::Memory);
+        assert_eq!(config.file, Some(PathBuf::from("config.toml")));
+        // the environment overrides the file
+        let vars = [(ENV_HOME, "/from/env"), (ENV_STORE, "env"), (ENV_DEBUG, "false"), (ENV_ENGINE, "file")];
+        let mut config = Config::resolve(file(contents), &env(&vars), None).unwrap();
+        assert_eq!(config.data_dir, PathBuf::from("/from/env"));
+        assert_eq!((config.store.as_str(), config.format.as_str()), ("env", "csv"));
+        assert!(!config.debug && config.engine == Engine::File);
+        // and flags override both
+        let config_flag = Config::resolve(file(contents), &env(&vars), Some("/from/flag".to_string())).unwrap();
+        assert_eq!(config_flag.data_dir, PathBuf::from("/from/flag"));
+        let flags = ["--store=first", "--store=flag", "--f=json", "--debug=true", "--engine=memory"].map(String::from);
+        config.apply_flags(&flags).unwrap();
+        assert_eq!((config.store.as_str(), config.format.as_str()), ("flag", "json"));
+        assert!(config.debug && config.engine == Engine::Memory);
+    }
+
+    #[test]
+    fn invalid_settings() {
+        for contents in ["store = ", "debug = \"yes\"", "engine = \"disk\"", "store = 5"] {
+            assert!(matches!(Config::resolve(file(contents), &env(&[]), None), Err(Error::Config(_))));
+        }
+        assert!(matches!(Config::resolve(None, &env(&[(ENV_DEBUG, "1")]), None), Err(Error::Config(_))));
+        let mut config = Config::resolve(None, &env(&[]), None).unwrap();
+        assert!(matches!(config.apply_flags(&["--engine=disk".to_string()]), Err(Error::Config(_))));
+    }
+}
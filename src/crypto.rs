@@ -4,65 +4,268 @@ use std::{fmt::Write, num::ParseIntError};
 use crypto::aead::{AeadDecryptor, AeadEncryptor};
 use crypto::chacha20poly1305::ChaCha20Poly1305;
 use crypto::curve25519::{curve25519, curve25519_base};
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::scrypt::{scrypt, ScryptParams};
+use crypto::sha2::Sha256;
 use rand::{OsRng, Rng};
+use zeroize::Zeroizing;
 
-pub enum EncryptError {
-    RngInitializationFailed,
+use crate::error::Error;
+
+// first byte of an envelope, the layout of everything after it
+pub const ENVELOPE_VERSION: u8 = 1;
+// same layout, with associated data authenticated along with the header, see encrypt_bound
+pub const ENVELOPE_VERSION_BOUND: u8 = 2;
+// key encapsulation: an ephemeral X25519 key agreement, its shared secret turned into a key with HMAC-SHA256
+pub const KEM_X25519_HMAC_SHA256: u8 = 1;
+// ChaCha20-Poly1305 from rust-crypto with an 8 byte nonce, what values were encrypted with before envelopes
+pub const AEAD_CHACHA20_POLY1305: u8 = 1;
+// XChaCha20-Poly1305 from the RustCrypto chacha20poly1305 crate, its 24 byte nonce is safe to pick at random
+pub const AEAD_XCHACHA20_POLY1305: u8 = 2;
+// envelope header: version, kem and aead identifiers, then the ephemeral public key
+const HEADER_LEN: usize = 3 + 32;
+
+// bytes added to every encrypted message: the envelope header, the nonce and the poly1305 tag
+pub const OVERHEAD: usize = HEADER_LEN + 24 + 16;
+
+/**
+ * An authenticated cipher with a 32 byte key and a 16 byte tag.
+ * Envelopes name the one they were sealed with, so ciphers can be replaced without losing old values
+ */
+pub trait Aead: Sync {
+    fn id(&self) -> u8;
+    fn name(&self) -> &'static str;
+    fn nonce_len(&self) -> usize;
+    /**
+     * encrypts message, returning the ciphertext followed by the tag.
+     * aad is authenticated but not encrypted, opening fails if it doesn't match.
+     * fails when the nonce doesn't have the length of the cipher
+     */
+    fn seal(&self, key: &[u8; 32], nonce: &[u8], aad: &[u8], message: &[u8]) -> Result<Vec<u8>, EncryptError>;
+    fn open(&self, key: &[u8; 32], nonce: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, DecryptError>;
 }
 
-pub fn encrypt(public_key: &[u8; 32], message: &[u8]) -> Result<Vec<u8>, EncryptError> {
-    let mut rng = OsRng::new().map_err(|_| EncryptError::RngInitializationFailed)?;
+/**
+ * ChaCha20-Poly1305 from rust-crypto, unmaintained. Only kept to read what it encrypted
+ */
+pub struct ChaCha20Poly1305Legacy;
 
-    let mut ephemeral_secret_key = [0u8; 32];
-    rng.fill_bytes(&mut ephemeral_secret_key[..]);
+impl Aead for ChaCha20Poly1305Legacy {
+    fn id(&self) -> u8 {
+        AEAD_CHACHA20_POLY1305
+    }
 
-    let ephemeral_public_key: [u8; 32] = curve25519_base(&ephemeral_secret_key[..]);
-    let symmetric_key = curve25519(&ephemeral_secret_key[..], &public_key[..]);
+    fn name(&self) -> &'static str {
+        "chacha20-poly1305"
+    }
 
-    let mut c = ChaCha20Poly1305::new(&symmetric_key, &[0u8; 8][..], &[]);
+    fn nonce_len(&self) -> usize {
+        8
+    }
 
-    let mut output = vec![0; 32 + 16 + message.len()];
-    let mut tag = [0u8; 16];
-    c.encrypt(message, &mut output[32 + 16..], &mut tag[..]);
+    fn seal(&self, key: &[u8; 32], nonce: &[u8], aad: &[u8], message: &[u8]) -> Result<Vec<u8>, EncryptError> {
+        if nonce.len() != self.nonce_len() {
+            return Err(EncryptError::InvalidNonce);
+        }
+        let mut output = vec![0; message.len() + 16];
+        let mut tag = [0u8; 16];
+        let (ciphertext, _) = output.split_at_mut(message.len());
+        ChaCha20Poly1305::new(key, nonce, aad).encrypt(message, ciphertext, &mut tag);
+        output[message.len()..].copy_from_slice(&tag);
+        Ok(output)
+    }
 
-    for (dest, src) in (&mut output[0..32])
-        .iter_mut()
-        .zip(ephemeral_public_key.iter())
-    {
-        *dest = *src;
+    fn open(&self, key: &[u8; 32], nonce: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, DecryptError> {
+        if sealed.len() < 16 || nonce.len() != self.nonce_len() {
+            return Err(DecryptError::Malformed);
+        }
+        let (ciphertext, tag) = sealed.split_at(sealed.len() - 16);
+        let mut plaintext = vec![0; ciphertext.len()];
+        if !ChaCha20Poly1305::new(key, nonce, aad).decrypt(ciphertext, &mut plaintext, tag) {
+            return Err(DecryptError::Invalid);
+        }
+        Ok(plaintext)
+    }
+}
+
+/**
+ * XChaCha20-Poly1305, what everything is encrypted with now
+ */
+pub struct XChaCha20Poly1305;
+
+impl Aead for XChaCha20Poly1305 {
+    fn id(&self) -> u8 {
+        AEAD_XCHACHA20_POLY1305
+    }
+
+    fn name(&self) -> &'static str {
+        "xchacha20-poly1305"
+    }
+
+    fn nonce_len(&self) -> usize {
+        24
+    }
+
+    fn seal(&self, key: &[u8; 32], nonce: &[u8], aad: &[u8], message: &[u8]) -> Result<Vec<u8>, EncryptError> {
+        use chacha20poly1305::aead::{Aead as _, KeyInit, Payload};
+        if nonce.len() != self.nonce_len() {
+            return Err(EncryptError::InvalidNonce);
+        }
+        let sealed = chacha20poly1305::XChaCha20Poly1305::new(key.into())
+            .encrypt(nonce.into(), Payload { msg: message, aad })
+            // only fails for messages over 256 GiB
+            .expect("message too long to encrypt");
+        Ok(sealed)
+    }
+
+    fn open(&self, key: &[u8; 32], nonce: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, DecryptError> {
+        use chacha20poly1305::aead::{Aead as _, KeyInit, Payload};
+        if sealed.len() < 16 || nonce.len() != self.nonce_len() {
+            return Err(DecryptError::Malformed);
+        }
+        chacha20poly1305::XChaCha20Poly1305::new(key.into())
+            .decrypt(nonce.into(), Payload { msg: sealed, aad })
+            .map_err(|_| DecryptError::Invalid)
     }
+}
+
+/**
+ * the cipher with the given identifier, None if this version doesn't know it
+ */
+pub fn aead(id: u8) -> Option<&'static dyn Aead> {
+    match id {
+        AEAD_CHACHA20_POLY1305 => Some(&ChaCha20Poly1305Legacy),
+        AEAD_XCHACHA20_POLY1305 => Some(&XChaCha20Poly1305),
+        _ => None,
+    }
+}
+
+/**
+ * the cipher with the given name, as written in key files
+ */
+pub fn aead_from_name(name: &str) -> Option<&'static dyn Aead> {
+    [AEAD_CHACHA20_POLY1305, AEAD_XCHACHA20_POLY1305]
+        .into_iter()
+        .filter_map(aead)
+        .find(|aead| aead.name() == name)
+}
+
+/**
+ * the cipher new envelopes and key files are sealed with
+ */
+pub fn current_aead() -> &'static dyn Aead {
+    &XChaCha20Poly1305
+}
+
+#[derive(Debug)]
+pub enum EncryptError {
+    RngInitializationFailed,
+    // the nonce passed to Aead::seal doesn't have the length of the cipher
+    InvalidNonce,
+}
 
-    for (dest, src) in (&mut output[32..48]).iter_mut().zip(tag.iter()) {
-        *dest = *src;
+impl From<EncryptError> for Error {
+    fn from(e: EncryptError) -> Error {
+        match e {
+            EncryptError::RngInitializationFailed => {
+                Error::Crypto("could not initialize the random number generator".to_string())
+            }
+            EncryptError::InvalidNonce => Error::Crypto("the nonce doesn't have the length the cipher needs".to_string()),
+        }
     }
+}
 
+/**
+ * encrypts message for the holder of the private key of public_key, as an envelope:
+ * version, kem and aead identifiers, the ephemeral public key, the nonce, then the ciphertext and its tag.
+ * the header is authenticated along with the message, so the identifiers can't be swapped
+ */
+pub fn encrypt(public_key: &[u8; 32], message: &[u8]) -> Result<Vec<u8>, EncryptError> {
+    seal_envelope(public_key, message, None)
+}
+
+/**
+ * same as encrypt, binding the message to associated data, like the name of the key it's saved under.
+ * the associated data isn't saved, decrypting needs the same one
+ */
+pub fn encrypt_bound(public_key: &[u8; 32], message: &[u8], associated: &[u8]) -> Result<Vec<u8>, EncryptError> {
+    seal_envelope(public_key, message, Some(associated))
+}
+
+fn seal_envelope(public_key: &[u8; 32], message: &[u8], associated: Option<&[u8]>) -> Result<Vec<u8>, EncryptError> {
+    let aead = current_aead();
+    let ephemeral_secret_key = Zeroizing::new(random_bytes::<32>()?);
+    let ephemeral_public_key = curve25519_base(&*ephemeral_secret_key);
+    let shared_secret = Zeroizing::new(curve25519(&*ephemeral_secret_key, public_key));
+    let key = Zeroizing::new(envelope_key(&shared_secret, &ephemeral_public_key, public_key));
+    let nonce = random_vec(aead.nonce_len())?;
+
+    let version = match associated {
+        Some(_) => ENVELOPE_VERSION_BOUND,
+        None => ENVELOPE_VERSION,
+    };
+    let mut output = Vec::with_capacity(OVERHEAD + message.len());
+    output.extend_from_slice(&[version, KEM_X25519_HMAC_SHA256, aead.id()]);
+    output.extend_from_slice(&ephemeral_public_key);
+    let sealed = aead.seal(&key, &nonce, &[&output[..], associated.unwrap_or_default()].concat(), message)?;
+    output.extend_from_slice(&nonce);
+    output.extend_from_slice(&sealed);
     Ok(output)
 }
 
-pub fn encrypt_string(public_key: &[u8; 32], message: String) -> String {
+/**
+ * key of an envelope. the shared secret is not uniformly random, so instead of being used
+ * as it is, it keys a hash over both public keys
+ */
+fn envelope_key(shared_secret: &[u8; 32], ephemeral_public_key: &[u8; 32], public_key: &[u8; 32]) -> [u8; 32] {
+    keyed_hash(shared_secret, &[&b"gui-kvstore envelope"[..], ephemeral_public_key, public_key].concat())
+}
+
+/**
+ * encrypt_bound for strings, hex encoded
+ */
+pub fn encrypt_string_bound(public_key: &[u8; 32], message: &str, associated: &[u8]) -> Result<String, Error> {
+    Ok(encode_hex(&encrypt_bound(public_key, message.as_bytes(), associated)?))
+}
+
+/**
+ * decrypt_bound for strings, hex encoded
+ */
+pub fn decrypt_string_bound(secret_key: &[u8; 32], message: &str, associated: &[u8]) -> Result<String, Error> {
+    let message_bytes = decode_hex(message)
+        .map_err(|_| Error::Corrupt("encrypted value is not hex encoded".to_string()))?;
+    String::from_utf8(decrypt_bound(secret_key, &message_bytes, associated)?)
+        .map_err(|_| Error::Corrupt("decrypted value is not valid utf-8".to_string()))
+}
+
+pub fn encrypt_string(public_key: &[u8; 32], message: String) -> Result<String, Error> {
     // converting our input message to a vector of bytes
     let message_bytes = message.as_bytes().to_vec();
-    // here we use &* to convert the vector to [u8]
-    let result = encrypt(&public_key, &*message_bytes).ok().unwrap();
+    let result = encrypt(public_key, &message_bytes)?;
     // println!("Encrypted Bytes: {:?}", result);
     //we format the bytes as strings
-    encode_hex(&result)
+    Ok(encode_hex(&result))
 }
 
-pub fn decrypt_string(secret_key: &[u8; 32], message: String) -> String {
+pub fn decrypt_string(secret_key: &[u8; 32], message: String) -> Result<String, Error> {
     // converting our input message to a vector of bytes
-    let message_bytes = decode_hex(&message).unwrap();
+    let message_bytes = decode_hex(&message)
+        .map_err(|_| Error::Corrupt("encrypted value is not hex encoded".to_string()))?;
     // println!("Encrypted Bytes: {:?}", message_bytes);
     //
-    let result = decrypt(&secret_key, &message_bytes).ok().unwrap();
+    let result = decrypt(secret_key, &message_bytes)?;
     //we format the bytes as strings
-    String::from_utf8(result).unwrap()
+    String::from_utf8(result)
+        .map_err(|_| Error::Corrupt("decrypted value is not valid utf-8".to_string()))
 }
 
 pub fn decode_hex(s: &str) -> Result<Vec<u8>, ParseIntError> {
     (0..s.len())
         .step_by(2)
-        .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
+        // odd lengths and non ascii characters end up as a parse error instead of a panic
+        .map(|i| u8::from_str_radix(s.get(i..i + 2).unwrap_or(""), 16))
         .collect()
 }
 
@@ -74,36 +277,200 @@ pub fn encode_hex(bytes: &[u8]) -> String {
     s
 }
 
+#[derive(Debug)]
 pub enum DecryptError {
     Malformed,
     Invalid,
 }
 
+impl From<DecryptError> for Error {
+    fn from(e: DecryptError) -> Error {
+        match e {
+            DecryptError::Malformed => Error::Corrupt("encrypted value is too short".to_string()),
+            DecryptError::Invalid => {
+                Error::Crypto("value could not be decrypted with the private key".to_string())
+            }
+        }
+    }
+}
+
+/**
+ * decrypts an envelope, or a message encrypted before envelopes existed
+ */
 pub fn decrypt(secret_key: &[u8; 32], message: &[u8]) -> Result<Vec<u8>, DecryptError> {
-    if message.len() < 48 {
-        return Err(DecryptError::Malformed);
+    decrypt_bound(secret_key, message, &[])
+}
+
+/**
+ * decrypts an envelope made by encrypt_bound with the same associated data. envelopes without
+ * associated data, and older messages, decrypt as they are
+ */
+pub fn decrypt_bound(secret_key: &[u8; 32], message: &[u8], associated: &[u8]) -> Result<Vec<u8>, DecryptError> {
+    // messages from before envelopes have no header, they start right with the ephemeral key.
+    // an envelope that doesn't open is an error, it isn't tried again as an old message
+    match envelope_aead(message) {
+        Some(aead) => decrypt_envelope(secret_key, message, associated, aead),
+        None => decrypt_legacy(secret_key, message),
     }
+}
 
-    let ephemeral_public_key = &message[0..32];
-    let tag = &message[32..48];
-    let ciphertext = &message[48..];
+/**
+ * the cipher named by the header of an envelope, None if message doesn't start with one
+ */
+fn envelope_aead(message: &[u8]) -> Option<&'static dyn Aead> {
+    if message.len() < HEADER_LEN
+        || message[1] != KEM_X25519_HMAC_SHA256
+        || ![ENVELOPE_VERSION, ENVELOPE_VERSION_BOUND].contains(&message[0])
+    {
+        return None;
+    }
+    aead(message[2])
+}
 
-    let mut plaintext = vec![0; ciphertext.len()];
-    let symmetric_key = curve25519(secret_key, ephemeral_public_key);
+fn decrypt_envelope(
+    secret_key: &[u8; 32],
+    message: &[u8],
+    associated: &[u8],
+    aead: &dyn Aead,
+) -> Result<Vec<u8>, DecryptError> {
+    let associated = match message[0] {
+        ENVELOPE_VERSION_BOUND => associated,
+        _ => &[][..],
+    };
+    let (header, rest) = message.split_at(HEADER_LEN);
+    if rest.len() < aead.nonce_len() + 16 {
+        return Err(DecryptError::Malformed);
+    }
+    let (nonce, sealed) = rest.split_at(aead.nonce_len());
+    let mut ephemeral_public_key = [0u8; 32];
+    ephemeral_public_key.copy_from_slice(&header[3..]);
+    let public_key = curve25519_base(secret_key);
+    let shared_secret = Zeroizing::new(curve25519(secret_key, &ephemeral_public_key));
+    let key = Zeroizing::new(envelope_key(&shared_secret, &ephemeral_public_key, &public_key));
+    aead.open(&key, nonce, &[header, associated].concat(), sealed)
+}
 
-    let mut decrypter = ChaCha20Poly1305::new(&symmetric_key[..], &[0u8; 8][..], &[]);
-    if !decrypter.decrypt(ciphertext, &mut plaintext[..], tag) {
-        return Err(DecryptError::Invalid);
+/**
+ * messages from before envelopes: the ephemeral public key, the tag and the ciphertext,
+ * sealed with the raw shared secret and a zero nonce
+ */
+fn decrypt_legacy(secret_key: &[u8; 32], message: &[u8]) -> Result<Vec<u8>, DecryptError> {
+    if message.len() < 48 {
+        return Err(DecryptError::Malformed);
     }
+    let (ephemeral_public_key, rest) = message.split_at(32);
+    let (tag, ciphertext) = rest.split_at(16);
+    let symmetric_key = Zeroizing::new(curve25519(secret_key, ephemeral_public_key));
+    ChaCha20Poly1305Legacy.open(&symmetric_key, &[0u8; 8], &[], &[ciphertext, tag].concat())
+}
 
-    Ok(plaintext)
+/**
+ * derives a key from a passphrase with scrypt, which needs 128 * r * 2^log_n bytes of memory
+ */
+pub fn derive_key(passphrase: &str, salt: &[u8], log_n: u8, r: u32, p: u32) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    scrypt(passphrase.as_bytes(), salt, &ScryptParams::new(log_n, r, p), &mut key);
+    key
 }
 
-pub fn generate_key_pair() -> ([u8; 32], [u8; 32]) {
+pub fn random_bytes<const N: usize>() -> Result<[u8; N], EncryptError> {
+    let mut bytes = [0u8; N];
+    OsRng::new()
+        .map_err(|_| EncryptError::RngInitializationFailed)?
+        .fill_bytes(&mut bytes);
+    Ok(bytes)
+}
+
+pub fn random_vec(len: usize) -> Result<Vec<u8>, EncryptError> {
+    let mut bytes = vec![0u8; len];
+    OsRng::new()
+        .map_err(|_| EncryptError::RngInitializationFailed)?
+        .fill_bytes(&mut bytes);
+    Ok(bytes)
+}
+
+pub fn generate_key_pair() -> Result<([u8; 32], [u8; 32]), EncryptError> {
     let mut secret_key = [0u8; 32];
-    OsRng::new().unwrap().fill_bytes(&mut secret_key[..]);
+    OsRng::new()
+        .map_err(|_| EncryptError::RngInitializationFailed)?
+        .fill_bytes(&mut secret_key[..]);
     let public_key = curve25519_base(&secret_key[..]);
-    (public_key, secret_key)
+    Ok((public_key, secret_key))
+}
+
+/**
+ * the public key of a private key
+ */
+pub fn public_key(secret_key: &[u8; 32]) -> [u8; 32] {
+    curve25519_base(secret_key)
+}
+
+/**
+ * first 4 bytes of the SHA-256 of data, hex encoded, to catch mistakes in text typed by hand
+ */
+pub fn checksum(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    let mut digest = [0u8; 32];
+    hasher.result(&mut digest);
+    encode_hex(&digest[..4])
+}
+
+/**
+ * returns a short, printable identifier for a public key:
+ * the first 8 bytes of its SHA-256 digest, hex encoded
+ */
+pub fn fingerprint(public_key: &[u8; 32]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(public_key);
+    let mut digest = [0u8; 32];
+    hasher.result(&mut digest);
+    encode_hex(&digest[..8])
+}
+
+/**
+ * SHA-256 of everything written to it
+ */
+pub struct Hasher(Sha256);
+
+impl Hasher {
+    pub fn new() -> Hasher {
+        Hasher(Sha256::new())
+    }
+
+    pub fn finish(mut self) -> [u8; 32] {
+        let mut digest = [0u8; 32];
+        self.0.result(&mut digest);
+        digest
+    }
+}
+
+impl Default for Hasher {
+    fn default() -> Hasher {
+        Hasher::new()
+    }
+}
+
+impl std::io::Write for Hasher {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.0.input(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/**
+ * HMAC-SHA256 of data, so equal data can be found without revealing it to whoever doesn't have the key
+ */
+pub fn keyed_hash(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut hmac = Hmac::new(Sha256::new(), key);
+    hmac.input(data);
+    let mut digest = [0u8; 32];
+    digest.copy_from_slice(hmac.result().code());
+    digest
 }
 
 #[cfg(test)]
@@ -134,26 +501,85 @@ mod tests {
         {
             // Corrupt the tag
             let mut corrupt_2 = encrypted_message.clone();
-            corrupt_2[35] ^= 1;
+            corrupt_2[encrypted_message.len() - 1] ^= 1;
             assert!(decrypt(&secret_key, &corrupt_2[..]).is_err());
         }
 
         {
             // Corrupt the message
             let mut corrupt_3 = encrypted_message.clone();
-            corrupt_3[50] ^= 1;
+            corrupt_3[OVERHEAD - 16] ^= 1;
             assert!(decrypt(&secret_key, &corrupt_3[..]).is_err());
         }
     }
     #[test]
     fn high_level_test() {
-        let (public, private) = generate_key_pair();
+        let (public, private) = generate_key_pair().unwrap();
         let test_string = "Testing encryption";
         // println!("Original: {}", test_string);
-        let encrypted = encrypt_string(&public, test_string.to_string());
+        let encrypted = encrypt_string(&public, test_string.to_string()).unwrap();
         // println!("Encrypted: {}", encrypted);
-        let decrypted = decrypt_string(&private, encrypted);
+        let decrypted = decrypt_string(&private, encrypted.to_string()).unwrap();
         // println!("Decrypted: {}", decrypted);
         assert_eq!(test_string, decrypted);
+        // a different key can't decrypt it, and garbage is reported as corruption
+        let (_, other_private) = generate_key_pair().unwrap();
+        assert!(matches!(decrypt_string(&other_private, encrypted), Err(Error::Crypto(_))));
+        assert!(matches!(decrypt_string(&private, "abc".to_string()), Err(Error::Corrupt(_))));
+    }
+    #[test]
+    fn fingerprint_test() {
+        let (public, _) = generate_key_pair().unwrap();
+        let (other, _) = generate_key_pair().unwrap();
+        assert_eq!(fingerprint(&public).len(), 16);
+        assert_eq!(fingerprint(&public), fingerprint(&public));
+        assert_ne!(fingerprint(&public), fingerprint(&other));
+        assert_eq!(keyed_hash(&public, b"data"), keyed_hash(&public, b"data"));
+        let key = derive_key("passphrase", b"salt", 4, 8, 1);
+        assert_ne!(key, derive_key("other", b"salt", 4, 8, 1));
+        let sealed = current_aead().seal(&key, &[1; 24], b"aad", b"message").unwrap();
+        assert_eq!(current_aead().open(&key, &[1; 24], b"aad", &sealed).unwrap(), b"message");
+        assert!(matches!(
+            current_aead().open(&key, &[1; 24], b"other", &sealed),
+            Err(DecryptError::Invalid)
+        ));
+        assert_ne!(keyed_hash(&public, b"data"), keyed_hash(&other, b"data"));
+    }
+
+    #[test]
+    fn envelopes() {
+        let (public, private) = generate_key_pair().unwrap();
+        let message = encrypt(&public, b"value").unwrap();
+        assert_eq!(
+            message[..3],
+            [ENVELOPE_VERSION, KEM_X25519_HMAC_SHA256, AEAD_XCHACHA20_POLY1305]
+        );
+        assert_eq!(message.len(), OVERHEAD + 5);
+        // the identifiers are authenticated, they can't be swapped for a weaker cipher
+        let mut swapped = message.clone();
+        swapped[2] = AEAD_CHACHA20_POLY1305;
+        assert!(decrypt(&private, &swapped).is_err());
+        // messages from before envelopes still decrypt
+        let ephemeral = generate_key_pair().unwrap();
+        let sealed = ChaCha20Poly1305Legacy
+            .seal(&curve25519(&ephemeral.1, &public), &[0; 8], &[], b"old value")
+            .unwrap();
+        let (ciphertext, tag) = sealed.split_at(sealed.len() - 16);
+        let legacy = [&ephemeral.0[..], tag, ciphertext].concat();
+        assert_eq!(decrypt(&private, &legacy).unwrap(), b"old value");
+        // a nonce of the wrong length is an error, not a panic
+        for aead in [aead(AEAD_CHACHA20_POLY1305).unwrap(), aead(AEAD_XCHACHA20_POLY1305).unwrap()] {
+            assert!(matches!(aead.seal(&public, &[0; 12], &[], b"value"), Err(EncryptError::InvalidNonce)));
+        }
+        assert_eq!(aead_from_name("xchacha20-poly1305").map(|aead| aead.id()), Some(AEAD_XCHACHA20_POLY1305));
+        assert!(aead(0).is_none());
+        // bound envelopes only open with the same associated data
+        let bound = encrypt_bound(&public, b"value", b"api_token").unwrap();
+        assert_eq!(bound[0], ENVELOPE_VERSION_BOUND);
+        assert_eq!(decrypt_bound(&private, &bound, b"api_token").unwrap(), b"value");
+        // envelopes that don't open fail as they are, without being tried as old messages
+        assert!(matches!(decrypt_bound(&private, &bound, b"other_key"), Err(DecryptError::Invalid)));
+        assert!(decrypt(&private, &bound).is_err());
+        assert_eq!(decrypt_bound(&private, &message, b"api_token").unwrap(), b"value");
     }
 }
@@ -1,111 +1,1079 @@
-use std::{collections::HashMap, path::Path};
-#[path = "log.rs"]
-mod log;
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-// where to save our files. ideally we move this to a config file later
-//static DB_DIR: &str = "C:\\var\\gui-kvstore";
+use zeroize::Zeroize;
+
+use crate::blob;
+use crate::crypto;
+use crate::error::{Error, Result};
+use crate::integrity::{self, Integrity};
+use crate::keys::KeyPair;
+use crate::limits::{self, Access, Limits, Usage};
+use crate::log;
+use crate::mvcc::{Snapshot, VersionedMap};
+use crate::sealed::{self, SealedReader, SealedWriter};
+
+// first line of every store file written by this version of the app
+static HEADER_PREFIX: &str = "#gui-kvstore";
+// bump this whenever the layout of the store file changes
+pub const FORMAT_VERSION: u32 = 2;
+// a lock file older than this was left behind by a process that died while flushing
+const STALE_LOCK_AGE: Duration = Duration::from_secs(30);
+// how long opening a store for writing waits for another process writing it
+const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+const LOCK_RETRY: Duration = Duration::from_millis(20);
+// file next to a cache store with how its entries were used. reads update it, not the store file
+static USAGE_SUFFIX: &str = ".usage";
+// files kept next to a store file, they go along with it when it's dropped, renamed or copied
+static SIDECAR_SUFFIXES: [&str; 2] = [USAGE_SUFFIX, blob::REFS_SUFFIX];
+// header field set on stores that save their values as they are, unencrypted. the MAC of the
+// store still covers them, keyed by its data key
+pub static META_PLAINTEXT: &str = "plaintext";
+
+/**
+ * A value saved in the store, along with its attributes
+ */
+#[derive(Clone)]
+pub struct Record {
+    pub value: String,
+    // unix timestamp after which the record is gone. it stays in the file until the next flush
+    pub expires: Option<u64>,
+    // set when the value is a reference to a file in the blob directory, holding its size
+    pub blob: Option<u64>,
+    // unix timestamp of the last write, None for records written before it was tracked
+    pub modified: Option<u64>,
+}
+
+impl Record {
+    pub fn new(value: String) -> Record {
+        Record {
+            value,
+            expires: None,
+            blob: None,
+            modified: None,
+        }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires.map(|e| e <= now).unwrap_or(false)
+    }
+}
+
+/**
+ * Where a Database keeps its entries
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Engine {
+    // entries are loaded from and flushed to the store file
+    File,
+    // entries only live as long as the Database, nothing touches the disk
+    Memory,
+}
+
+impl Engine {
+    pub fn from_name(name: &str) -> Option<Engine> {
+        match name {
+            "file" => Some(Engine::File),
+            "memory" => Some(Engine::Memory),
+            _ => None,
+        }
+    }
+}
 
 pub struct Database {
-    db_data: HashMap<String, String>,
+    // every committed version of the entries still visible to a snapshot
+    data: VersionedMap,
+    meta: HashMap<String, String>,
+    // parsed from the header fields
+    limits: Limits,
+    // when entries were used, only tracked for caches
+    usage: Mutex<Usage>,
     db_file_path: String,
     logger: log::Logger,
+    dirty: bool,
+    engine: Engine,
+    // opened with open_file, flush refuses to write
+    read_only: bool,
+    // data key the file is sealed with, see the sealed module
+    seal: Option<KeyPair>,
+    // a sealed file that wasn't unsealed yet, only its header is loaded
+    locked: bool,
+    // hash of the header and records as they were read, checked against the MAC of the store
+    content_hash: [u8; 32],
+    // key of the MAC written on flush, see the integrity module
+    integrity: Option<Integrity>,
+    // held from open until dropped when opened with open_locked, flush takes it otherwise
+    lock: Option<StoreLock>,
+    pub name: String,
+}
+
+/**
+ * What was read from a store file
+ */
+#[derive(Default)]
+struct Contents {
+    meta: HashMap<String, String>,
+    records: HashMap<String, Record>,
+    usage: Usage,
+    // of everything but the MAC
+    hash: [u8; 32],
+}
+
+/**
+ * Summary of a store file, as displayed by the --info command
+ */
+pub struct StoreInfo {
     pub name: String,
+    pub keys: usize,
+    pub file_size: u64,
+    pub modified: SystemTime,
+    pub version: u32,
+    pub fingerprint: Option<String>,
+    pub limits: Limits,
+}
+
+/**
+ * Size analytics of a store, as displayed by the --stats command
+ */
+pub struct StoreStats {
+    pub name: String,
+    // live keys, the stored size of their values and if they are encrypted and hex encoded, largest first
+    pub value_sizes: Vec<(String, usize, bool)>,
+    // entries past their expiry that are still in the file
+    pub expired: usize,
+    pub file_size: u64,
+    // how big the file would be if it was flushed now
+    pub compacted_size: u64,
+}
+
+/**
+ * escapes backslashes, tabs and line breaks, which would split a field of a line in a file
+ */
+pub(crate) fn escape(field: &str) -> String {
+    field.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+pub(crate) fn unescape(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/**
+ * current unix timestamp in seconds
+ */
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
 }
 
 impl Database {
-    pub fn new(store_name: String, store_path: String, log_debug:bool) -> Result<Database, std::io::Error> {
+    /**
+     * opens a store with the given engine. the memory engine starts empty and never writes
+     */
+    pub fn open(store_name: String, store_path: String, log_debug: bool, engine: Engine) -> Result<Database> {
         let mut logger:log::Logger = log::Logger::new();
         logger.toggle_debug(log_debug);
         //
         logger.debug(format!("Store Path: {}", store_path));
         //
-        let mut db_map = HashMap::new();
+        let mut contents = Contents::default();
+        let mut locked = false;
         // creating map to save entries into
         let db_file_path = Database::get_store_filename(&store_path, &store_name);
         logger.debug(format!("Store File: {}", &db_file_path));
         //checking if file exists
         let file_exists = std::path::Path::new(&db_file_path).exists();
         //
-        if file_exists {
-            /*
-            let contents = match std::fs::read_to_string(format!("{}.db", store)) {
-                Ok(c) => c,
-                Err(e) => {
-                    return Err(e);
-                }
-            };
-            */
-            let contents: String = std::fs::read_to_string(&db_file_path)?; //the question mark here is equivalent to the commented block above
-                                                                         // reading each line of the file and saving them to the map
-            for line in contents.lines() {
-                //.lines here returns slices or views to the lines, which is represented by the &str
-                let mut chunks = line.splitn(2, '\t');
-                let key: &str = chunks.next().expect("no key!"); // getting key as a string slice
-                let value: &str = chunks.next().expect("no value!"); // getting value as a string slice
-                db_map.insert(key.to_owned(), value.to_owned()); // using to_owned() copies the strings to an owned value
+        if engine == Engine::Memory {
+            logger.debug("memory engine, not touching the store file".to_string());
+        } else if file_exists {
+            let mut reader = BufReader::new(std::fs::File::open(&db_file_path)?);
+            // the records of a sealed store are only read once it's given the key, by unseal
+            if sealed::is_sealed(reader.fill_buf()?) {
+                let mut header = String::new();
+                reader.read_line(&mut header)?;
+                contents.meta = Database::parse_header(header.trim_end());
+                locked = true;
+            } else {
+                contents = Database::parse_lines(reader.lines(), &db_file_path)?;
             }
         } else {
             // create file
             //std::fs::File::create(&db_file_path).expect("create failed");
             let db_path = Path::new(&db_file_path);
-            std::fs::create_dir_all(db_path.clone().parent().unwrap())?;
-            std::fs::File::create(&db_file_path).expect("create failed");
+            if let Some(parent) = db_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::File::create(&db_file_path)?;
         }
+        // a brand new store still needs its header written
+        let dirty = !file_exists;
+        let limits = Limits::from_meta(&contents.meta)?;
 
         // returning the Database struct wrapped in an Ok
-        Ok(Database {
-            db_data: db_map,
+        let db = Database {
+            data: VersionedMap::from_records(contents.records),
+            meta: contents.meta,
+            limits,
+            usage: Mutex::new(contents.usage),
             name: store_name,
             db_file_path,
             logger,
+            dirty,
+            engine,
+            read_only: false,
+            seal: None,
+            locked,
+            content_hash: contents.hash,
+            integrity: None,
+            lock: None,
+        };
+        db.load_usage()?;
+        Ok(db)
+    }
+
+    /**
+     * same as open, taking the lock of the store file before reading it and holding it until the
+     * Database is dropped. nothing else writes the store in between, so changes made to what was
+     * read can't overwrite the ones of another process. waits a bit for a process writing it
+     */
+    pub fn open_locked(store_name: String, store_path: String, log_debug: bool, engine: Engine) -> Result<Database> {
+        if engine == Engine::Memory {
+            return Database::open(store_name, store_path, log_debug, engine);
+        }
+        std::fs::create_dir_all(&store_path)?;
+        let lock = StoreLock::wait(&Database::get_store_filename(&store_path, &store_name), LOCK_TIMEOUT)?;
+        let mut db = Database::open(store_name, store_path, log_debug, engine)?;
+        db.lock = Some(lock);
+        Ok(db)
+    }
+
+    /**
+     * parses the lines of a store file: the header, if it has one, and the records
+     */
+    fn parse_lines(
+        lines: impl Iterator<Item = std::io::Result<String>>,
+        db_file_path: &str,
+    ) -> Result<Contents> {
+        let (mut meta, mut records, mut usage) = (HashMap::new(), HashMap::new(), Usage::default());
+        // the MAC covers the file as it was written, except for itself
+        let mut hasher = crypto::Hasher::new();
+        let mut lines = lines.peekable();
+        // stores written before the header existed are treated as format version 0
+        if let Some(Ok(header)) = lines.next_if(|l| l.as_ref().is_ok_and(|l| l.starts_with(HEADER_PREFIX))) {
+            meta = Database::parse_header(&header);
+            let mac_field = format!("{}=", integrity::META_MAC);
+            let without_mac: Vec<&str> = header.split('\t').filter(|f| !f.starts_with(&mac_field)).collect();
+            writeln!(hasher, "{}", without_mac.join("\t"))?;
+        }
+        let version: u32 = meta
+            .get("version")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        if version > FORMAT_VERSION {
+            return Err(Error::Format(format!(
+                "{} was written with format v{}, this version reads up to v{}",
+                db_file_path, version, FORMAT_VERSION
+            )));
+        }
+        for (number, line) in lines.enumerate() {
+            let line = line?;
+            writeln!(hasher, "{}", line)?;
+            let mut chunks = line.splitn(3, '\t');
+            let key: &str = chunks.next().unwrap_or_default(); // getting key as a string slice
+            // getting value as a string slice
+            let value: &str = chunks.next().ok_or_else(|| {
+                Error::Corrupt(format!("record {} of {} has no value", number + 1, db_file_path))
+            })?;
+            let mut record = Record::new(value.to_owned()); // using to_owned() copies the strings to an owned value
+            // since format version 2 records can carry attributes in a third column
+            if let Some(attributes) = chunks.next() {
+                let mut access = Access::default();
+                Database::parse_attributes(&mut record, &mut access, attributes);
+                if access != Access::default() {
+                    usage.load(key, access);
+                }
+            }
+            records.insert(key.to_owned(), record);
+        }
+        Ok(Contents {
+            meta,
+            records,
+            usage,
+            hash: hasher.finish(),
         })
     }
 
     /**
-     * inserts a new entry in the db
+     * loads the records of a sealed store, decrypting it with its data key. the store stays sealed
+     * with that key when flushed. nothing to do for stores that aren't sealed
+     */
+    pub(crate) fn unseal(&mut self, keypair: &KeyPair) -> Result<()> {
+        if !self.locked {
+            return Ok(());
+        }
+        let mut input = BufReader::new(std::fs::File::open(&self.db_file_path)?);
+        // the header is read again, the file may have been flushed since it was opened
+        let mut header = String::new();
+        input.read_line(&mut header)?;
+        let reader = SealedReader::new(input, &header, &Database::parse_header(header.trim_end()), keypair)?;
+        let contents = Database::parse_lines(BufReader::new(reader).lines(), &self.db_file_path)?;
+        self.limits = Limits::from_meta(&contents.meta)?;
+        self.meta = contents.meta;
+        self.data = VersionedMap::from_records(contents.records);
+        self.usage = Mutex::new(contents.usage);
+        self.content_hash = contents.hash;
+        self.seal = Some(keypair.clone());
+        self.locked = false;
+        self.load_usage()
+    }
+
+    /**
+     * loads the usage file of a cache store, on top of the usage older versions saved in the store file
+     */
+    fn load_usage(&self) -> Result<()> {
+        if self.engine == Engine::Memory || self.locked || !self.limits.is_cache() {
+            return Ok(());
+        }
+        let contents = match std::fs::read_to_string(self.usage_path()) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let keys: HashMap<String, String> = self
+            .data
+            .snapshot()
+            .keys()
+            .into_iter()
+            .map(|key| (self.usage_id(&key), key))
+            .collect();
+        let mut usage = self.usage();
+        for line in contents.lines() {
+            let mut fields = line.split('\t');
+            // it only orders evictions, a line that doesn't make sense is skipped
+            if let (Some(key), Some(Ok(tick)), Some(Ok(hits))) = (
+                fields.next().and_then(|id| keys.get(id)),
+                fields.next().map(str::parse),
+                fields.next().map(str::parse),
+            ) {
+                usage.load(key, Access { tick, hits });
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * writes the usage file of a cache store, removing it once the store isn't a cache anymore
+     */
+    fn save_usage(&self) -> Result<()> {
+        let path = self.usage_path();
+        if !self.limits.is_cache() {
+            return match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+        let mut usage = self.usage();
+        let mut lines: Vec<String> = usage
+            .iter()
+            .map(|(key, access)| format!("{}\t{}\t{}\n", self.usage_id(key), access.tick, access.hits))
+            .collect();
+        lines.sort();
+        // processes reading the store save it at the same time, each writes its own file and renames it
+        let tmp_path = format!("{}.{}.tmp", path, std::process::id());
+        std::fs::write(&tmp_path, lines.concat())?;
+        std::fs::rename(&tmp_path, &path)?;
+        usage.changed = false;
+        Ok(())
+    }
+
+    fn usage_path(&self) -> String {
+        format!("{}{}", self.db_file_path, USAGE_SUFFIX)
+    }
+
+    /**
+     * name of key in the usage file. sealed stores hide their key names, there it's a keyed hash
+     */
+    fn usage_id(&self, key: &str) -> String {
+        match &self.seal {
+            Some(keypair) => crypto::encode_hex(&crypto::keyed_hash(&keypair.1, key.as_bytes())),
+            None => key.to_string(),
+        }
+    }
+
+    /**
+     * checks the MAC of the store and that it wasn't rolled back, then keeps the key to sign it on flush.
+     * a store from before MACs is signed right away, the only time opening a store writes it:
+     * from then on it can't lose its MAC unnoticed. returns if it was
+     */
+    pub(crate) fn protect(&mut self, integrity: Integrity) -> Result<bool> {
+        if self.engine == Engine::Memory {
+            return Ok(false);
+        }
+        integrity.verify(self)?;
+        integrity.saw(self)?;
+        // brand new stores are dirty, they get their MAC when they're first written
+        let unsigned = !self.meta.contains_key(integrity::META_MAC)
+            && !self.dirty
+            && !self.read_only
+            && integrity.remembers();
+        self.integrity = Some(integrity);
+        if unsigned {
+            self.flush()?;
+        }
+        Ok(unsigned)
+    }
+
+    /**
+     * signs the store with a new data key from the next flush on
+     */
+    pub(crate) fn rekey_integrity(&mut self, data_key: &KeyPair) {
+        if let Some(integrity) = &self.integrity {
+            self.integrity = Some(integrity.rekeyed(data_key));
+            self.dirty = true;
+        }
+    }
+
+    /**
+     * hash of the header and records as they were read, without the MAC
+     */
+    pub(crate) fn content_hash(&self) -> [u8; 32] {
+        self.content_hash
+    }
+
+    /**
+     * if the store file is encrypted whole, see the sealed module
+     */
+    pub fn is_sealed(&self) -> bool {
+        self.locked || self.seal.is_some()
+    }
+
+    /**
+     * seals the store file with the given data key on the next flush, or writes it as plain text again
+     */
+    pub(crate) fn set_seal(&mut self, keypair: Option<&KeyPair>) {
+        self.seal = keypair.cloned();
+        self.dirty = true;
+    }
+
+    /**
+     * opens a store file anywhere on disk, like a copy kept as a backup, without ever writing to it
+     */
+    pub fn open_file(path: &Path, log_debug: bool) -> Result<Database> {
+        if !path.is_file() {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("store file {} not found", path.display()),
+            )));
+        }
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let store_path = path
+            .parent()
+            .map(|parent| parent.display().to_string())
+            .unwrap_or_default();
+        let mut db = Database::open(name, store_path, log_debug, Engine::File)?;
+        db.read_only = true;
+        Ok(db)
+    }
+
+    /**
+     * inserts a new entry in the db. fails with a Quota error if it goes over the limits of the store,
+     * unless the store is a cache, then other entries are evicted to make room
+     */
+    pub fn insert(&mut self, key_arg: String, value_arg: String) -> Result<()> {
+        self.insert_record(key_arg, Record::new(value_arg))
+    }
+
+    /**
+     * same as insert, with the attributes of the record. it is stamped with the current time unless it has one
+     */
+    pub fn insert_record(&mut self, key_arg: String, mut record: Record) -> Result<()> {
+        self.make_room(&key_arg, &record)?;
+        record.modified.get_or_insert_with(now);
+        self.data.put(&key_arg, Some(record));
+        if self.limits.is_cache() {
+            self.usage().written(&key_arg);
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    /**
+     * checks the limits for a write of key, evicting entries from caches until it fits
+     */
+    fn make_room(&mut self, key: &str, record: &Record) -> Result<()> {
+        self.limits.check_record(key, record)?;
+        let now = now();
+        let (mut keys, mut bytes) = (1, limits::record_size(key, record));
+        self.data.for_each(|other, record| {
+            if other != key && !record.is_expired(now) {
+                keys += 1;
+                bytes += limits::record_size(other, record);
+            }
+        });
+        // the other entries are only copied when some of them have to be evicted
+        let others = match (self.limits.exceeded(keys, bytes), self.limits.is_cache()) {
+            (Some(_), true) => self
+                .live_records()
+                .into_iter()
+                .filter(|(other, _)| other != key)
+                .collect(),
+            _ => vec![],
+        };
+        self.evict(others, keys, bytes)
+            .map_err(|reason| Error::Quota(format!("can't save '{}' in store '{}': {}", key, self.name, reason)))
+    }
+
+    /**
+     * drops candidates in eviction order until keys and bytes fit the limits.
+     * returns why they don't fit when the store isn't a cache or evicting isn't enough
+     */
+    fn evict(&mut self, candidates: Vec<(String, Record)>, mut keys: usize, mut bytes: usize) -> std::result::Result<(), String> {
+        let policy = match (self.limits.exceeded(keys, bytes), self.limits.eviction) {
+            (None, _) => return Ok(()),
+            (Some(reason), None) => return Err(reason),
+            (Some(_), Some(policy)) => policy,
+        };
+        let victims = limits::eviction_order(policy, candidates, &self.usage());
+        for (victim, record) in victims {
+            if self.limits.exceeded(keys, bytes).is_none() {
+                break;
+            }
+            self.logger
+                .debug(format!("evicting '{}' from {} ({})", victim, self.name, policy.name()));
+            self.delete(&victim);
+            keys -= 1;
+            bytes -= limits::record_size(&victim, &record);
+        }
+        match self.limits.exceeded(keys, bytes) {
+            Some(reason) => Err(reason),
+            None => Ok(()),
+        }
+    }
+
+    pub fn engine(&self) -> Engine {
+        self.engine
+    }
+
+    /**
+     * limits of the store, from its header
+     */
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /**
+     * saves new limits in the header. a cache evicts entries right away to fit them,
+     * other stores keep their entries but can't grow until they are under the limits
+     */
+    pub fn set_limits(&mut self, limits: Limits) -> Result<()> {
+        for (field, value) in limits.to_meta() {
+            match value {
+                Some(value) => self.set_meta(field, value),
+                None => self.dirty |= self.meta.remove(field).is_some(),
+            }
+        }
+        self.limits = limits;
+        if limits.is_cache() {
+            let records = self.live_records();
+            let (keys, bytes) = (
+                records.len(),
+                records
+                    .iter()
+                    .map(|(key, record)| limits::record_size(key, record))
+                    .sum(),
+            );
+            self.evict(records, keys, bytes)
+                .map_err(|reason| Error::Quota(format!("can't fit store '{}': {}", self.name, reason)))?;
+        }
+        Ok(())
+    }
+
+    /**
+     * removes an entry from the db, returning if it was there
+     */
+    pub fn delete(&mut self, key: &str) -> bool {
+        let existed = self.data.get(key).is_some();
+        if existed {
+            self.data.put(key, None);
+            self.usage().remove(key);
+            self.dirty = true;
+        }
+        existed
+    }
+
+    /**
+     * sets the unix timestamp after which an entry expires
+     */
+    pub fn expire(&mut self, key: &str, expires: u64) {
+        if let Some(mut record) = self.data.get(key) {
+            record.expires = Some(expires);
+            self.data.put(key, Some(record));
+            self.dirty = true;
+        }
+    }
+
+    /**
+     * swaps the value of key for the same value encrypted differently, under new_key, keeping
+     * its attributes and usage. the limits aren't checked, it's the same entry
+     */
+    pub(crate) fn replace_entry(&mut self, key: &str, new_key: String, value: String) {
+        if let Some(mut record) = self.data.get(key) {
+            record.value = value;
+            if new_key != key {
+                self.data.put(key, None);
+                let mut usage = self.usage();
+                let access = usage.get(key);
+                usage.remove(key);
+                if self.limits.is_cache() {
+                    usage.load(&new_key, access);
+                }
+            }
+            self.data.put(&new_key, Some(record));
+            self.dirty = true;
+        }
+    }
+
+    /**
+     * the record of key with its attributes, even if it expired
+     */
+    pub fn get_record(&self, key: &str) -> Option<Record> {
+        self.data.get(key)
+    }
+
+    /**
+     * reads a header field of the store, like the fingerprint of the key that wrote it
+     */
+    pub fn get_meta(&self, key: &str) -> Option<&String> {
+        self.meta.get(key)
+    }
+
+    /**
+     * sets a header field of the store. it is persisted on the next flush
+     */
+    pub fn set_meta(&mut self, key: &str, value: String) {
+        if self.meta.get(key) != Some(&value) {
+            self.meta.insert(key.to_string(), value);
+            self.dirty = true;
+        }
+    }
+
+    /**
+     * sets a header field that doesn't need a write of its own, like the data key of a store that
+     * is still empty. it is persisted along with the next change
+     */
+    pub(crate) fn set_meta_quietly(&mut self, key: &str, value: String) {
+        self.meta.insert(key.to_string(), value);
+    }
+
+    /**
+     * path of the store file
+     */
+    pub(crate) fn file_path(&self) -> &str {
+        &self.db_file_path
+    }
+
+    /**
+     * opened with open_file, like a backup copy, it's never written
+     */
+    pub(crate) fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /**
+     * if the store has changes that weren't flushed yet
      */
-    pub fn insert(&mut self, key_arg: String, value_arg: String) {
-        self.db_data.insert(key_arg, value_arg);
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /**
+     * removes a header field of the store
+     */
+    pub fn remove_meta(&mut self, key: &str) {
+        if self.meta.remove(key).is_some() {
+            self.dirty = true;
+        }
+    }
+
+    /**
+     * format version the store file was written with. 0 means a file without header
+     */
+    pub fn format_version(&self) -> u32 {
+        self.meta
+            .get("version")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0)
     }
 
     /**
      * read an entry by key. if it doesn`t exist, return an empty string
      */
-    pub fn read(&mut self, key: String) -> String {
-        self.db_data.get(&key).unwrap_or(&String::from("")).to_owned()
+    pub fn read(&self, key: String) -> String {
+        match self.data.get(&key) {
+            Some(record) if !record.is_expired(now()) => {
+                if self.limits.is_cache() {
+                    self.usage().read(&key);
+                }
+                record.value
+            }
+            _ => String::from(""),
+        }
     }
 
     /**
      * flushes the database, writing it into file
      */
-    fn flush(&mut self) -> std::io::Result<()> {
+    pub fn flush(&mut self) -> Result<()> {
+        if self.engine == Engine::Memory {
+            return Ok(());
+        }
+        if self.read_only {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("{} was opened read-only", self.db_file_path),
+            )));
+        }
+        if self.locked {
+            return Err(Error::KeyMissing(format!(
+                "{} is sealed and wasn't opened with its data key",
+                self.db_file_path
+            )));
+        }
         self.logger.debug(format!("flushing db: {}", self.name));
-        let mut contents = String::new();
-        for (key, value) in &self.db_data {
-            contents.push_str(&Database::format_keypair(key, value));
+        // the same instant for the MAC and the write, so they skip the same expired entries
+        let now = now();
+        match self.integrity.clone() {
+            Some(integrity) => self.sign(&integrity, now)?,
+            // the MAC would be stale, and a store without one is taken for a tampered one
+            None if self.meta.contains_key(integrity::META_MAC) => {
+                return Err(Error::KeyMissing(format!(
+                    "{} has a MAC, it needs its data key to be written",
+                    self.db_file_path
+                )));
+            }
+            None => {}
         }
+        // only one process writes a store at a time
+        let _lock = match self.lock {
+            Some(_) => None,
+            None => Some(StoreLock::acquire(&self.db_file_path)?),
+        };
         self.logger.debug(format!("writing on: {}", &self.db_file_path));
-        std::fs::write(&self.db_file_path, contents)
+        // writing next to the store and renaming, so the file is never left half written
+        let tmp_path = format!("{}.tmp", self.db_file_path);
+        let file = BufWriter::new(std::fs::File::create(&tmp_path)?);
+        match &self.seal {
+            Some(keypair) => {
+                // the data key has to be found before the rest can be decrypted
+                let fields: Vec<(&str, &str)> = ["fingerprint", "data_key", "recipients"]
+                    .into_iter()
+                    .filter_map(|field| self.meta.get(field).map(|value| (field, value.as_str())))
+                    .collect();
+                let mut writer = SealedWriter::new(file, &fields, keypair)?;
+                self.write_contents(&mut writer, now)?;
+                writer.finish()?;
+            }
+            None => {
+                let mut writer = file;
+                self.write_contents(&mut writer, now)?;
+                writer.flush()?;
+            }
+        }
+        std::fs::rename(&tmp_path, &self.db_file_path)?;
+        self.dirty = false;
+        if let Some(integrity) = &self.integrity {
+            integrity.saw(self)?;
+        }
+        self.save_usage()
+    }
+
+    /**
+     * moves the store to its next generation and sets the MAC over what is going to be written
+     */
+    fn sign(&mut self, integrity: &Integrity, now: u64) -> Result<()> {
+        let generation = integrity::generation(self) + 1;
+        self.meta.insert(integrity::META_GENERATION.to_string(), generation.to_string());
+        if !self.meta.contains_key(integrity::META_STORE_ID) {
+            let id = crypto::encode_hex(&crypto::random_bytes::<16>()?);
+            self.meta.insert(integrity::META_STORE_ID.to_string(), id);
+        }
+        self.meta.remove(integrity::META_MAC);
+        let mut hasher = crypto::Hasher::new();
+        self.write_contents(&mut hasher, now)?;
+        self.content_hash = hasher.finish();
+        let mac = integrity.mac(&self.content_hash);
+        self.meta.insert(integrity::META_MAC.to_string(), mac);
+        Ok(())
+    }
+
+    /**
+     * writes the contents of the store file. expired entries are left out, which is how they get collected
+     */
+    fn write_contents(&self, out: &mut impl Write, now: u64) -> std::io::Result<()> {
+        out.write_all(self.format_header().as_bytes())?;
+        for (key, record) in self.data.snapshot() {
+            if !record.is_expired(now) {
+                out.write_all(Database::format_keypair(&key, &record).as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * builds the header line, always stamped with the current format version
+     */
+    fn format_header(&self) -> String {
+        let mut fields: Vec<String> = self
+            .meta
+            .iter()
+            .filter(|(key, _)| key.as_str() != "version")
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        // sorting so the header doesn't change between flushes
+        fields.sort();
+        fields.insert(0, format!("version={}", FORMAT_VERSION));
+        format!("{}\t{}\n", HEADER_PREFIX, fields.join("\t"))
+    }
+
+    /**
+     * parses a header line into its name=value fields
+     */
+    fn parse_header(line: &str) -> HashMap<String, String> {
+        let mut meta = HashMap::new();
+        for field in line.split('\t').skip(1) {
+            let mut chunks = field.splitn(2, '=');
+            let key = chunks.next().unwrap_or_default();
+            let value = chunks.next().unwrap_or_default();
+            if !key.is_empty() {
+                meta.insert(key.to_owned(), value.to_owned());
+            }
+        }
+        meta
+    }
+
+    /**
+     * returns a summary of the store: key count, file size, last modification and header fields
+     */
+    pub fn info(&self) -> Result<StoreInfo> {
+        let (file_size, modified) = match self.engine {
+            Engine::File => {
+                let metadata = std::fs::metadata(&self.db_file_path)?;
+                (metadata.len(), metadata.modified()?)
+            }
+            Engine::Memory => (0, SystemTime::now()),
+        };
+        Ok(StoreInfo {
+            name: self.name.to_string(),
+            keys: self.live_count(),
+            file_size,
+            modified,
+            version: self.format_version(),
+            fingerprint: self.meta.get("fingerprint").cloned(),
+            limits: self.limits,
+        })
+    }
+
+    /**
+     * returns value sizes, expired entries and how much of the file would be reclaimed by a flush.
+     * encrypted tells the records saved encrypted and hex encoded from the ones saved as they are
+     */
+    pub fn stats(&self, encrypted: impl Fn(&Record) -> bool) -> Result<StoreStats> {
+        let now = now();
+        let (mut value_sizes, mut expired) = (vec![], 0);
+        // only the sizes are needed, the records aren't copied
+        self.data.for_each(|key, record| match record.is_expired(now) {
+            true => expired += 1,
+            false => value_sizes.push((key.to_string(), record.value.len(), encrypted(record))),
+        });
+        value_sizes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        Ok(StoreStats {
+            name: self.name.to_string(),
+            expired,
+            value_sizes,
+            file_size: match self.engine {
+                Engine::File => std::fs::metadata(&self.db_file_path)?.len(),
+                Engine::Memory => 0,
+            },
+            compacted_size: match self.engine {
+                Engine::File => {
+                    let mut size = CountingWriter(0);
+                    self.write_contents(&mut size, now)?;
+                    size.0
+                }
+                Engine::Memory => 0,
+            },
+        })
+    }
+
+    /**
+     * how many entries haven't expired
+     */
+    pub fn live_count(&self) -> usize {
+        let now = now();
+        self.data.count(|record| !record.is_expired(now))
+    }
+
+    /**
+     * the entries that haven't expired, sorted by key
+     */
+    pub fn live_records(&self) -> Vec<(String, Record)> {
+        let now = now();
+        self.data
+            .snapshot()
+            .records()
+            .into_iter()
+            .filter(|(_, record)| !record.is_expired(now))
+            .collect()
+    }
+
+    /**
+     * pins the current state of the entries, writes made after this are not seen by it.
+     * expired entries are still in there, readers check is_expired themselves
+     */
+    pub fn snapshot(&self) -> Snapshot {
+        self.data.snapshot()
+    }
+
+    /**
+     * number of entry versions kept in memory, more than the entries while snapshots are open
+     */
+    pub fn retained_versions(&self) -> usize {
+        self.data.retained_versions()
+    }
+
+    /**
+     * names of all the stores in store_path
+     */
+    pub fn list_stores(store_path: &str) -> Result<Vec<String>> {
+        let mut stores: Vec<String> = vec![];
+        for entry in std::fs::read_dir(store_path)? {
+            let path = entry?.path();
+            if path.extension().map(|e| e == "db").unwrap_or(false) {
+                if let Some(name) = path.file_stem() {
+                    stores.push(name.to_string_lossy().to_string());
+                }
+            }
+        }
+        stores.sort();
+        Ok(stores)
+    }
+
+    /**
+     * checks if a store with store_name exists in store_path
+     */
+    pub fn exists(store_path: &str, store_name: &str) -> bool {
+        Path::new(&Database::get_store_filename(store_path, store_name)).exists()
+    }
+
+    /**
+     * deletes the file of the store with store_name
+     */
+    pub fn drop_store(store_path: &str, store_name: &str) -> Result<()> {
+        let db_file_path = Database::get_store_filename(store_path, store_name);
+        let refs = blob::read_refs(&db_file_path)?;
+        std::fs::remove_file(&db_file_path)?;
+        for suffix in SIDECAR_SUFFIXES {
+            match std::fs::remove_file(format!("{}{}", db_file_path, suffix)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        // the files of the store go with it, unless another store has them too
+        let blob_dir = Path::new(store_path).parent().unwrap_or_else(|| Path::new(".")).join("blobs");
+        blob::release(&blob_dir, Path::new(store_path), refs.unwrap_or_default())
+    }
+
+    /**
+     * renames a store file. fails if the target store already exists
+     */
+    pub fn rename_store(store_path: &str, from: &str, to: &str) -> Result<()> {
+        Database::ensure_absent(store_path, to)?;
+        let (from, to) = (
+            Database::get_store_filename(store_path, from),
+            Database::get_store_filename(store_path, to),
+        );
+        std::fs::rename(&from, &to)?;
+        for suffix in SIDECAR_SUFFIXES {
+            let from_sidecar = format!("{}{}", from, suffix);
+            if Path::new(&from_sidecar).exists() {
+                std::fs::rename(from_sidecar, format!("{}{}", to, suffix))?;
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * copies a store file into a new store. fails if the target store already exists
+     */
+    pub fn copy_store(store_path: &str, from: &str, to: &str) -> Result<()> {
+        Database::ensure_absent(store_path, to)?;
+        let (from, to) = (
+            Database::get_store_filename(store_path, from),
+            Database::get_store_filename(store_path, to),
+        );
+        std::fs::copy(&from, &to)?;
+        for suffix in SIDECAR_SUFFIXES {
+            let from_sidecar = format!("{}{}", from, suffix);
+            if Path::new(&from_sidecar).exists() {
+                std::fs::copy(from_sidecar, format!("{}{}", to, suffix))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn ensure_absent(store_path: &str, store_name: &str) -> Result<()> {
+        if Database::exists(store_path, store_name) {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("store '{}' already exists", store_name),
+            )));
+        }
+        Ok(())
     }
 
     /**
      * Iterates over the DB_DIR folder and prints all stores found
      */
-    pub fn print_stores(&self) {
-        let db_path = Path::new(&self.db_file_path).parent();
-        self.logger.debug(format!("reading stores in: {}", db_path.unwrap().display()));
-        let paths = std::fs::read_dir(db_path.unwrap()).unwrap();
+    pub fn print_stores(&self) -> Result<()> {
+        let db_path = Path::new(&self.db_file_path).parent().unwrap_or_else(|| Path::new("."));
+        self.logger.debug(format!("reading stores in: {}", db_path.display()));
+        let paths = std::fs::read_dir(db_path)?;
         for path in paths {
             self.logger.display(format!(
                 "Store Name: {}",
-                path.unwrap().path().file_name().unwrap_or_default().to_str().unwrap_or("Unknown")
+                path?.path().file_name().unwrap_or_default().to_str().unwrap_or("Unknown")
             ));
         }
+        Ok(())
     }
 
     /* pub fn print_store(&self) {
         self.logger.display("Printing all stores...".to_string());
-        for (key, value) in &self.db_data {
+        for (key, value) in &self.data {
             self.logger.display(format!(
                 "{}:{}",
                 key,
@@ -118,7 +1086,10 @@ impl Database {
      * returns a copy of the db data
      */
     pub fn get_stores(&self) -> HashMap<String, String> {
-        self.db_data.clone()
+        self.live_records()
+            .into_iter()
+            .map(|(key, record)| (key, record.value))
+            .collect()
     }
 
     /**
@@ -131,8 +1102,120 @@ impl Database {
     /**
      * function to format the key-pair into a string
      */
-    fn format_keypair(key: &str, value: &str) -> String {
-        format!("{}\t{}\n", key, value)
+    fn format_keypair(key: &str, record: &Record) -> String {
+        let mut attributes = vec![];
+        if let Some(expires) = record.expires {
+            attributes.push(format!("x={}", expires));
+        }
+        if let Some(size) = record.blob {
+            attributes.push(format!("b={}", size));
+        }
+        if let Some(modified) = record.modified {
+            attributes.push(format!("m={}", modified));
+        }
+        if attributes.is_empty() {
+            format!("{}\t{}\n", key, record.value)
+        } else {
+            format!("{}\t{}\t{}\n", key, record.value, attributes.join(","))
+        }
+    }
+
+    /**
+     * parses the comma separated attributes column of a record
+     */
+    fn parse_attributes(record: &mut Record, access: &mut Access, attributes: &str) {
+        for attribute in attributes.split(',') {
+            let mut chunks = attribute.splitn(2, '=');
+            match (chunks.next(), chunks.next()) {
+                (Some("x"), Some(value)) => record.expires = value.parse().ok(),
+                (Some("b"), Some(value)) => record.blob = value.parse().ok(),
+                (Some("m"), Some(value)) => record.modified = value.parse().ok(),
+                // how a cache entry was used, saved in the store file before the usage file existed
+                (Some("a"), Some(value)) => access.tick = value.parse().unwrap_or(0),
+                (Some("h"), Some(value)) => access.hits = value.parse().unwrap_or(0),
+                _ => {}
+            }
+        }
+    }
+
+    fn usage(&self) -> MutexGuard<'_, Usage> {
+        // the usage is only counters, still fine to use after a panic
+        self.usage.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/**
+ * Counts the bytes written to it, to size a flush without building it
+ */
+struct CountingWriter(u64);
+
+impl Write for CountingWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.0 += data.len() as u64;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/**
+ * Lock file held while a store file is written, removed when dropped
+ */
+pub(crate) struct StoreLock {
+    path: PathBuf,
+}
+
+impl StoreLock {
+    pub(crate) fn acquire(db_file_path: &str) -> Result<StoreLock> {
+        let path = PathBuf::from(format!("{}.lock", db_file_path));
+        // create_new fails if the file is there, which makes taking the lock atomic
+        for _ in 0..2 {
+            match std::fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(StoreLock { path }),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    if !StoreLock::is_stale(&path) {
+                        break;
+                    }
+                    let _ = std::fs::remove_file(&path);
+                }
+                Err(e) => return Err(Error::Io(e)),
+            }
+        }
+        Err(Error::Lock(format!(
+            "{} is being written by another process, remove {} if that's not the case",
+            db_file_path,
+            path.display()
+        )))
+    }
+
+    /**
+     * same as acquire, retrying until timeout while another process holds the lock
+     */
+    pub(crate) fn wait(db_file_path: &str, timeout: Duration) -> Result<StoreLock> {
+        let start = std::time::Instant::now();
+        loop {
+            match StoreLock::acquire(db_file_path) {
+                Err(Error::Lock(_)) if start.elapsed() < timeout => std::thread::sleep(LOCK_RETRY),
+                result => return result,
+            }
+        }
+    }
+
+    fn is_stale(path: &Path) -> bool {
+        std::fs::metadata(path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .map(|age| age > STALE_LOCK_AGE)
+            .unwrap_or(false)
+    }
+}
+
+impl Drop for StoreLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
     }
 }
 
@@ -143,7 +1226,171 @@ impl Database {
  */
 impl Drop for Database {
     fn drop(&mut self) {
-        //using underscore binding here to ignore the result
-        let _ = self.flush();
+        // nothing to persist if the store wasn't changed. reads of a cache only save its usage file
+        if !self.read_only && self.engine == Engine::File && !self.locked {
+            //using underscore binding here to ignore the result
+            if self.dirty {
+                let _ = self.flush();
+            } else if self.usage().changed {
+                let _ = self.save_usage();
+            }
+        }
+        if let Some(keypair) = &mut self.seal {
+            keypair.zeroize();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn header_round_trip() {
+        let dir = TempDir::new("header");
+        let store_path = dir.to_string();
+        {
+            let mut db = Database::open("test".to_string(), store_path.to_string(), false, Engine::File).unwrap();
+            db.set_meta("fingerprint", "abcd".to_string());
+            db.insert("key".to_string(), "value".to_string()).unwrap();
+        }
+        let db = Database::open("test".to_string(), store_path.to_string(), false, Engine::File).unwrap();
+        assert_eq!(db.format_version(), FORMAT_VERSION);
+        assert_eq!(db.get_meta("fingerprint"), Some(&"abcd".to_string()));
+        assert_eq!(db.get_stores().len(), 1);
+    }
+
+    #[test]
+    fn store_management() {
+        let dir = TempDir::new("management");
+        let store_path = dir.to_string();
+        {
+            let mut db = Database::open("a".to_string(), store_path.to_string(), false, Engine::File).unwrap();
+            db.insert("key".to_string(), "value".to_string()).unwrap();
+        }
+        Database::copy_store(&store_path, "a", "b").unwrap();
+        assert!(Database::copy_store(&store_path, "a", "b").is_err());
+        Database::rename_store(&store_path, "b", "c").unwrap();
+        assert!(!Database::exists(&store_path, "b"));
+        let info = Database::open("c".to_string(), store_path.to_string(), false, Engine::File)
+            .unwrap()
+            .info()
+            .unwrap();
+        assert_eq!(info.keys, 1);
+        Database::drop_store(&store_path, "c").unwrap();
+        assert!(!Database::exists(&store_path, "c"));
+    }
+
+    #[test]
+    fn expired_entries() {
+        let dir = TempDir::new("expired");
+        let store_path = dir.to_string();
+        {
+            let mut db = Database::open("test".to_string(), store_path.to_string(), false, Engine::File).unwrap();
+            db.insert("live".to_string(), "value".to_string()).unwrap();
+            db.insert("gone".to_string(), "value".to_string()).unwrap();
+            db.flush().unwrap();
+            db.expire("gone", now() - 1);
+            assert_eq!(db.read("gone".to_string()), "");
+            let stats = db.stats(|_| true).unwrap();
+            assert_eq!(stats.value_sizes.len(), 1);
+            assert_eq!(stats.expired, 1);
+            assert!(stats.compacted_size < stats.file_size);
+        }
+        // the expired entry was collected when the store was flushed
+        let db = Database::open("test".to_string(), store_path.to_string(), false, Engine::File).unwrap();
+        assert_eq!(db.stats(|_| true).unwrap().expired, 0);
+        assert_eq!(db.get_stores().len(), 1);
+    }
+
+    #[test]
+    fn typed_errors() {
+        let dir = TempDir::new("errors");
+        let store_path = dir.to_string();
+        std::fs::create_dir_all(&store_path).unwrap();
+        let db_file = Database::get_store_filename(&store_path, "test");
+        std::fs::write(&db_file, "#gui-kvstore\tversion=99\n").unwrap();
+        assert!(matches!(
+            Database::open("test".to_string(), store_path.to_string(), false, Engine::File),
+            Err(Error::Format(_))
+        ));
+        std::fs::write(&db_file, "key without value\n").unwrap();
+        assert!(matches!(
+            Database::open("test".to_string(), store_path.to_string(), false, Engine::File),
+            Err(Error::Corrupt(_))
+        ));
+        std::fs::write(&db_file, "").unwrap();
+        let mut db = Database::open("test".to_string(), store_path.to_string(), false, Engine::File).unwrap();
+        let lock = StoreLock::acquire(&db_file).unwrap();
+        assert!(matches!(db.flush(), Err(Error::Lock(_))));
+        drop(lock);
+        db.flush().unwrap();
+    }
+
+    #[test]
+    fn memory_engine() {
+        let dir = TempDir::new("memory");
+        let store_path = dir.to_string();
+        {
+            let mut db = Database::open("test".to_string(), store_path.to_string(), false, Engine::Memory).unwrap();
+            db.insert("key".to_string(), "value".to_string()).unwrap();
+            assert_eq!(db.read("key".to_string()), "value");
+        }
+        assert!(!Database::exists(&store_path, "test"));
+    }
+
+    #[test]
+    fn list_stores() {
+        let dir = TempDir::new("list");
+        let store_path = dir.to_string();
+        Database::open("b".to_string(), store_path.to_string(), false, Engine::File).unwrap();
+        Database::open("a".to_string(), store_path.to_string(), false, Engine::File).unwrap();
+        assert_eq!(Database::list_stores(&store_path).unwrap(), vec!["a", "b"]);
+        Database::drop_store(&store_path, "a").unwrap();
+        assert_eq!(Database::list_stores(&store_path).unwrap(), vec!["b"]);
+    }
+
+    #[test]
+    fn quotas_and_eviction() {
+        let dir = TempDir::new("quotas");
+        let store_path = dir.to_string();
+        let open = || Database::open("test".to_string(), store_path.to_string(), false, Engine::File).unwrap();
+        {
+            let mut db = open();
+            let limits = Limits {
+                max_keys: Some(2),
+                max_value_bytes: Some(8),
+                ..Default::default()
+            };
+            db.set_limits(limits).unwrap();
+            db.insert("a".to_string(), "value".to_string()).unwrap();
+            db.insert("b".to_string(), "value".to_string()).unwrap();
+            assert!(matches!(db.insert("c".to_string(), "value".to_string()), Err(Error::Quota(_))));
+            assert!(matches!(db.insert("a".to_string(), "too long value".to_string()), Err(Error::Quota(_))));
+            // overwriting a key doesn't add one
+            db.insert("a".to_string(), "other".to_string()).unwrap();
+            db.set_limits(Limits {
+                eviction: Some(limits::Eviction::Lru),
+                ..limits
+            })
+            .unwrap();
+        }
+        let file = Path::new(&store_path).join("test.db");
+        let written = std::fs::read(&file).unwrap();
+        {
+            // usage is only tracked once the store is a cache, so after b is read a is the least recently used
+            let db = open();
+            assert_eq!(db.read("b".to_string()), "value");
+        }
+        // reads are kept in the usage file, the store file isn't written for them
+        assert_eq!(std::fs::read(&file).unwrap(), written);
+        assert!(Path::new(&store_path).join("test.db.usage").exists());
+        let mut db = open();
+        assert!(db.limits().is_cache());
+        db.insert("c".to_string(), "value".to_string()).unwrap();
+        assert_eq!(db.read("a".to_string()), "");
+        assert_eq!(db.get_stores().len(), 2);
     }
 }
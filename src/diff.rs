@@ -0,0 +1,117 @@
+use std::cmp::Ordering;
+
+use crate::error::Result;
+use crate::merge;
+use crate::store::Store;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ChangeKind {
+    // only in the second store
+    Added,
+    // only in the first store
+    Removed,
+    // in both with different values
+    Changed,
+}
+
+/**
+ * A key that differs between two stores, with its decrypted values on each side
+ */
+#[derive(PartialEq, Debug)]
+pub struct Change {
+    pub key: String,
+    pub kind: ChangeKind,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/**
+ * Compares the decrypted values of two stores, going from a to b.
+ * Changes are sorted by key, keys with the same value are left out
+ */
+pub fn diff(a: &Store, b: &Store) -> Result<Vec<Change>> {
+    let old: Vec<(String, String)> = a.snapshot().into_iter().collect::<Result<_>>()?;
+    let new: Vec<(String, String)> = b.snapshot().into_iter().collect::<Result<_>>()?;
+    let (mut old, mut new) = (old.into_iter().peekable(), new.into_iter().peekable());
+    let mut changes = vec![];
+    // both sides are sorted by key, so they are walked together like in a merge sort
+    loop {
+        let order = match (old.peek(), new.peek()) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((old_key, _)), Some((new_key, _))) => old_key.cmp(new_key),
+        };
+        match order {
+            Ordering::Less => {
+                if let Some((key, value)) = old.next() {
+                    changes.push(Change {
+                        key,
+                        kind: ChangeKind::Removed,
+                        old: Some(value),
+                        new: None,
+                    });
+                }
+            }
+            Ordering::Greater => {
+                if let Some((key, value)) = new.next() {
+                    changes.push(Change {
+                        key,
+                        kind: ChangeKind::Added,
+                        old: None,
+                        new: Some(value),
+                    });
+                }
+            }
+            Ordering::Equal => {
+                if let (Some((key, old_value)), Some((_, new_value))) = (old.next(), new.next()) {
+                    if !merge::same_contents(b, a, &key, &old_value, &new_value)? {
+                        changes.push(Change {
+                            key,
+                            kind: ChangeKind::Changed,
+                            old: Some(old_value),
+                            new: Some(new_value),
+                        });
+                    }
+                }
+            }
+        }
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Engine;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn added_removed_changed() {
+        let data_dir = TempDir::new("diff");
+        let open = |name: &str| Store::open_with(&data_dir, name, Engine::Memory, false).unwrap();
+        let (mut staging, mut production) = (open("staging"), open("production"));
+        staging.set("removed", "value").unwrap();
+        staging.set("same", "value").unwrap();
+        production.set("same", "value").unwrap();
+        staging.set("token", "old").unwrap();
+        production.set("token", "new").unwrap();
+        production.set("added", "value").unwrap();
+        let changes = diff(&staging, &production).unwrap();
+        let summary: Vec<(&str, ChangeKind)> = changes
+            .iter()
+            .map(|change| (change.key.as_str(), change.kind))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("added", ChangeKind::Added),
+                ("removed", ChangeKind::Removed),
+                ("token", ChangeKind::Changed)
+            ]
+        );
+        assert_eq!(changes[2].old, Some("old".to_string()));
+        assert_eq!(changes[2].new, Some("new".to_string()));
+        assert!(diff(&staging, &staging).unwrap().is_empty());
+    }
+}
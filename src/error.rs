@@ -0,0 +1,105 @@
+use std::fmt;
+
+/**
+ * Errors returned by the Store API, the db and the crypto functions.
+ * Each category maps to the exit code the command line uses for it.
+ */
+#[derive(Debug)]
+pub enum Error {
+    // reading or writing a file failed
+    Io(std::io::Error),
+    // a store file or a value in it is damaged
+    Corrupt(String),
+    // a value couldn't be decrypted with the private key, or encryption failed
+    Crypto(String),
+    // the key file is needed but not there
+    KeyMissing(String),
+    // the key file exists but can't be parsed
+    InvalidKeyFile(String),
+    // another process is writing the store
+    Lock(String),
+    // the store was written in a format this version doesn't understand
+    Format(String),
+    // the config file or a setting is invalid
+    Config(String),
+    // a write would go over one of the limits of the store
+    Quota(String),
+    // the key file is protected and the passphrase is wrong or wasn't given
+    Passphrase(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// exit codes of the command line, documented in the README
+// not an error: --diff found differences, like diff does
+pub const EXIT_DIFFERENCES: i32 = 1;
+pub const EXIT_CONFIG: i32 = 2;
+pub const EXIT_IO: i32 = 3;
+pub const EXIT_CORRUPT: i32 = 4;
+pub const EXIT_CRYPTO: i32 = 5;
+pub const EXIT_KEY: i32 = 6;
+pub const EXIT_LOCK: i32 = 7;
+pub const EXIT_FORMAT: i32 = 8;
+pub const EXIT_QUOTA: i32 = 9;
+pub const EXIT_PASSPHRASE: i32 = 10;
+
+impl Error {
+    /**
+     * exit code of the command line for this error category
+     */
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(_) => EXIT_IO,
+            Error::Corrupt(_) => EXIT_CORRUPT,
+            Error::Crypto(_) => EXIT_CRYPTO,
+            Error::KeyMissing(_) | Error::InvalidKeyFile(_) => EXIT_KEY,
+            Error::Lock(_) => EXIT_LOCK,
+            Error::Format(_) => EXIT_FORMAT,
+            Error::Config(_) => EXIT_CONFIG,
+            Error::Quota(_) => EXIT_QUOTA,
+            Error::Passphrase(_) => EXIT_PASSPHRASE,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Corrupt(message) => write!(f, "corrupt store: {}", message),
+            Error::Crypto(message) => write!(f, "encryption error: {}", message),
+            Error::KeyMissing(message) => write!(f, "key missing: {}", message),
+            Error::InvalidKeyFile(message) => write!(f, "invalid key file: {}", message),
+            Error::Lock(message) => write!(f, "store locked: {}", message),
+            Error::Format(message) => write!(f, "unsupported format: {}", message),
+            Error::Config(message) => write!(f, "config error: {}", message),
+            Error::Quota(message) => write!(f, "quota exceeded: {}", message),
+            Error::Passphrase(message) => write!(f, "passphrase error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        // readers like BlobReader return our errors wrapped in an io error
+        if !e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return Error::Io(e);
+        }
+        let kind = e.kind();
+        match e.into_inner().map(|inner| inner.downcast::<Error>()) {
+            Some(Ok(inner)) => *inner,
+            // anything else stays the io error it was
+            Some(Err(inner)) => Error::Io(std::io::Error::new(kind, inner)),
+            None => Error::Io(kind.into()),
+        }
+    }
+}
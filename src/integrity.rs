@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use zeroize::Zeroize;
+
+use crate::crypto;
+use crate::db::Database;
+use crate::error::{Error, Result};
+use crate::keys::KeyPair;
+
+// header field with the MAC of the whole store, keyed by its data key
+pub static META_MAC: &str = "mac";
+// header field counting the flushes of a store, so an older copy put back can be told apart
+pub static META_GENERATION: &str = "generation";
+// header field with a random id, so a new store with the name of a dropped one isn't taken for it
+pub static META_STORE_ID: &str = "store_id";
+// file of the data directory with the last generation seen of every store
+pub static SEEN_FILE: &str = "seen-stores";
+
+/**
+ * Checks that a store file is the one this key wrote last. Values are encrypted one by one,
+ * so on its own nothing notices records removed, moved around, or a whole store replaced by
+ * an older copy. On every flush the store gets a MAC over its header and every record, keyed
+ * by its data key, and its generation goes up. The last generation seen of every store is kept
+ * in the data directory, outside of the store files, and opening one that went back is an error
+ */
+#[derive(Clone)]
+pub(crate) struct Integrity {
+    mac_key: [u8; 32],
+    // where generations are remembered, None for files opened read-only, like backups
+    seen: Option<PathBuf>,
+}
+
+impl Integrity {
+    pub fn new(data_key: &KeyPair, data_dir: Option<&Path>) -> Integrity {
+        Integrity {
+            mac_key: crypto::keyed_hash(&data_key.1, b"gui-kvstore store mac"),
+            seen: data_dir.map(|data_dir| data_dir.join(SEEN_FILE)),
+        }
+    }
+
+    /**
+     * the same, with the MAC keyed by another data key
+     */
+    pub fn rekeyed(&self, data_key: &KeyPair) -> Integrity {
+        Integrity {
+            mac_key: crypto::keyed_hash(&data_key.1, b"gui-kvstore store mac"),
+            seen: self.seen.clone(),
+        }
+    }
+
+    /**
+     * the MAC of a store, given the hash of its contents without the MAC
+     */
+    pub fn mac(&self, content_hash: &[u8; 32]) -> String {
+        crypto::encode_hex(&crypto::keyed_hash(&self.mac_key, content_hash))
+    }
+
+    /**
+     * if the generations of stores are remembered, false for files opened read-only
+     */
+    pub fn remembers(&self) -> bool {
+        self.seen.is_some()
+    }
+
+    /**
+     * checks the MAC of db and that it's the same store, not older than the last time it was seen.
+     * stores written before MACs existed pass the first time they are seen, see Database::protect
+     */
+    pub fn verify(&self, db: &Database) -> Result<()> {
+        if let Some(mac) = db.get_meta(META_MAC) {
+            let expected = self.mac(&db.content_hash());
+            if *mac != expected {
+                return Err(Error::Corrupt(format!(
+                    "store '{}' was changed outside of gui-kvstore: records were modified, removed or reordered",
+                    db.name
+                )));
+            }
+        }
+        let seen = match &self.seen {
+            Some(path) => read_seen(path)?,
+            None => return Ok(()),
+        };
+        let (id, last) = match (seen.get(&db.name), db.get_meta(META_MAC), db.get_meta(META_STORE_ID)) {
+            (Some(entry), _, _) => entry,
+            // only stores that never had a MAC have no id either
+            (None, None, Some(_)) => {
+                return Err(Error::Corrupt(format!("store '{}' lost its MAC", db.name)));
+            }
+            (None, _, _) => return Ok(()),
+        };
+        match (db.get_meta(META_MAC), db.get_meta(META_STORE_ID)) {
+            (Some(_), Some(store_id)) if store_id == id => {
+                let generation = generation(db);
+                if generation < *last {
+                    return Err(Error::Corrupt(format!(
+                        "store '{}' was rolled back to generation {}, generation {} was seen before",
+                        db.name, generation, last
+                    )));
+                }
+                Ok(())
+            }
+            // dropping or renaming a store forgets it, so this is another store put in its place
+            (Some(_), Some(store_id)) => Err(Error::Corrupt(format!(
+                "store '{}' was replaced by another store: its id is {}, the one seen before is {}",
+                db.name, store_id, id
+            ))),
+            _ => Err(Error::Corrupt(format!(
+                "store '{}' lost its MAC, it had one at generation {}",
+                db.name, last
+            ))),
+        }
+    }
+
+    /**
+     * remembers the generation of db, once it's flushed or checked
+     */
+    pub fn saw(&self, db: &Database) -> Result<()> {
+        let (path, id) = match (&self.seen, db.get_meta(META_STORE_ID)) {
+            (Some(path), Some(id)) => (path, id),
+            _ => return Ok(()),
+        };
+        let mut seen = read_seen(path)?;
+        let generation = generation(db);
+        if seen.get(&db.name) == Some(&(id.to_string(), generation)) {
+            return Ok(());
+        }
+        seen.insert(db.name.to_string(), (id.to_string(), generation));
+        write_seen(path, &seen)
+    }
+}
+
+impl Drop for Integrity {
+    fn drop(&mut self) {
+        self.mac_key.zeroize();
+    }
+}
+
+/**
+ * how many times db was flushed since it has a MAC
+ */
+pub(crate) fn generation(db: &Database) -> u64 {
+    db.get_meta(META_GENERATION)
+        .and_then(|generation| generation.parse().ok())
+        .unwrap_or(0)
+}
+
+/**
+ * forgets the generation seen of a store, after it was dropped or renamed
+ */
+pub fn forget(data_dir: &Path, name: &str) -> Result<()> {
+    let path = data_dir.join(SEEN_FILE);
+    let mut seen = read_seen(&path)?;
+    if seen.remove(name).is_some() {
+        write_seen(&path, &seen)?;
+    }
+    Ok(())
+}
+
+fn read_seen(path: &Path) -> Result<HashMap<String, (String, u64)>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+    contents
+        .lines()
+        .map(|line| {
+            let mut fields = line.split('\t');
+            match (fields.next(), fields.next(), fields.next().and_then(|g| g.parse().ok())) {
+                (Some(name), Some(id), Some(generation)) => Ok((name.to_string(), (id.to_string(), generation))),
+                _ => Err(Error::Corrupt(format!("invalid line in {}: {}", path.display(), line))),
+            }
+        })
+        .collect()
+}
+
+fn write_seen(path: &Path, seen: &HashMap<String, (String, u64)>) -> Result<()> {
+    let mut lines: Vec<String> = seen
+        .iter()
+        .map(|(name, (id, generation))| format!("{}\t{}\t{}\n", name, id, generation))
+        .collect();
+    lines.sort();
+    // writing next to it and renaming, like store files
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, lines.concat())?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Engine;
+    use crate::store::Store;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn tampering_and_rollback() {
+        let data_dir = TempDir::new("integrity");
+        let file = data_dir.join("data").join("checked.db");
+        let open = || Store::open_with(&data_dir, "checked", Engine::File, false);
+        let mut store = open().unwrap();
+        store.set("a", "one").unwrap();
+        store.set("b", "two").unwrap();
+        store.flush().unwrap();
+        drop(store);
+        let old = std::fs::read_to_string(&file).unwrap();
+
+        // records swapped, or removed, don't match the MAC anymore
+        let lines: Vec<&str> = old.lines().collect();
+        let (a, b) = (lines[1].split_once('\t').unwrap(), lines[2].split_once('\t').unwrap());
+        let swapped = format!("{}\n{}\t{}\n{}\t{}\n", lines[0], a.0, b.1, b.0, a.1);
+        std::fs::write(&file, swapped).unwrap();
+        assert!(matches!(open(), Err(Error::Corrupt(_))));
+        std::fs::write(&file, format!("{}\n{}\n", lines[0], lines[1])).unwrap();
+        assert!(matches!(open(), Err(Error::Corrupt(_))));
+        let stripped: Vec<&str> = lines[0].split('\t').filter(|f| !f.starts_with("mac=")).collect();
+        std::fs::write(&file, format!("{}\n{}\n{}\n", stripped.join("\t"), lines[1], lines[2])).unwrap();
+        assert!(matches!(open(), Err(Error::Corrupt(_))));
+
+        // putting back an older copy of the store is noticed, but it can still be opened as a backup
+        std::fs::write(&file, &old).unwrap();
+        let mut store = open().unwrap();
+        assert_eq!(store.get("b").unwrap(), Some("two".to_string()));
+        store.set("c", "three").unwrap();
+        store.flush().unwrap();
+        drop(store);
+        std::fs::write(&file, &old).unwrap();
+        assert!(matches!(open(), Err(Error::Corrupt(_))));
+        let keypair = crate::keys::load(&crate::keys::key_path(&data_dir)).unwrap();
+        assert!(Store::open_file(&file, false, &keypair).is_ok());
+
+        // forgetting it doesn't make a store that had a MAC pass without one
+        forget(&data_dir, "checked").unwrap();
+        let stripped: Vec<&str> = old.lines().next().unwrap().split('\t').filter(|f| !f.starts_with("mac=")).collect();
+        let rest: Vec<&str> = old.lines().skip(1).collect();
+        std::fs::write(&file, format!("{}\n{}\n", stripped.join("\t"), rest.join("\n"))).unwrap();
+        assert!(matches!(open(), Err(Error::Corrupt(_))));
+
+        // a store dropped and created again starts over
+        std::fs::remove_file(&file).unwrap();
+        forget(&data_dir, "checked").unwrap();
+        let mut store = open().unwrap();
+        assert_eq!(store.len(), 0);
+        store.set("d", "four").unwrap();
+        drop(store);
+        // and the store it replaced can't be put back in its place, even with a valid MAC
+        std::fs::write(&file, &old).unwrap();
+        assert!(matches!(open(), Err(Error::Corrupt(_))));
+    }
+}
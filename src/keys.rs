@@ -0,0 +1,553 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use crate::crypto;
+use crate::db::Database;
+use crate::error::{Error, Result};
+use crate::passphrase::PassphraseSource;
+use crate::store::Store;
+
+// name of the key file inside the data directory
+pub static KEY_FILE: &str = "kvstore.key";
+// first line of a key file protected by a passphrase
+static KEY_HEADER: &str = "#gui-kvstore-key";
+// first and last lines of an exported key in armored text
+static ARMOR_BEGIN: &str = "-----BEGIN GUI-KVSTORE KEY-----";
+static ARMOR_END: &str = "-----END GUI-KVSTORE KEY-----";
+// the most memory the scrypt parameters of a key file can ask for, 1 GiB
+const MAX_KDF_MEMORY: u64 = 1 << 30;
+
+/**
+ * Cost of the scrypt derivation of the key protecting the private key:
+ * 2^log_n iterations using 128 * r * 2^log_n bytes of memory, 32 MiB by default
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct KdfParams {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> KdfParams {
+        KdfParams { log_n: 15, r: 8, p: 1 }
+    }
+}
+
+impl KdfParams {
+    /**
+     * If scrypt takes these parameters, within a cost no key file written by gui-kvstore goes past.
+     * They're read from the key file, so a corrupted or crafted one can't crash or exhaust the memory
+     */
+    pub fn is_valid(&self) -> bool {
+        (1..=20).contains(&self.log_n)
+            && (1..=32).contains(&self.r)
+            && (1..=16).contains(&self.p)
+            // scrypt needs 2^log_n < 2^(16 * r)
+            && u32::from(self.log_n) < self.r * 16
+            && (128 * u64::from(self.r)) << self.log_n <= MAX_KDF_MEMORY
+    }
+}
+
+/**
+ * (public, private) X25519 key pair used to encrypt the values of every store.
+ * It isn't Copy, so no copy of the private key is left behind unnoticed, and it's zeroed when dropped
+ */
+#[derive(Clone, Default, PartialEq)]
+pub struct KeyPair(pub [u8; 32], pub [u8; 32]);
+
+impl From<([u8; 32], [u8; 32])> for KeyPair {
+    fn from((public, private): ([u8; 32], [u8; 32])) -> KeyPair {
+        KeyPair(public, private)
+    }
+}
+
+impl Zeroize for KeyPair {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+        self.1.zeroize();
+    }
+}
+
+impl Drop for KeyPair {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for KeyPair {}
+
+impl fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KeyPair({}, ..)", crypto::fingerprint(&self.0))
+    }
+}
+
+/**
+ * The master key as stores use it: its public key wraps their data keys, and its private key
+ * unwraps them. A KeyPair holds the private key itself, the key agent keeps it in another process
+ */
+pub trait MasterKey {
+    fn public_key(&self) -> [u8; 32];
+
+    /**
+     * decrypts a data key wrapped with crypto::encrypt for the public key
+     */
+    fn unwrap_key(&self, wrapped: &[u8]) -> Result<Zeroizing<Vec<u8>>>;
+
+    /**
+     * the key pair itself, for stores from before data keys, which are encrypted with it.
+     * None when the private key is held somewhere else
+     */
+    fn keypair(&self) -> Option<&KeyPair>;
+}
+
+impl MasterKey for KeyPair {
+    fn public_key(&self) -> [u8; 32] {
+        self.0
+    }
+
+    fn unwrap_key(&self, wrapped: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        Ok(Zeroizing::new(crypto::decrypt(&self.1, wrapped)?))
+    }
+
+    fn keypair(&self) -> Option<&KeyPair> {
+        Some(self)
+    }
+}
+
+/**
+ * path of the key file inside data_dir
+ */
+pub fn key_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(KEY_FILE)
+}
+
+/**
+ * reads the key pair from data_dir, generating and saving a new one the first time.
+ * if there are stores already, a new key couldn't decrypt them, so that's a KeyMissing error.
+ * passphrases come from the environment
+ */
+pub fn load_or_create(data_dir: &Path) -> Result<KeyPair> {
+    load_or_create_with(data_dir, PassphraseSource::Environment)
+}
+
+/**
+ * same as load_or_create, choosing where the passphrase comes from.
+ * a new key is protected if a passphrase is given for it
+ */
+pub fn load_or_create_with(data_dir: &Path, source: PassphraseSource) -> Result<KeyPair> {
+    load_or_create_unlocked(data_dir, source).map(|(keypair, _)| keypair)
+}
+
+/**
+ * same as load_or_create_with, also returning the passphrase that unlocked or protects the key.
+ * None when the key isn't protected
+ */
+pub fn load_or_create_unlocked(data_dir: &Path, source: PassphraseSource) -> Result<(KeyPair, Option<String>)> {
+    let path = key_path(data_dir);
+    if path.exists() {
+        return unlock(&path, source);
+    }
+    let stores = Database::list_stores(&Store::store_path(data_dir)).unwrap_or_default();
+    if !stores.is_empty() {
+        return Err(Error::KeyMissing(format!(
+            "{} not found, but {} has {} stores encrypted with it",
+            path.display(),
+            data_dir.display(),
+            stores.len()
+        )));
+    }
+    let keypair = KeyPair::from(crypto::generate_key_pair()?);
+    std::fs::create_dir_all(data_dir)?;
+    let passphrase = source.new_key()?.filter(|passphrase| !passphrase.is_empty());
+    match &passphrase {
+        Some(passphrase) => save_protected(&path, &keypair, passphrase, KdfParams::default())?,
+        None => save(&path, &keypair)?,
+    }
+    Ok((keypair, passphrase))
+}
+
+/**
+ * reads a key file, taking the passphrase from the environment if it's protected
+ */
+pub fn load(path: &Path) -> Result<KeyPair> {
+    load_with(path, PassphraseSource::Environment)
+}
+
+/**
+ * reads a key file, either protected by a passphrase or stored as hex `public\tprivate`
+ */
+pub fn load_with(path: &Path, source: PassphraseSource) -> Result<KeyPair> {
+    unlock(path, source).map(|(keypair, _)| keypair)
+}
+
+/**
+ * same as load_with, also returning the passphrase that unlocked the key, None when it isn't protected
+ */
+pub fn unlock(path: &Path, source: PassphraseSource) -> Result<(KeyPair, Option<String>)> {
+    let contents = std::fs::read_to_string(path)?;
+    if !contents.starts_with(KEY_HEADER) {
+        let mut keys_split = contents.trim().split('\t');
+        let public = decode_key(keys_split.next(), "public")?;
+        let private = decode_key(keys_split.next(), "private")?;
+        return Ok((KeyPair(public, private), None));
+    }
+    let passphrase = source.current(&format!("Passphrase for {}: ", path.display()))?.ok_or_else(|| {
+        Error::Passphrase(format!(
+            "{} is protected by a passphrase, set {} or {}",
+            path.display(),
+            crate::passphrase::ENV_PASSPHRASE,
+            crate::passphrase::ENV_PASSPHRASE_FD
+        ))
+    })?;
+    let keypair = load_with_passphrase(path, &passphrase)?;
+    Ok((keypair, Some(passphrase)))
+}
+
+/**
+ * reads the public key of a key file, without asking for the passphrase when it's protected
+ */
+pub fn public_key(path: &Path) -> Result<[u8; 32]> {
+    let contents = std::fs::read_to_string(path)?;
+    if !contents.starts_with(KEY_HEADER) {
+        return decode_key(contents.trim().split('\t').next(), "public");
+    }
+    decode_key(
+        contents.trim().split('\t').find_map(|field| field.strip_prefix("public=")),
+        "public",
+    )
+}
+
+/**
+ * reads a key file protected by a passphrase
+ */
+pub fn load_with_passphrase(path: &Path, passphrase: &str) -> Result<KeyPair> {
+    let contents = std::fs::read_to_string(path)?;
+    open_protected(&contents, passphrase, &path.display().to_string())
+}
+
+/**
+ * decrypts the private key of a protected key file, read from origin
+ */
+fn open_protected(contents: &str, passphrase: &str, origin: &str) -> Result<KeyPair> {
+    if !contents.starts_with(KEY_HEADER) {
+        return Err(Error::InvalidKeyFile(format!("{} is not protected by a passphrase", origin)));
+    }
+    let fields: HashMap<&str, &str> = contents
+        .trim()
+        .split('\t')
+        .skip(1)
+        .filter_map(|field| field.split_once('='))
+        .collect();
+    let field = |name: &str| -> Result<&str> {
+        fields
+            .get(name)
+            .copied()
+            .ok_or_else(|| Error::InvalidKeyFile(format!("missing {} in {}", name, origin)))
+    };
+    let number = |name: &str| -> Result<u32> {
+        field(name)?
+            .parse()
+            .map_err(|_| Error::InvalidKeyFile(format!("invalid {} in {}", name, origin)))
+    };
+    if field("kdf")? != "scrypt" {
+        return Err(Error::InvalidKeyFile(format!("unknown kdf in {}", origin)));
+    }
+    let params = KdfParams {
+        log_n: number("log_n")?
+            .try_into()
+            .map_err(|_| Error::InvalidKeyFile(format!("invalid log_n in {}", origin)))?,
+        r: number("r")?,
+        p: number("p")?,
+    };
+    if !params.is_valid() {
+        return Err(Error::InvalidKeyFile(format!(
+            "scrypt parameters out of range in {}: log_n={} r={} p={}",
+            origin, params.log_n, params.r, params.p
+        )));
+    }
+    let public = decode_key(Some(field("public")?), "public")?;
+    let salt = decode_bytes(field("salt")?, "salt")?;
+    // key files from before the aead field were sealed with chacha20-poly1305
+    let aead_name = fields.get("aead").copied().unwrap_or("chacha20-poly1305");
+    let aead = crypto::aead_from_name(aead_name)
+        .ok_or_else(|| Error::InvalidKeyFile(format!("unknown aead '{}' in {}", aead_name, origin)))?;
+    let nonce = decode_bytes(field("nonce")?, "nonce")?;
+    let sealed = decode_bytes(field("private")?, "private key")?;
+    let wrapping_key = Zeroizing::new(crypto::derive_key(passphrase, &salt, params.log_n, params.r, params.p));
+    // the public key is authenticated too, so it can't be swapped for another one
+    let private = Zeroizing::new(
+        aead.open(&wrapping_key, &nonce, &public, &sealed)
+            .map_err(|_| Error::Passphrase(format!("wrong passphrase for {}", origin)))?,
+    );
+    let private: [u8; 32] = private
+        .as_slice()
+        .try_into()
+        .map_err(|_| Error::InvalidKeyFile("incorrect private key".to_string()))?;
+    Ok(KeyPair(public, private))
+}
+
+/**
+ * writes a key pair as hex `public\tprivate`, readable by anyone who can read the file
+ */
+pub fn save(path: &Path, keypair: &KeyPair) -> Result<()> {
+    let keypair_content = format!(
+        "{}\t{}",
+        crypto::encode_hex(&keypair.0),
+        crypto::encode_hex(&keypair.1)
+    );
+    write_key_file(path, &keypair_content)
+}
+
+/**
+ * writes a key pair with the private key encrypted by a key derived from passphrase
+ */
+pub fn save_protected(path: &Path, keypair: &KeyPair, passphrase: &str, params: KdfParams) -> Result<()> {
+    write_key_file(path, &protect(keypair, passphrase, params)?)
+}
+
+/**
+ * the contents of a key file with the private key encrypted by a key derived from passphrase
+ */
+fn protect(keypair: &KeyPair, passphrase: &str, params: KdfParams) -> Result<String> {
+    if !params.is_valid() {
+        return Err(Error::Config(format!(
+            "scrypt parameters out of range: log_n={} r={} p={}",
+            params.log_n, params.r, params.p
+        )));
+    }
+    let salt: [u8; 16] = crypto::random_bytes()?;
+    let aead = crypto::current_aead();
+    let nonce = crypto::random_vec(aead.nonce_len())?;
+    let wrapping_key = Zeroizing::new(crypto::derive_key(passphrase, &salt, params.log_n, params.r, params.p));
+    let sealed = aead.seal(&wrapping_key, &nonce, &keypair.0, &keypair.1)?;
+    let contents = format!(
+        "{}\tversion=1\tkdf=scrypt\tlog_n={}\tr={}\tp={}\taead={}\tsalt={}\tnonce={}\tpublic={}\tprivate={}\n",
+        KEY_HEADER,
+        params.log_n,
+        params.r,
+        params.p,
+        aead.name(),
+        crypto::encode_hex(&salt),
+        crypto::encode_hex(&nonce),
+        crypto::encode_hex(&keypair.0),
+        crypto::encode_hex(&sealed)
+    );
+    Ok(contents)
+}
+
+/**
+ * a copy of keypair to move it to another machine, always protected by passphrase.
+ * armored, it's a block of text that survives being pasted in an email or printed:
+ * the key file in hex lines, with a checksum to catch mistakes when it's typed back
+ */
+pub fn export(keypair: &KeyPair, passphrase: &str, params: KdfParams, armor: bool) -> Result<String> {
+    if passphrase.is_empty() {
+        return Err(Error::Passphrase("exported keys need a passphrase".to_string()));
+    }
+    let contents = Zeroizing::new(protect(keypair, passphrase, params)?);
+    if !armor {
+        return Ok(contents.to_string());
+    }
+    let encoded = Zeroizing::new(crypto::encode_hex(contents.trim_end().as_bytes()));
+    let mut armored = format!("{}\nFingerprint: {}\n\n", ARMOR_BEGIN, crypto::fingerprint(&keypair.0));
+    for line in encoded.as_bytes().chunks(64) {
+        armored.push_str(&String::from_utf8_lossy(line));
+        armored.push('\n');
+    }
+    armored.push_str(&format!("Checksum: {}\n{}\n", crypto::checksum(encoded.as_bytes()), ARMOR_END));
+    Ok(armored)
+}
+
+/**
+ * reads a key made by export, armored or not
+ */
+pub fn import(contents: &str, passphrase: &str) -> Result<KeyPair> {
+    let contents = contents.trim();
+    if !contents.starts_with(ARMOR_BEGIN) {
+        return open_protected(contents, passphrase, "the imported key");
+    }
+    let body = contents
+        .strip_suffix(ARMOR_END)
+        .ok_or_else(|| Error::InvalidKeyFile(format!("the armored key doesn't end with {}", ARMOR_END)))?;
+    let (mut encoded, mut expected) = (String::new(), None);
+    for line in body.lines().skip(1).map(|line| line.trim()) {
+        if let Some(value) = line.strip_prefix("Checksum:") {
+            expected = Some(value.trim().to_string());
+        } else if !line.is_empty() && !line.contains(':') {
+            encoded.push_str(line);
+        }
+    }
+    let expected = expected.ok_or_else(|| Error::InvalidKeyFile("the armored key has no checksum".to_string()))?;
+    if crypto::checksum(encoded.as_bytes()) != expected.to_lowercase() {
+        return Err(Error::InvalidKeyFile(
+            "the checksum of the armored key doesn't match, it was changed or mistyped".to_string(),
+        ));
+    }
+    let decoded = crypto::decode_hex(&encoded)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or_else(|| Error::InvalidKeyFile("the armored key is not hex encoded".to_string()))?;
+    open_protected(&decoded, passphrase, "the imported key")
+}
+
+/**
+ * if the key file is protected by a passphrase
+ */
+pub fn is_protected(path: &Path) -> Result<bool> {
+    Ok(std::fs::read_to_string(path)?.starts_with(KEY_HEADER))
+}
+
+/**
+ * replaces the key file, only readable by its owner. the old one stays until the new one is complete
+ */
+pub fn write_key_file(path: &Path, contents: &str) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&tmp_path)?;
+    std::io::Write::write_all(&mut file, contents.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn decode_bytes(hex: &str, name: &str) -> Result<Vec<u8>> {
+    crypto::decode_hex(hex).map_err(|_| Error::InvalidKeyFile(format!("incorrect {}", name)))
+}
+
+fn decode_key(hex: Option<&str>, name: &str) -> Result<[u8; 32]> {
+    let hex = hex.ok_or_else(|| Error::InvalidKeyFile(format!("missing {} key", name)))?;
+    crypto::decode_hex(hex)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Error::InvalidKeyFile(format!("incorrect {} key", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn save_and_load() {
+        let data_dir = TempDir::new("keys");
+        let created = load_or_create(&data_dir).unwrap();
+        let loaded = load_or_create(&data_dir).unwrap();
+        assert_eq!(created, loaded);
+        std::fs::write(key_path(&data_dir), "not a key").unwrap();
+        assert!(matches!(load(&key_path(&data_dir)), Err(Error::InvalidKeyFile(_))));
+        // losing the key when there are stores is an error instead of a new key
+        std::fs::remove_file(key_path(&data_dir)).unwrap();
+        std::fs::create_dir_all(data_dir.join("data")).unwrap();
+        std::fs::write(data_dir.join("data").join("default.db"), "").unwrap();
+        assert!(matches!(load_or_create(&data_dir), Err(Error::KeyMissing(_))));
+    }
+
+    #[test]
+    fn passphrase_protected() {
+        let data_dir = TempDir::new("keys-protected");
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let path = key_path(&data_dir);
+        let keypair = KeyPair::from(crypto::generate_key_pair().unwrap());
+        // cheap parameters, the tests don't need to resist brute force
+        let params = KdfParams { log_n: 4, r: 8, p: 1 };
+        save_protected(&path, &keypair, "passphrase", params).unwrap();
+        assert!(is_protected(&path).unwrap());
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains(&crypto::encode_hex(&keypair.1)));
+        // the environment is the only source here. no other test reads these variables
+        use crate::passphrase::{ENV_PASSPHRASE, ENV_PASSPHRASE_FD};
+        std::env::remove_var(ENV_PASSPHRASE);
+        std::env::remove_var(ENV_PASSPHRASE_FD);
+        assert!(matches!(load(&path), Err(Error::Passphrase(_))));
+        std::env::set_var(ENV_PASSPHRASE, "passphrase");
+        assert_eq!(load(&path).unwrap(), keypair);
+        std::env::remove_var(ENV_PASSPHRASE);
+        // a descriptor is read a line at a time and left open
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let written = b"passphrase\nwrong\n";
+        assert_eq!(unsafe { libc::write(fds[1], written.as_ptr().cast(), written.len()) }, written.len() as isize);
+        std::env::set_var(ENV_PASSPHRASE_FD, fds[0].to_string());
+        assert_eq!(load(&path).unwrap(), keypair);
+        assert!(matches!(load(&path), Err(Error::Passphrase(_))));
+        unsafe {
+            assert_ne!(libc::fcntl(fds[0], libc::F_GETFD), -1);
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+        std::env::set_var(ENV_PASSPHRASE_FD, "-1");
+        assert!(matches!(load(&path), Err(Error::Config(_))));
+        std::env::remove_var(ENV_PASSPHRASE_FD);
+        assert_eq!(load_with_passphrase(&path, "passphrase").unwrap(), keypair);
+        assert!(matches!(load_with_passphrase(&path, "wrong"), Err(Error::Passphrase(_))));
+        // changing the passphrase keeps the key pair, so nothing has to be re-encrypted
+        save_protected(&path, &keypair, "other", params).unwrap();
+        assert_eq!(load_with_passphrase(&path, "other").unwrap(), keypair);
+        save(&path, &keypair).unwrap();
+        assert_eq!(load(&path).unwrap(), keypair);
+        assert!(!is_protected(&path).unwrap());
+    }
+
+    #[test]
+    fn corrupted_kdf_params() {
+        let data_dir = TempDir::new("keys-kdf");
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let path = key_path(&data_dir);
+        let keypair = KeyPair::from(crypto::generate_key_pair().unwrap());
+        save_protected(&path, &keypair, "passphrase", KdfParams { log_n: 4, r: 8, p: 1 }).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        // parameters scrypt would panic on, or that would take too much memory, are an invalid key file
+        for params in ["log_n=0", "log_n=64", "log_n=300", "log_n=21", "r=0", "r=100000", "p=0", "p=100000"] {
+            let (name, _) = params.split_once('=').unwrap();
+            let field = format!("\t{}=", name);
+            let start = contents.find(&field).unwrap() + 1;
+            let end = start + contents[start..].find('\t').unwrap();
+            std::fs::write(&path, format!("{}{}{}", &contents[..start], params, &contents[end..])).unwrap();
+            assert!(
+                matches!(load_with_passphrase(&path, "passphrase"), Err(Error::InvalidKeyFile(_))),
+                "{}",
+                params
+            );
+        }
+        // each one within range, but together over the memory cap
+        let over_the_cap = contents.replace("\tlog_n=4\tr=8\t", "\tlog_n=20\tr=16\t");
+        assert_ne!(over_the_cap, contents);
+        assert!(matches!(import(&over_the_cap, "passphrase"), Err(Error::InvalidKeyFile(_))));
+        assert!(matches!(
+            save_protected(&path, &keypair, "passphrase", KdfParams { log_n: 0, r: 8, p: 1 }),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn export_and_import() {
+        let keypair = KeyPair::from(crypto::generate_key_pair().unwrap());
+        let params = KdfParams { log_n: 4, r: 8, p: 1 };
+        assert!(matches!(export(&keypair, "", params, false), Err(Error::Passphrase(_))));
+        let exported = export(&keypair, "passphrase", params, false).unwrap();
+        assert_eq!(import(&exported, "passphrase").unwrap(), keypair);
+        assert!(matches!(import(&exported, "wrong"), Err(Error::Passphrase(_))));
+
+        // the armored text is printable, and a mistyped character is caught by its checksum
+        let armored = export(&keypair, "passphrase", params, true).unwrap();
+        assert!(armored.starts_with(ARMOR_BEGIN));
+        assert!(armored.contains(&crypto::fingerprint(&keypair.0)));
+        assert!(armored.lines().all(|line| line.len() <= 64 && line.is_ascii()));
+        assert_eq!(import(&format!("\n  {}\n", armored), "passphrase").unwrap(), keypair);
+        let line = armored.lines().nth(3).unwrap();
+        let typo = line.replacen(&line[..1], if line.starts_with('0') { "1" } else { "0" }, 1);
+        assert!(matches!(
+            import(&armored.replacen(line, &typo, 1), "passphrase"),
+            Err(Error::InvalidKeyFile(_))
+        ));
+        assert!(matches!(import("not a key", "passphrase"), Err(Error::InvalidKeyFile(_))));
+    }
+}
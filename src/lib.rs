@@ -0,0 +1,59 @@
+//! gui-kvstore: a simple key-value store with encrypted values.
+//!
+//! Values are encrypted with an X25519 key pair kept in the data directory,
+//! each store is a file in the `data` folder of that directory.
+//!
+//! ```
+//! use gui_kvstore::Store;
+//!
+//! let data_dir = std::env::temp_dir().join("gui-kvstore-doc");
+//! let mut store = Store::open(&data_dir, "default")?;
+//! store.set("api_token", "s3cr3t")?;
+//! assert_eq!(store.get("api_token")?, Some("s3cr3t".to_string()));
+//! for pair in store.iter() {
+//!     let (key, value) = pair?;
+//!     println!("{}={}", key, value);
+//! }
+//! store.delete("api_token");
+//! store.flush()?;
+//! # drop(store);
+//! # std::fs::remove_dir_all(data_dir).unwrap();
+//! # Ok::<(), gui_kvstore::Error>(())
+//! ```
+//!
+//! To share a store between threads, wrap it in a [`SharedStore`]: clones are cheap
+//! handles to the same store, reads run concurrently and writes are serialized.
+//! With the `async` feature it also has `get_async`, `set_async`, `delete_async` and
+//! `flush_async`, which run on a separate thread instead of blocking the executor.
+pub mod agent;
+pub mod archive;
+pub mod audit;
+pub mod blind;
+pub mod blob;
+pub mod config;
+pub mod crypto;
+pub mod db;
+pub mod diff;
+pub mod error;
+pub mod integrity;
+pub mod keys;
+pub mod limits;
+pub mod log;
+pub mod merge;
+pub mod mvcc;
+pub mod passphrase;
+pub mod recipients;
+pub mod rotate;
+pub mod sealed;
+pub mod secret;
+pub mod shamir;
+pub mod shared;
+pub mod store;
+#[cfg(test)]
+mod testing;
+
+pub use db::{Database, Engine};
+pub use error::{Error, Result};
+pub use limits::{Eviction, Limits};
+pub use shared::SharedStore;
+pub use store::{Store, StoreSnapshot};
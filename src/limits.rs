@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+
+use crate::db::Record;
+use crate::error::{Error, Result};
+
+// header fields holding the limits of a store
+pub static META_MAX_KEYS: &str = "max_keys";
+pub static META_MAX_VALUE_BYTES: &str = "max_value_bytes";
+pub static META_MAX_BYTES: &str = "max_bytes";
+pub static META_EVICTION: &str = "eviction";
+
+/**
+ * Which entries a cache store drops first when a write would go over its limits
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Eviction {
+    // least recently read or written
+    Lru,
+    // least often read, ties broken by recency
+    Lfu,
+    // entries closest to expiring, then the ones without a ttl by recency
+    TtlFirst,
+}
+
+impl Eviction {
+    pub fn from_name(name: &str) -> Option<Eviction> {
+        match name {
+            "lru" => Some(Eviction::Lru),
+            "lfu" => Some(Eviction::Lfu),
+            "ttl-first" => Some(Eviction::TtlFirst),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Eviction::Lru => "lru",
+            Eviction::Lfu => "lfu",
+            Eviction::TtlFirst => "ttl-first",
+        }
+    }
+}
+
+/**
+ * Limits of a store, kept in its header. Sizes are measured as stored:
+ * the key plus the encrypted, hex encoded value.
+ * A store with an eviction policy is a cache: instead of failing, writes evict other entries
+ */
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Limits {
+    pub max_keys: Option<usize>,
+    pub max_value_bytes: Option<usize>,
+    pub max_bytes: Option<usize>,
+    pub eviction: Option<Eviction>,
+}
+
+impl Limits {
+    /**
+     * reads the limits from the header fields of a store
+     */
+    pub fn from_meta(meta: &HashMap<String, String>) -> Result<Limits> {
+        let number = |field: &str| -> Result<Option<usize>> {
+            match meta.get(field) {
+                Some(value) => value
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| Error::Corrupt(format!("invalid {} in store header: '{}'", field, value))),
+                None => Ok(None),
+            }
+        };
+        let eviction = match meta.get(META_EVICTION) {
+            Some(name) => Some(Eviction::from_name(name).ok_or_else(|| {
+                Error::Corrupt(format!("unknown eviction policy in store header: '{}'", name))
+            })?),
+            None => None,
+        };
+        Ok(Limits {
+            max_keys: number(META_MAX_KEYS)?,
+            max_value_bytes: number(META_MAX_VALUE_BYTES)?,
+            max_bytes: number(META_MAX_BYTES)?,
+            eviction,
+        })
+    }
+
+    /**
+     * header fields for these limits, None means the field is removed
+     */
+    pub fn to_meta(&self) -> Vec<(&'static str, Option<String>)> {
+        vec![
+            (META_MAX_KEYS, self.max_keys.map(|n| n.to_string())),
+            (META_MAX_VALUE_BYTES, self.max_value_bytes.map(|n| n.to_string())),
+            (META_MAX_BYTES, self.max_bytes.map(|n| n.to_string())),
+            (META_EVICTION, self.eviction.map(|e| e.name().to_string())),
+        ]
+    }
+
+    pub fn is_cache(&self) -> bool {
+        self.eviction.is_some()
+    }
+
+    /**
+     * checks a single entry against the limits, no eviction can make room for it
+     */
+    pub fn check_entry(&self, key: &str, value: &str) -> Result<()> {
+        self.check_size(key, value.len())
+    }
+
+    /**
+     * same as check_entry for a record, files count for their size in the blob directory
+     */
+    pub fn check_record(&self, key: &str, record: &Record) -> Result<()> {
+        self.check_size(key, record_size(key, record) - key.len())
+    }
+
+    fn check_size(&self, key: &str, value_size: usize) -> Result<()> {
+        if self.max_keys == Some(0) {
+            return Err(Error::Quota("the store doesn't allow any keys".to_string()));
+        }
+        if let Some(max) = self.max_value_bytes {
+            if value_size > max {
+                return Err(Error::Quota(format!(
+                    "value of '{}' is {} bytes, the store allows {} per value",
+                    key, value_size, max
+                )));
+            }
+        }
+        if let Some(max) = self.max_bytes {
+            if key.len() + value_size > max {
+                return Err(Error::Quota(format!(
+                    "'{}' takes {} bytes, more than the {} the whole store allows",
+                    key,
+                    key.len() + value_size,
+                    max
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * describes the first limit that keys and bytes go over, if any
+     */
+    pub fn exceeded(&self, keys: usize, bytes: usize) -> Option<String> {
+        if let Some(max) = self.max_keys.filter(|max| keys > *max) {
+            return Some(format!("the store is limited to {} keys", max));
+        }
+        if let Some(max) = self.max_bytes.filter(|max| bytes > *max) {
+            return Some(format!("the store is limited to {} bytes, it would take {}", max, bytes));
+        }
+        None
+    }
+}
+
+/**
+ * bytes an entry counts for
+ */
+pub fn entry_size(key: &str, value: &str) -> usize {
+    key.len() + value.len()
+}
+
+/**
+ * bytes a record counts for: a file saved with put_file counts for its size, not for its reference
+ */
+pub fn record_size(key: &str, record: &Record) -> usize {
+    match record.blob {
+        Some(size) => key.len() + usize::try_from(size).unwrap_or(usize::MAX),
+        None => entry_size(key, &record.value),
+    }
+}
+
+/**
+ * When an entry was last used, on a logical clock, and how many times it was read
+ */
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Access {
+    pub tick: u64,
+    pub hits: u64,
+}
+
+/**
+ * Access tracking for the eviction policies of a cache store
+ */
+#[derive(Default)]
+pub struct Usage {
+    // a counter instead of a timestamp, so entries used in the same second still have an order
+    clock: u64,
+    entries: HashMap<String, Access>,
+    // there are accesses that haven't been flushed
+    pub changed: bool,
+}
+
+impl Usage {
+    /**
+     * restores the access of an entry read from the store file
+     */
+    pub fn load(&mut self, key: &str, access: Access) {
+        self.clock = self.clock.max(access.tick);
+        self.entries.insert(key.to_string(), access);
+    }
+
+    /**
+     * records a write of key
+     */
+    pub fn written(&mut self, key: &str) {
+        self.clock += 1;
+        let entry = self.entries.entry(key.to_string()).or_default();
+        entry.tick = self.clock;
+        self.changed = true;
+    }
+
+    /**
+     * records a read of key
+     */
+    pub fn read(&mut self, key: &str) {
+        self.written(key);
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.hits += 1;
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.entries.remove(key);
+    }
+
+    pub fn get(&self, key: &str) -> Access {
+        self.entries.get(key).copied().unwrap_or_default()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Access)> {
+        self.entries.iter()
+    }
+}
+
+/**
+ * orders the candidates so the first one is the first to be evicted
+ */
+pub fn eviction_order(policy: Eviction, mut candidates: Vec<(String, Record)>, usage: &Usage) -> Vec<(String, Record)> {
+    candidates.sort_by_key(|(key, record)| {
+        let access = usage.get(key);
+        match policy {
+            Eviction::Lru => (0, 0, access.tick),
+            Eviction::Lfu => (0, access.hits, access.tick),
+            // entries with a ttl go first, soonest to expire first
+            Eviction::TtlFirst => match record.expires {
+                Some(expires) => (0, expires, 0),
+                None => (1, 0, access.tick),
+            },
+        }
+    });
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(keys: &[&str]) -> Vec<(String, Record)> {
+        keys.iter()
+            .map(|key| (key.to_string(), Record::new("value".to_string())))
+            .collect()
+    }
+
+    fn order(policy: Eviction, candidates: Vec<(String, Record)>, usage: &Usage) -> Vec<String> {
+        eviction_order(policy, candidates, usage)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    #[test]
+    fn eviction_policies() {
+        let mut usage = Usage::default();
+        for key in ["a", "b", "c"] {
+            usage.written(key);
+        }
+        usage.read("a");
+        usage.read("a");
+        usage.read("b");
+        // c was only written, b was read after a
+        assert_eq!(order(Eviction::Lru, records(&["a", "b", "c"]), &usage), vec!["c", "a", "b"]);
+        assert_eq!(order(Eviction::Lfu, records(&["a", "b", "c"]), &usage), vec!["c", "b", "a"]);
+        let mut candidates = records(&["a", "b", "c"]);
+        candidates[0].1.expires = Some(200);
+        candidates[1].1.expires = Some(100);
+        assert_eq!(order(Eviction::TtlFirst, candidates, &usage), vec!["b", "a", "c"]);
+    }
+
+    #[test]
+    fn limits_in_meta() {
+        let limits = Limits {
+            max_keys: Some(10),
+            max_value_bytes: None,
+            max_bytes: Some(2048),
+            eviction: Some(Eviction::TtlFirst),
+        };
+        let meta: HashMap<String, String> = limits
+            .to_meta()
+            .into_iter()
+            .filter_map(|(field, value)| value.map(|v| (field.to_string(), v)))
+            .collect();
+        assert_eq!(Limits::from_meta(&meta).unwrap(), limits);
+        assert!(limits.exceeded(11, 0).is_some());
+        assert!(limits.exceeded(10, 2049).is_some());
+        assert!(limits.exceeded(10, 2048).is_none());
+        let mut bad = HashMap::new();
+        bad.insert(META_MAX_KEYS.to_string(), "lots".to_string());
+        assert!(matches!(Limits::from_meta(&bad), Err(Error::Corrupt(_))));
+    }
+}
@@ -1,12 +1,18 @@
 pub enum LogLevel {
-    VERBOSE, //always want to print
-    DEBUG,   //only print if debug
+    Verbose, //always want to print
+    Debug,   //only print if debug
 }
 
 pub struct Logger {
     pub is_debug: bool,
 }
 
+impl Default for Logger {
+    fn default() -> Self {
+        Logger::new()
+    }
+}
+
 impl Logger {
     pub fn new() -> Logger {
         Logger { is_debug: false }
@@ -14,10 +20,10 @@ impl Logger {
 
     fn log(&self, log_level: LogLevel, message: String) {
         match log_level {
-            LogLevel::VERBOSE => {
+            LogLevel::Verbose => {
                 println!("{}", message);
             }
-            LogLevel::DEBUG => {
+            LogLevel::Debug => {
                 if self.is_debug {
                     println!("DEBUG:\t{}", message);
                 }
@@ -26,11 +32,16 @@ impl Logger {
     }
 
     pub fn debug(&self, message: String) {
-        self.log(LogLevel::DEBUG, message);
+        self.log(LogLevel::Debug, message);
     }
 
     pub fn display(&self, message: String) {
-        self.log(LogLevel::VERBOSE, message);
+        self.log(LogLevel::Verbose, message);
+    }
+
+    // prints a message holding secrets as it is, the caller zeroes it after
+    pub fn display_secret(&self, message: &str) {
+        print!("{}", message);
     }
 
     pub fn toggle_debug(&mut self, e:bool) {
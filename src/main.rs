@@ -1,7 +1,11 @@
 mod app;
-mod crypto;
-fn main() {
+// the library's test helpers, for the tests of the commands
+#[cfg(test)]
+#[path = "testing.rs"]
+mod testing;
+fn main() -> std::process::ExitCode {
     let arguments: Vec<String> = std::env::args().collect();
     let app:app::App = app::App::new(arguments);
-    app.init();
-}
\ No newline at end of file
+    // errors come back as exit codes too, so the stores a command opened are closed and unlocked first
+    std::process::ExitCode::from(app.init() as u8)
+}
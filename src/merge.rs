@@ -0,0 +1,218 @@
+use crate::blob;
+use crate::error::Result;
+use crate::store::Store;
+
+/**
+ * How a key with different values in both stores is resolved.
+ * Ours is the destination store, theirs the one being merged into it
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Policy {
+    Ours,
+    Theirs,
+    // the value written last wins, ours on a tie
+    Newest,
+    // each conflict is resolved by the caller
+    Interactive,
+}
+
+impl Policy {
+    pub fn from_name(name: &str) -> Option<Policy> {
+        match name {
+            "ours" => Some(Policy::Ours),
+            "theirs" => Some(Policy::Theirs),
+            "newest" => Some(Policy::Newest),
+            "interactive" => Some(Policy::Interactive),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Side {
+    Ours,
+    Theirs,
+}
+
+/**
+ * A key whose decrypted values differ between the two stores.
+ * Files saved with put_file are compared by contents, their values are descriptions
+ */
+pub struct Conflict {
+    pub key: String,
+    pub ours: String,
+    pub theirs: String,
+    // unix timestamps of the last write of each side, if known
+    pub ours_modified: Option<u64>,
+    pub theirs_modified: Option<u64>,
+    // the side that was kept. None in a dry run of an interactive merge
+    pub resolution: Option<Side>,
+}
+
+/**
+ * What a merge changed, or would change in a dry run
+ */
+#[derive(Default)]
+pub struct MergeSummary {
+    // keys only in theirs, copied over
+    pub added: Vec<String>,
+    // conflicting keys that took the value of theirs
+    pub updated: Vec<String>,
+    pub conflicts: Vec<Conflict>,
+    // keys with the same value in both
+    pub unchanged: usize,
+}
+
+/**
+ * Merges the keys of src into dst. Values are decrypted before being compared,
+ * so the same value encrypted twice is not a conflict.
+ * With dry_run nothing is written and ask is never called
+ */
+pub fn merge(
+    src: &Store,
+    dst: &mut Store,
+    policy: Policy,
+    dry_run: bool,
+    mut ask: impl FnMut(&Conflict) -> Side,
+) -> Result<MergeSummary> {
+    let mut summary = MergeSummary::default();
+    // reading from a snapshot, so src can't change under the merge
+    for pair in src.snapshot() {
+        let (key, theirs) = pair?;
+        let ours = match dst.get(&key)? {
+            Some(ours) => ours,
+            None => {
+                if !dry_run {
+                    copy_key(src, dst, &key, &theirs)?;
+                }
+                summary.added.push(key);
+                continue;
+            }
+        };
+        if same_contents(src, dst, &key, &ours, &theirs)? {
+            summary.unchanged += 1;
+            continue;
+        }
+        let mut conflict = Conflict {
+            ours_modified: modified(dst, &key),
+            theirs_modified: modified(src, &key),
+            key,
+            ours,
+            theirs,
+            resolution: None,
+        };
+        conflict.resolution = match policy {
+            Policy::Ours => Some(Side::Ours),
+            Policy::Theirs => Some(Side::Theirs),
+            Policy::Newest if conflict.theirs_modified > conflict.ours_modified => Some(Side::Theirs),
+            Policy::Newest => Some(Side::Ours),
+            Policy::Interactive if dry_run => None,
+            Policy::Interactive => Some(ask(&conflict)),
+        };
+        if conflict.resolution == Some(Side::Theirs) {
+            if !dry_run {
+                copy_key(src, dst, &conflict.key, &conflict.theirs)?;
+            }
+            summary.updated.push(conflict.key.to_string());
+        }
+        summary.conflicts.push(conflict);
+    }
+    Ok(summary)
+}
+
+/**
+ * writes the value of key in src into dst, keeping its expiry and when it was written,
+ * so later merges by newest still compare the original writes
+ */
+fn copy_key(src: &Store, dst: &mut Store, key: &str, value: &str) -> Result<()> {
+    match src.get_file(key)? {
+        Some(mut reader) => {
+            dst.put_file(key, &mut reader)?;
+        }
+        None => dst.set(key, value)?,
+    }
+    match src.record(key) {
+        Some(record) => dst.copy_attributes(key, &record),
+        None => Ok(()),
+    }
+}
+
+/**
+ * compares decrypted values, streaming both files when the key holds one
+ */
+pub(crate) fn same_contents(src: &Store, dst: &Store, key: &str, ours: &str, theirs: &str) -> Result<bool> {
+    match (dst.get_file(key)?, src.get_file(key)?) {
+        (Some(mut ours), Some(mut theirs)) => {
+            if ours.size() != theirs.size() {
+                return Ok(false);
+            }
+            let (mut ours_buffer, mut theirs_buffer) = (vec![0u8; 64 * 1024], vec![0u8; 64 * 1024]);
+            loop {
+                let filled = blob::read_chunk(&mut ours, &mut ours_buffer)?;
+                if blob::read_chunk(&mut theirs, &mut theirs_buffer)? != filled
+                    || ours_buffer[..filled] != theirs_buffer[..filled]
+                {
+                    return Ok(false);
+                }
+                if filled == 0 {
+                    return Ok(true);
+                }
+            }
+        }
+        (None, None) => Ok(ours == theirs),
+        _ => Ok(false),
+    }
+}
+
+fn modified(store: &Store, key: &str) -> Option<u64> {
+    store.record(key).and_then(|record| record.modified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Engine;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn merge_policies() {
+        let data_dir = TempDir::new("merge");
+        let open = |name: &str| Store::open_with(&data_dir, name, Engine::Memory, false).unwrap();
+        let (mut src, mut dst) = (open("src"), open("dst"));
+        src.set("added", "value").unwrap();
+        src.set("same", "value").unwrap();
+        dst.set("same", "value").unwrap();
+        src.set("conflict", "theirs").unwrap();
+        dst.set("conflict", "ours").unwrap();
+        // ours is older than theirs
+        let mut record = dst.database().get_record("conflict").unwrap();
+        record.modified = Some(1);
+        dst.database_mut().insert_record("conflict".to_string(), record).unwrap();
+        let mut record = src.database().get_record("added").unwrap();
+        record.modified = Some(5);
+        src.database_mut().insert_record("added".to_string(), record).unwrap();
+
+        let summary = merge(&src, &mut dst, Policy::Theirs, true, |_| Side::Ours).unwrap();
+        assert_eq!(summary.added, vec!["added"]);
+        assert_eq!(summary.updated, vec!["conflict"]);
+        assert_eq!(summary.unchanged, 1);
+        // a dry run doesn't write anything
+        assert_eq!(dst.get("added").unwrap(), None);
+
+        let summary = merge(&src, &mut dst, Policy::Ours, false, |_| Side::Theirs).unwrap();
+        assert_eq!(summary.conflicts.len(), 1);
+        assert!(summary.updated.is_empty());
+        assert_eq!(dst.get("added").unwrap(), Some("value".to_string()));
+        assert_eq!(dst.get("conflict").unwrap(), Some("ours".to_string()));
+
+        let summary = merge(&src, &mut dst, Policy::Interactive, true, |_| panic!("not asked in a dry run")).unwrap();
+        assert_eq!(summary.conflicts[0].resolution, None);
+
+        merge(&src, &mut dst, Policy::Newest, false, |_| Side::Ours).unwrap();
+        assert_eq!(dst.get("conflict").unwrap(), Some("theirs".to_string()));
+        // copied values keep the time they were written in src
+        for key in ["added", "conflict"] {
+            assert_eq!(modified(&dst, key), modified(&src, key));
+        }
+    }
+}
@@ -0,0 +1,336 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::db::Record;
+
+/**
+ * One value of a key. A None record is a delete (tombstone)
+ */
+struct Version {
+    version: u64,
+    record: Option<Record>,
+}
+
+#[derive(Default)]
+struct Versions {
+    // versions of each key, oldest first
+    chains: HashMap<String, Vec<Version>>,
+    // last committed version
+    current: u64,
+    // snapshot version -> how many readers hold it
+    pins: BTreeMap<u64, usize>,
+    // keys with older versions kept for a snapshot, collected once no reader needs them anymore
+    stale: HashSet<String>,
+}
+
+impl Versions {
+    /**
+     * newest record of key that a reader at version `at` can see
+     */
+    fn visible(&self, key: &str, at: u64) -> Option<&Record> {
+        self.chains
+            .get(key)?
+            .iter()
+            .rev()
+            .find(|v| v.version <= at)?
+            .record
+            .as_ref()
+    }
+
+    /**
+     * drops the versions of key no reader can see anymore: only the versions newer than the
+     * oldest pinned snapshot, plus the one that snapshot sees, are kept.
+     * returns if older versions are still kept for a snapshot
+     */
+    fn collect(&mut self, key: &str) -> bool {
+        let horizon = self.pins.keys().next().copied().unwrap_or(self.current);
+        let chain = match self.chains.get_mut(key) {
+            Some(chain) => chain,
+            None => return false,
+        };
+        if let Some(pos) = chain.iter().rposition(|v| v.version <= horizon) {
+            chain.drain(..pos);
+        }
+        // a delete that every reader already sees leaves nothing behind
+        if chain.len() == 1 && chain[0].record.is_none() {
+            self.chains.remove(key);
+            return false;
+        }
+        chain.len() > 1
+    }
+
+    /**
+     * collects the keys that were written while a snapshot was pinned. only those can have
+     * versions to drop, so this costs as much as the writes made meanwhile, not the whole map
+     */
+    fn collect_garbage(&mut self) {
+        let stale = std::mem::take(&mut self.stale);
+        let still_stale: HashSet<String> = stale.into_iter().filter(|key| self.collect(key)).collect();
+        self.stale = still_stale;
+    }
+}
+
+/**
+ * Map keeping several versions of each key, so readers can pin a consistent
+ * snapshot while writers keep committing new versions
+ */
+pub struct VersionedMap {
+    inner: Arc<RwLock<Versions>>,
+}
+
+impl VersionedMap {
+    /**
+     * map holding records as its first version
+     */
+    pub fn from_records(records: HashMap<String, Record>) -> VersionedMap {
+        let chains = records
+            .into_iter()
+            .map(|(key, record)| {
+                let version = Version {
+                    version: 0,
+                    record: Some(record),
+                };
+                (key, vec![version])
+            })
+            .collect();
+        VersionedMap {
+            inner: Arc::new(RwLock::new(Versions {
+                chains,
+                ..Default::default()
+            })),
+        }
+    }
+
+    /**
+     * latest committed record of key
+     */
+    pub fn get(&self, key: &str) -> Option<Record> {
+        let versions = read(&self.inner);
+        versions.visible(key, versions.current).cloned()
+    }
+
+    /**
+     * commits a new version of key, None deletes it. returns the new version number
+     */
+    pub fn put(&self, key: &str, record: Option<Record>) -> u64 {
+        let mut versions = write(&self.inner);
+        versions.current += 1;
+        let version = versions.current;
+        versions
+            .chains
+            .entry(key.to_string())
+            .or_default()
+            .push(Version { version, record });
+        if versions.collect(key) {
+            versions.stale.insert(key.to_string());
+        }
+        version
+    }
+
+    /**
+     * pins the current version. it stays readable until the snapshot is dropped
+     */
+    pub fn snapshot(&self) -> Snapshot {
+        let mut versions = write(&self.inner);
+        let version = versions.current;
+        *versions.pins.entry(version).or_insert(0) += 1;
+        Snapshot {
+            inner: self.inner.clone(),
+            version,
+        }
+    }
+
+    /**
+     * last committed version
+     */
+    pub fn version(&self) -> u64 {
+        read(&self.inner).current
+    }
+
+    /**
+     * how many of the latest committed records match predicate, without copying them
+     */
+    pub fn count(&self, predicate: impl Fn(&Record) -> bool) -> usize {
+        let versions = read(&self.inner);
+        versions
+            .chains
+            .keys()
+            .filter_map(|key| versions.visible(key, versions.current))
+            .filter(|record| predicate(record))
+            .count()
+    }
+
+    /**
+     * calls f with every latest committed record, without copying them.
+     * the map is locked meanwhile, f can't write to it
+     */
+    pub fn for_each(&self, mut f: impl FnMut(&str, &Record)) {
+        let versions = read(&self.inner);
+        for key in versions.chains.keys() {
+            if let Some(record) = versions.visible(key, versions.current) {
+                f(key, record);
+            }
+        }
+    }
+
+    /**
+     * how many versions are kept across all keys, tombstones included
+     */
+    pub fn retained_versions(&self) -> usize {
+        read(&self.inner).chains.values().map(|chain| chain.len()).sum()
+    }
+}
+
+/**
+ * Read transaction: sees the map as it was when the snapshot was taken.
+ * It doesn't hold any lock between calls, so writers are never blocked by it
+ */
+pub struct Snapshot {
+    inner: Arc<RwLock<Versions>>,
+    version: u64,
+}
+
+impl Snapshot {
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn get(&self, key: &str) -> Option<Record> {
+        read(&self.inner).visible(key, self.version).cloned()
+    }
+
+    /**
+     * keys visible in the snapshot, sorted
+     */
+    pub fn keys(&self) -> Vec<String> {
+        let versions = read(&self.inner);
+        let mut keys: Vec<String> = versions
+            .chains
+            .keys()
+            .filter(|key| versions.visible(key, self.version).is_some())
+            .cloned()
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /**
+     * every visible record, sorted by key
+     */
+    pub fn records(&self) -> Vec<(String, Record)> {
+        self.keys()
+            .into_iter()
+            .filter_map(|key| self.get(&key).map(|record| (key, record)))
+            .collect()
+    }
+}
+
+impl IntoIterator for Snapshot {
+    type Item = (String, Record);
+    type IntoIter = SnapshotIter;
+
+    /**
+     * iterates lazily, looking up one record at a time
+     */
+    fn into_iter(self) -> SnapshotIter {
+        SnapshotIter {
+            keys: self.keys().into_iter(),
+            snapshot: self,
+        }
+    }
+}
+
+pub struct SnapshotIter {
+    snapshot: Snapshot,
+    keys: std::vec::IntoIter<String>,
+}
+
+impl Iterator for SnapshotIter {
+    type Item = (String, Record);
+
+    fn next(&mut self) -> Option<(String, Record)> {
+        for key in self.keys.by_ref() {
+            if let Some(record) = self.snapshot.get(&key) {
+                return Some((key, record));
+            }
+        }
+        None
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut versions = write(&self.inner);
+        let oldest = versions.pins.keys().next().copied();
+        if let Some(count) = versions.pins.get_mut(&self.version) {
+            *count -= 1;
+            if *count == 0 {
+                versions.pins.remove(&self.version);
+            }
+        }
+        // versions are only kept for the oldest snapshot, nothing to drop while it's still there
+        if versions.pins.keys().next().copied() != oldest {
+            versions.collect_garbage();
+        }
+    }
+}
+
+// nothing panics while holding these locks, so a poisoned lock still has consistent data
+fn read(lock: &RwLock<Versions>) -> RwLockReadGuard<'_, Versions> {
+    lock.read().unwrap_or_else(|e| e.into_inner())
+}
+
+fn write(lock: &RwLock<Versions>) -> RwLockWriteGuard<'_, Versions> {
+    lock.write().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(map: &VersionedMap, key: &str) -> Option<String> {
+        map.get(key).map(|r| r.value)
+    }
+
+    #[test]
+    fn snapshot_isolation() {
+        let mut records = HashMap::new();
+        records.insert("a".to_string(), Record::new("1".to_string()));
+        let map = VersionedMap::from_records(records);
+        let snapshot = map.snapshot();
+        map.put("a", Some(Record::new("2".to_string())));
+        map.put("b", Some(Record::new("3".to_string())));
+        // the snapshot still sees the old version, new reads see the new one
+        assert_eq!(snapshot.get("a").map(|r| r.value), Some("1".to_string()));
+        assert_eq!(snapshot.keys(), vec!["a"]);
+        assert_eq!(value(&map, "a"), Some("2".to_string()));
+        map.put("a", None);
+        assert_eq!(value(&map, "a"), None);
+        assert_eq!(snapshot.records().len(), 1);
+        let newer = map.snapshot();
+        assert_eq!(newer.keys(), vec!["b"]);
+        // a:0, a:1, a:3 (delete) and b:2 are needed by the two snapshots
+        assert_eq!(map.retained_versions(), 4);
+        drop(snapshot);
+        drop(newer);
+        // once nobody pins them, only the latest version of b is left
+        assert_eq!(map.retained_versions(), 1);
+    }
+
+    #[test]
+    fn iterating_while_writing() {
+        let map = VersionedMap::from_records(HashMap::new());
+        for i in 0..5 {
+            map.put(&format!("key-{}", i), Some(Record::new(i.to_string())));
+        }
+        let mut seen = 0;
+        for (key, _) in map.snapshot() {
+            // writers go ahead while the iteration is running
+            map.put(&key, None);
+            map.put("new", Some(Record::new("x".to_string())));
+            seen += 1;
+        }
+        assert_eq!(seen, 5);
+        assert_eq!(map.snapshot().keys(), vec!["new"]);
+    }
+}
@@ -0,0 +1,159 @@
+use std::io::{BufRead, BufReader, Write};
+
+use crate::error::{Error, Result};
+
+// passphrase of the key file
+pub static ENV_PASSPHRASE: &str = "GUI_KVSTORE_PASSPHRASE";
+// file descriptor to read the passphrase from, its first line is used
+pub static ENV_PASSPHRASE_FD: &str = "GUI_KVSTORE_PASSPHRASE_FD";
+// passphrase replacing the current one when changing it
+pub static ENV_NEW_PASSPHRASE: &str = "GUI_KVSTORE_NEW_PASSPHRASE";
+
+/**
+ * Where passphrases come from. The environment is checked first: GUI_KVSTORE_PASSPHRASE,
+ * then the file descriptor in GUI_KVSTORE_PASSPHRASE_FD. The terminal source then prompts on the TTY
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PassphraseSource {
+    // for libraries and scripts, never prompts
+    Environment,
+    // for the command line, prompts when the environment has none
+    Terminal,
+}
+
+impl PassphraseSource {
+    /**
+     * the passphrase unlocking an existing key, None if there's no way to get one
+     */
+    pub fn current(&self, prompt: &str) -> Result<Option<String>> {
+        if let Some(passphrase) = from_env()? {
+            return Ok(Some(passphrase));
+        }
+        match self {
+            PassphraseSource::Environment => Ok(None),
+            PassphraseSource::Terminal => read_tty(prompt),
+        }
+    }
+
+    /**
+     * the passphrase protecting a new key. None or empty leaves it unprotected
+     */
+    pub fn new_key(&self) -> Result<Option<String>> {
+        if let Some(passphrase) = from_env()? {
+            return Ok(Some(passphrase));
+        }
+        self.ask_twice()
+    }
+
+    /**
+     * the passphrase replacing the current one, from GUI_KVSTORE_NEW_PASSPHRASE or the terminal
+     */
+    pub fn replacement(&self) -> Result<Option<String>> {
+        if let Ok(passphrase) = std::env::var(ENV_NEW_PASSPHRASE) {
+            return Ok(Some(passphrase));
+        }
+        self.ask_twice()
+    }
+
+    fn ask_twice(&self) -> Result<Option<String>> {
+        if *self == PassphraseSource::Environment {
+            return Ok(None);
+        }
+        let passphrase = match read_tty("New passphrase (empty for none): ")? {
+            Some(passphrase) => passphrase,
+            None => return Ok(None),
+        };
+        if !passphrase.is_empty() && read_tty("Repeat the passphrase: ")?.as_ref() != Some(&passphrase) {
+            return Err(Error::Passphrase("the passphrases don't match".to_string()));
+        }
+        Ok(Some(passphrase))
+    }
+}
+
+/**
+ * passphrase from the environment variable, or from the file descriptor it names
+ */
+fn from_env() -> Result<Option<String>> {
+    if let Ok(passphrase) = std::env::var(ENV_PASSPHRASE) {
+        return Ok(Some(passphrase));
+    }
+    match std::env::var(ENV_PASSPHRASE_FD) {
+        Ok(fd) => {
+            let fd: i32 = fd
+                .parse()
+                .map_err(|_| Error::Config(format!("{} is not a file descriptor: '{}'", ENV_PASSPHRASE_FD, fd)))?;
+            read_fd(fd).map(Some)
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(unix)]
+fn read_fd(fd: i32) -> Result<String> {
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+    if fd < 0 || unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(Error::Config(format!("{} is not an open file descriptor: {}", ENV_PASSPHRASE_FD, fd)));
+    }
+    // the descriptor isn't ours to close, like when it's stdin: a copy of it is read and closed
+    let copy = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if copy == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let mut file = unsafe { std::fs::File::from_raw_fd(copy) };
+    // a byte at a time, so nothing after the first line is taken from it
+    let mut line = vec![];
+    let mut byte = [0u8; 1];
+    while file.read(&mut byte)? == 1 && byte[0] != b'\n' {
+        line.push(byte[0]);
+    }
+    let line = String::from_utf8(line)
+        .map_err(|_| Error::Passphrase(format!("the passphrase read from {} is not utf-8", ENV_PASSPHRASE_FD)))?;
+    Ok(line.trim_end_matches('\r').to_string())
+}
+
+#[cfg(not(unix))]
+fn read_fd(_fd: i32) -> Result<String> {
+    Err(Error::Config(format!("{} is only supported on unix", ENV_PASSPHRASE_FD)))
+}
+
+/**
+ * prompts on the terminal without echoing what is typed. None when there's no terminal
+ */
+#[cfg(unix)]
+fn read_tty(prompt: &str) -> Result<Option<String>> {
+    use std::os::unix::io::AsRawFd;
+    let tty = match std::fs::OpenOptions::new().read(true).write(true).open("/dev/tty") {
+        Ok(tty) => tty,
+        Err(_) => return Ok(None),
+    };
+    let fd = tty.as_raw_fd();
+    let mut writer = &tty;
+    writer.write_all(prompt.as_bytes())?;
+    writer.flush()?;
+    // turning echo off, and back on whatever happens while reading
+    let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+    let echo_off = unsafe { libc::tcgetattr(fd, &mut termios) } == 0;
+    if echo_off {
+        let mut silent = termios;
+        silent.c_lflag &= !libc::ECHO;
+        silent.c_lflag |= libc::ECHONL;
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &silent) };
+    }
+    let mut line = String::new();
+    let result = BufReader::new(&tty).read_line(&mut line);
+    if echo_off {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) };
+    }
+    result?;
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+#[cfg(not(unix))]
+fn read_tty(prompt: &str) -> Result<Option<String>> {
+    print!("{}", prompt);
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
@@ -0,0 +1,196 @@
+use crate::crypto;
+use crate::db::Database;
+use crate::error::{Error, Result};
+use crate::keys::KeyPair;
+
+// header field listing who else a store is shared with, along with the data key wrapped for each of them
+pub static META_RECIPIENTS: &str = "recipients";
+
+/**
+ * Someone a store is shared with. The data key of the store is wrapped with the public key
+ * of every recipient, the same way it's wrapped with the master key of its owner, so each
+ * of them opens the store with their own kvstore.key
+ */
+#[derive(Clone, PartialEq, Debug)]
+pub struct Recipient {
+    // a name for the recipient, like their email address
+    pub label: String,
+    pub public_key: [u8; 32],
+    // the data key, encrypted with public_key
+    wrapped: String,
+}
+
+impl Recipient {
+    /**
+     * wraps data_key for public_key
+     */
+    pub(crate) fn new(label: &str, public_key: [u8; 32], data_key: &KeyPair) -> Result<Recipient> {
+        if label.is_empty() || !label.chars().all(|c| c.is_alphanumeric() || "._@+-".contains(c)) {
+            return Err(Error::Config(format!(
+                "invalid recipient name '{}', use letters, digits and . _ @ + -",
+                label
+            )));
+        }
+        Ok(Recipient {
+            label: label.to_string(),
+            public_key,
+            wrapped: crate::store::wrap_data_key(&public_key, data_key)?,
+        })
+    }
+
+    pub fn fingerprint(&self) -> String {
+        crypto::fingerprint(&self.public_key)
+    }
+
+    /**
+     * if who is the name or the fingerprint of this recipient
+     */
+    pub fn is(&self, who: &str) -> bool {
+        self.label == who || self.fingerprint() == who
+    }
+
+    /**
+     * the wrapped data key, as saved in the store header
+     */
+    pub(crate) fn wrapped_key(&self) -> &str {
+        &self.wrapped
+    }
+}
+
+/**
+ * parses a public key given as 64 hex characters
+ */
+pub fn parse_public_key(hex: &str) -> Result<[u8; 32]> {
+    crypto::decode_hex(hex.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Error::Config(format!("'{}' is not a public key, expected 64 hex characters", hex)))
+}
+
+/**
+ * the recipients listed in the header of db
+ */
+pub(crate) fn read(db: &Database) -> Result<Vec<Recipient>> {
+    let field = match db.get_meta(META_RECIPIENTS) {
+        Some(field) if !field.is_empty() => field,
+        _ => return Ok(vec![]),
+    };
+    field
+        .split(',')
+        .map(|entry| {
+            let mut parts = entry.splitn(3, ':');
+            match (parts.next(), parts.next().map(parse_public_key), parts.next()) {
+                (Some(label), Some(Ok(public_key)), Some(wrapped)) => Ok(Recipient {
+                    label: label.to_string(),
+                    public_key,
+                    wrapped: wrapped.to_string(),
+                }),
+                _ => Err(Error::Corrupt(format!("invalid recipient in the header of store '{}'", db.name))),
+            }
+        })
+        .collect()
+}
+
+/**
+ * saves recipients in the header of db, removing the field when there are none
+ */
+pub(crate) fn write(db: &mut Database, recipients: &[Recipient]) {
+    if recipients.is_empty() {
+        db.remove_meta(META_RECIPIENTS);
+        return;
+    }
+    let field: Vec<String> = recipients
+        .iter()
+        .map(|r| format!("{}:{}:{}", r.label, crypto::encode_hex(&r.public_key), r.wrapped))
+        .collect();
+    db.set_meta(META_RECIPIENTS, field.join(","));
+}
+
+/**
+ * wraps a new data key for every recipient of db, after the store was re-keyed
+ */
+pub(crate) fn rewrap(db: &mut Database, data_key: &KeyPair) -> Result<()> {
+    let recipients = read(db)?
+        .into_iter()
+        .map(|r| Recipient::new(&r.label, r.public_key, data_key))
+        .collect::<Result<Vec<Recipient>>>()?;
+    write(db, &recipients);
+    Ok(())
+}
+
+/**
+ * the recipient of db holding the key with fingerprint, unless it's the owner of the store
+ */
+pub(crate) fn find(db: &Database, fingerprint: &str) -> Result<Option<Recipient>> {
+    if db.get_meta("fingerprint").map(|f| f.as_str()) == Some(fingerprint) {
+        return Ok(None);
+    }
+    Ok(read(db)?.into_iter().find(|r| r.fingerprint() == fingerprint))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Engine;
+    use crate::store::Store;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn shared_store() {
+        let data_dir = TempDir::new("recipients");
+        let (owner, bob, carol) = (
+            KeyPair::from(crypto::generate_key_pair().unwrap()),
+            KeyPair::from(crypto::generate_key_pair().unwrap()),
+            KeyPair::from(crypto::generate_key_pair().unwrap()),
+        );
+        let open = |keypair: &KeyPair| Store::open_with_keypair(&data_dir, "team", Engine::File, false, keypair);
+        let mut store = open(&owner).unwrap();
+        store.set("token", "secret").unwrap();
+        store.put_file("file", &mut &b"contents"[..]).unwrap();
+        store.add_recipient("bob", bob.0).unwrap();
+        store.add_recipient("carol", carol.0).unwrap();
+        assert!(matches!(store.add_recipient("bob", bob.0), Err(Error::Config(_))));
+        store.set_sealed(true);
+        store.flush().unwrap();
+        drop(store);
+
+        // recipients read and write with their own key, the store still belongs to the owner
+        let mut shared = open(&bob).unwrap();
+        assert_eq!(shared.get("token").unwrap(), Some("secret".to_string()));
+        shared.set("other", "value").unwrap();
+        assert_eq!(shared.database().get_meta("fingerprint"), Some(&crypto::fingerprint(&owner.0)));
+        assert!(matches!(shared.remove_recipient("carol", &bob), Err(Error::KeyMissing(_))));
+        drop(shared);
+
+        let objects = || crate::blob::list_objects(&Store::blob_path(&data_dir)).unwrap();
+        let old_objects = objects();
+        let mut store = open(&owner).unwrap();
+        let data_key = store.data_key_fingerprint();
+        let removed = store.remove_recipient(&crypto::fingerprint(&carol.0), &owner).unwrap();
+        assert_eq!(removed.label, "carol");
+        assert_ne!(store.data_key_fingerprint(), data_key);
+        assert_eq!(store.recipients().unwrap().len(), 1);
+        store.flush().unwrap();
+        drop(store);
+        assert!(matches!(open(&carol), Err(Error::KeyMissing(_))));
+        // the file was saved again with the new data key, carol can't read its old objects, they're gone
+        let new_objects = objects();
+        assert_eq!(new_objects.len(), old_objects.len());
+        assert!(new_objects.is_disjoint(&old_objects));
+        let shared = open(&bob).unwrap();
+        assert_eq!(shared.get("other").unwrap(), Some("value".to_string()));
+        let mut file = vec![];
+        std::io::Read::read_to_end(&mut shared.get_file("file").unwrap().unwrap(), &mut file).unwrap();
+        assert_eq!(file, b"contents");
+        drop(shared);
+
+        // rotating the key of a recipient only moves their copy of the data key
+        let data_key = open(&owner).unwrap().data_key_fingerprint();
+        let rotated = KeyPair::from(crypto::generate_key_pair().unwrap());
+        assert!(crate::rotate::rotate_store(&data_dir, "team", &bob, &rotated).unwrap());
+        assert!(!crate::rotate::rotate_store(&data_dir, "team", &bob, &rotated).unwrap());
+        assert_eq!(open(&rotated).unwrap().get("token").unwrap(), Some("secret".to_string()));
+        assert!(open(&bob).is_err());
+        assert_eq!(open(&owner).unwrap().data_key_fingerprint(), data_key);
+    }
+}
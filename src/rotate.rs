@@ -0,0 +1,274 @@
+use std::path::{Path, PathBuf};
+
+use crate::audit;
+use crate::blind::EntryKeys;
+use crate::blob::{self, BlobStore};
+use crate::crypto;
+use crate::db::{Database, Engine};
+use crate::error::{Error, Result};
+use crate::integrity::Integrity;
+use crate::keys::{self, KdfParams, KeyPair};
+use crate::recipients::{self, Recipient};
+use crate::store::{self, Store};
+
+// key being rotated to, inside the data directory. it replaces the key file once every store uses it
+pub static NEXT_KEY_FILE: &str = "kvstore.key.next";
+// folder of the data directory keeping the keys that were rotated out, named after their fingerprint
+pub static ARCHIVE_DIR: &str = "old-keys";
+
+/**
+ * What a key rotation did
+ */
+pub struct Rotation {
+    pub old_fingerprint: String,
+    pub new_fingerprint: String,
+    // stores re-encrypted by this run
+    pub rotated: Vec<String>,
+    // stores an interrupted run had already re-encrypted
+    pub resumed: Vec<String>,
+    // copy of the old key file
+    pub archived: PathBuf,
+    pub verification: Verification,
+}
+
+/**
+ * What was checked to decrypt with a key
+ */
+#[derive(Default, PartialEq, Debug)]
+pub struct Verification {
+    pub stores: usize,
+    pub values: usize,
+    pub files: usize,
+}
+
+/**
+ * Replaces the key pair of data_dir with a new one. Every store gets a new data key wrapped with it,
+ * its values and files re-encrypted, since the old data keys can be unwrapped with the old master key.
+ * Stores are rewritten one at a time and stamped with the fingerprint of the new key, so running
+ * it again after an interruption picks up the pending key and skips the stores already done.
+ * The new key is protected with passphrase, if any. Once every store is rotated the key of the
+ * audit log is wrapped with the new key, the old key file is archived, and everything is checked
+ * to decrypt with the new key
+ */
+pub fn rotate_key(data_dir: &Path, old: &KeyPair, passphrase: Option<&str>) -> Result<(KeyPair, Rotation)> {
+    let new = pending_key(data_dir, passphrase)?;
+    let (old_fingerprint, new_fingerprint) = (crypto::fingerprint(&old.0), crypto::fingerprint(&new.0));
+    let (mut rotated, mut resumed) = (vec![], vec![]);
+    let store_path = Store::store_path(data_dir);
+    // files are saved again with the new data keys. the objects there before that no store
+    // references anymore are deleted at the end, the ones of stores rotated by an interrupted run too
+    let objects = blob::list_objects(&Store::blob_path(data_dir))?;
+    let stores = match Database::list_stores(&store_path) {
+        Ok(stores) => stores,
+        Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e),
+    };
+    for name in stores {
+        if rotate_store(data_dir, &name, old, &new)? {
+            rotated.push(name);
+        } else {
+            resumed.push(name);
+        }
+    }
+    blob::release(&Store::blob_path(data_dir), Path::new(&store_path), objects)?;
+    audit::rewrap_key(data_dir, old, &new)?;
+    // the old key is only put away once nothing needs it anymore. it's copied and then replaced,
+    // so there is a key file at every step
+    let archive = data_dir.join(ARCHIVE_DIR);
+    std::fs::create_dir_all(&archive)?;
+    let archived = archive.join(format!("{}.key", old_fingerprint));
+    std::fs::copy(keys::key_path(data_dir), &archived)?;
+    std::fs::rename(data_dir.join(NEXT_KEY_FILE), keys::key_path(data_dir))?;
+    let verification = verify(data_dir, &new)?;
+    Ok((
+        new,
+        Rotation {
+            old_fingerprint,
+            new_fingerprint,
+            rotated,
+            resumed,
+            archived,
+            verification,
+        },
+    ))
+}
+
+/**
+ * the key an interrupted rotation was moving to, or a new one saved as the pending key
+ */
+fn pending_key(data_dir: &Path, passphrase: Option<&str>) -> Result<KeyPair> {
+    let path = data_dir.join(NEXT_KEY_FILE);
+    if path.exists() {
+        return match passphrase {
+            Some(passphrase) if keys::is_protected(&path)? => keys::load_with_passphrase(&path, passphrase),
+            _ => keys::load(&path),
+        };
+    }
+    let keypair = KeyPair::from(crypto::generate_key_pair()?);
+    match passphrase {
+        Some(passphrase) => keys::save_protected(&path, &keypair, passphrase, KdfParams::default())?,
+        None => keys::save(&path, &keypair)?,
+    }
+    Ok(keypair)
+}
+
+/**
+ * re-encrypts the values and files of a store with a new data key wrapped by the new master key,
+ * in a single flush. returns false if it was already rotated
+ */
+pub(crate) fn rotate_store(data_dir: &Path, name: &str, old: &KeyPair, new: &KeyPair) -> Result<bool> {
+    let mut db = Database::open_locked(name.to_string(), Store::store_path(data_dir), false, Engine::File)?;
+    let (old_fingerprint, new_fingerprint) = (crypto::fingerprint(&old.0), crypto::fingerprint(&new.0));
+    if recipients::find(&db, &new_fingerprint)?.is_some() {
+        return Ok(false);
+    }
+    // stores shared by someone else belong to them, only the data key wrapped for us moves to the new key
+    if let Some(recipient) = recipients::find(&db, &old_fingerprint)? {
+        let data_key = store::data_key(&db, old)?.unwrap_or_else(|| old.clone());
+        db.unseal(&data_key)?;
+        db.protect(Integrity::new(&data_key, Some(data_dir)))?;
+        let all: Vec<Recipient> = recipients::read(&db)?
+            .into_iter()
+            .map(|r| match r == recipient {
+                true => Recipient::new(&r.label, new.0, &data_key),
+                false => Ok(r),
+            })
+            .collect::<Result<_>>()?;
+        recipients::write(&mut db, &all);
+        db.flush()?;
+        return Ok(true);
+    }
+    match db.get_meta("fingerprint") {
+        Some(fingerprint) if *fingerprint == new_fingerprint => return Ok(false),
+        Some(fingerprint) if *fingerprint != old_fingerprint => {
+            return Err(Error::KeyMissing(format!(
+                "store '{}' was written with key {}, not with the current key {}",
+                name, fingerprint, old_fingerprint
+            )))
+        }
+        _ => {}
+    }
+    // stores older than data keys are encrypted with the master key itself
+    let old_data_key = store::data_key(&db, old)?.unwrap_or_else(|| old.clone());
+    db.unseal(&old_data_key)?;
+    db.protect(Integrity::new(&old_data_key, Some(data_dir)))?;
+    let new_data_key = KeyPair::from(crypto::generate_key_pair()?);
+    let (old_blobs, new_blobs) = (
+        BlobStore::new(Store::blob_path(data_dir), &old_data_key),
+        BlobStore::new(Store::blob_path(data_dir), &new_data_key),
+    );
+    // the blind index is keyed by the data key too, so blinded entries move
+    let old_keys = EntryKeys::of(&old_data_key, &db);
+    let new_keys = old_keys.rekeyed(&new_data_key);
+    store::reencrypt(&mut db, &old_keys, &new_keys, Some((&old_blobs, &new_blobs)))?;
+    if db.is_sealed() {
+        db.set_seal(Some(&new_data_key));
+    }
+    db.set_meta(store::META_DATA_KEY, store::wrap_data_key(&new.0, &new_data_key)?);
+    recipients::rewrap(&mut db, &new_data_key)?;
+    db.rekey_integrity(&new_data_key);
+    db.set_meta("fingerprint", new_fingerprint);
+    // the objects encrypted with the old data key are deleted once the store stops referencing them
+    store::flush_with_refs(&mut db, &new_keys, &new_blobs)?;
+    Ok(true)
+}
+
+/**
+ * checks that every value and file of every store in data_dir decrypts with keypair
+ */
+pub fn verify(data_dir: &Path, keypair: &KeyPair) -> Result<Verification> {
+    let mut verification = Verification::default();
+    let store_path = Store::store_path(data_dir);
+    if !Path::new(&store_path).exists() {
+        return Ok(verification);
+    }
+    for name in Database::list_stores(&store_path)? {
+        let store = Store::open_with_keypair(data_dir, &name, Engine::File, false, keypair)?;
+        for pair in store.snapshot() {
+            let (key, _) = pair?;
+            match store.get_file(&key)? {
+                Some(mut reader) => {
+                    std::io::copy(&mut reader, &mut std::io::sink())?;
+                    verification.files += 1;
+                }
+                None => verification.values += 1,
+            }
+        }
+        verification.stores += 1;
+    }
+    Ok(verification)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn resumable_rotation() {
+        let data_dir = TempDir::new("rotate");
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let old = KeyPair::from(crypto::generate_key_pair().unwrap());
+        keys::save(&keys::key_path(&data_dir), &old).unwrap();
+        {
+            let open = |name: &str| Store::open_with_keypair(&data_dir, name, Engine::File, false, &old).unwrap();
+            let (mut a, mut b) = (open("a"), open("b"));
+            a.set("token", "secret").unwrap();
+            a.set_blind_keys(true).unwrap();
+            b.set_with_ttl("session", "value", 3600).unwrap();
+            b.put_file("file", &mut &[7u8; 100][..]).unwrap();
+            b.set_sealed(true);
+            // a store from before data keys, encrypted with the master key
+            let mut legacy = Database::open("legacy".to_string(), Store::store_path(&data_dir), false, Engine::File).unwrap();
+            legacy.insert("key".to_string(), crypto::encrypt_string(&old.0, "value".to_string()).unwrap()).unwrap();
+            legacy.flush().unwrap();
+        }
+        let objects = || -> Vec<PathBuf> {
+            std::fs::read_dir(Store::blob_path(&data_dir))
+                .unwrap()
+                .flat_map(|folder| std::fs::read_dir(folder.unwrap().path()).unwrap())
+                .map(|object| object.unwrap().path())
+                .collect()
+        };
+        let old_objects = objects();
+        assert_eq!(old_objects.len(), 2);
+        // a rotation interrupted after the first store
+        let new = pending_key(&data_dir, None).unwrap();
+        assert!(rotate_store(&data_dir, "a", &old, &new).unwrap());
+        assert!(!rotate_store(&data_dir, "a", &old, &new).unwrap());
+
+        let (rotated_to, rotation) = rotate_key(&data_dir, &old, None).unwrap();
+        assert_eq!(rotated_to, new);
+        assert_eq!(rotation.resumed, vec!["a"]);
+        assert_eq!(rotation.rotated, vec!["b", "legacy"]);
+        assert_eq!(
+            rotation.verification,
+            Verification {
+                stores: 3,
+                values: 3,
+                files: 1
+            }
+        );
+        assert_eq!(keys::load(&keys::key_path(&data_dir)).unwrap(), new);
+        assert_eq!(keys::load(&rotation.archived).unwrap(), old);
+        assert!(!data_dir.join(NEXT_KEY_FILE).exists());
+        // the file was saved again with the new data key, the objects of the old one are gone
+        assert_eq!(objects().len(), 2);
+        assert!(old_objects.iter().all(|object| !object.exists()));
+
+        let a = Store::open_with_keypair(&data_dir, "a", Engine::File, false, &new).unwrap();
+        assert_eq!(a.get("token").unwrap(), Some("secret".to_string()));
+        let b = Store::open_with_keypair(&data_dir, "b", Engine::File, false, &new).unwrap();
+        assert!(b.sealed());
+        assert_eq!(b.get("session").unwrap(), Some("value".to_string()));
+        assert!(b.database().get_record("session").unwrap().expires.is_some());
+        let mut contents = vec![];
+        std::io::Read::read_to_end(&mut b.get_file("file").unwrap().unwrap(), &mut contents).unwrap();
+        assert_eq!(contents, vec![7u8; 100]);
+        let legacy = Store::open_with_keypair(&data_dir, "legacy", Engine::File, false, &new).unwrap();
+        assert_eq!(legacy.get("key").unwrap(), Some("value".to_string()));
+        assert_ne!(legacy.data_key_fingerprint(), legacy.fingerprint());
+        // the old key can't read the stores anymore
+        assert!(verify(&data_dir, &old).is_err());
+    }
+}
@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+use std::io::{BufRead, ErrorKind, Read, Write};
+
+use crate::crypto::{self, Aead};
+use crate::error::{Error, Result};
+use crate::keys::KeyPair;
+
+// first field of the header of a sealed store file
+pub static SEALED_HEADER: &str = "#gui-kvstore-sealed";
+// bump this whenever the layout of sealed store files changes
+pub const SEALED_VERSION: u32 = 1;
+// plain text bytes per chunk, each one encrypted and authenticated on its own
+pub const CHUNK_SIZE: usize = 64 * 1024;
+// the rest of the 24 byte nonce is the chunk counter and the last chunk flag
+const NONCE_PREFIX_LEN: usize = 19;
+const TAG_LEN: usize = 16;
+
+/**
+ * Sealed stores are encrypted whole, so the file doesn't reveal the number of keys, their names
+ * or the size of their values. The file starts with a plain header line, holding what is needed to
+ * find the key: the fingerprint, the wrapped data key, and a random key for this write, wrapped with
+ * the data key. The contents of a regular store file follow, split in chunks encrypted with it.
+ * Each chunk nonce ends with its position and if it's the last one, and the header is authenticated
+ * with every chunk, so chunks can't be reordered, dropped or mixed with other writes
+ */
+pub fn is_sealed(start: &[u8]) -> bool {
+    start
+        .strip_prefix(SEALED_HEADER.as_bytes())
+        .is_some_and(|rest| rest.starts_with(b"\t"))
+}
+
+/**
+ * Encrypts everything written to it in chunks, see is_sealed
+ */
+pub struct SealedWriter<W: Write> {
+    out: W,
+    cipher: ChunkCipher,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> SealedWriter<W> {
+    /**
+     * writes the header with the given plain fields, like the fingerprint, and a new file key
+     * sealed for keypair, the data key of the store
+     */
+    pub fn new(out: W, fields: &[(&str, &str)], keypair: &KeyPair) -> Result<SealedWriter<W>> {
+        SealedWriter::with_chunk_size(out, fields, keypair, CHUNK_SIZE)
+    }
+
+    fn with_chunk_size(mut out: W, fields: &[(&str, &str)], keypair: &KeyPair, chunk_size: usize) -> Result<SealedWriter<W>> {
+        let aead = crypto::current_aead();
+        let file_key: [u8; 32] = crypto::random_bytes()?;
+        let nonce_prefix: [u8; NONCE_PREFIX_LEN] = crypto::random_bytes()?;
+        let mut header = format!("{}\tversion={}", SEALED_HEADER, SEALED_VERSION);
+        for (field, value) in fields {
+            header.push_str(&format!("\t{}={}", field, value));
+        }
+        header.push_str(&format!(
+            "\taead={}\tchunk_size={}\tfile_key={}\tnonce={}",
+            aead.name(),
+            chunk_size,
+            crypto::encode_hex(&crypto::encrypt(&keypair.0, &file_key)?),
+            crypto::encode_hex(&nonce_prefix)
+        ));
+        out.write_all(header.as_bytes())?;
+        out.write_all(b"\n")?;
+        Ok(SealedWriter {
+            out,
+            cipher: ChunkCipher {
+                aead,
+                key: file_key,
+                nonce_prefix,
+                header,
+                chunk_size,
+                counter: 0,
+            },
+            buffer: Vec::with_capacity(chunk_size),
+        })
+    }
+
+    /**
+     * encrypts what is left as the last chunk. without it the file doesn't open
+     */
+    pub fn finish(mut self) -> Result<W> {
+        let last = self.cipher.seal(&self.buffer, true)?;
+        self.out.write_all(&last)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> Write for SealedWriter<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(data);
+        // a full chunk is only written once more data follows, the last one is written by finish
+        while self.buffer.len() > self.cipher.chunk_size {
+            let rest = self.buffer.split_off(self.cipher.chunk_size);
+            let chunk = self.cipher.seal(&self.buffer, false).map_err(to_io)?;
+            self.out.write_all(&chunk)?;
+            self.buffer = rest;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/**
+ * Decrypts the chunks of a sealed store file, after its header line
+ */
+pub struct SealedReader<R: BufRead> {
+    input: R,
+    cipher: ChunkCipher,
+    buffer: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<R: BufRead> SealedReader<R> {
+    /**
+     * reads a sealed file from input, positioned after its header line. meta are the header fields
+     */
+    pub fn new(input: R, header: &str, meta: &HashMap<String, String>, keypair: &KeyPair) -> Result<SealedReader<R>> {
+        let field = |name: &str| -> Result<&String> {
+            meta.get(name)
+                .ok_or_else(|| Error::Corrupt(format!("sealed store header has no {}", name)))
+        };
+        let version: u32 = field("version")?.parse().unwrap_or(0);
+        if version > SEALED_VERSION {
+            return Err(Error::Format(format!(
+                "sealed store was written with format v{}, this version reads up to v{}",
+                version, SEALED_VERSION
+            )));
+        }
+        let aead = crypto::aead_from_name(field("aead")?)
+            .ok_or_else(|| Error::Format(format!("sealed store uses an unknown cipher '{}'", meta["aead"])))?;
+        let chunk_size: usize = field("chunk_size")?
+            .parse()
+            .ok()
+            .filter(|size| *size > 0)
+            .ok_or_else(|| Error::Corrupt("invalid chunk_size in sealed store header".to_string()))?;
+        let decode = |name: &str| -> Result<Vec<u8>> {
+            crypto::decode_hex(field(name)?)
+                .map_err(|_| Error::Corrupt(format!("{} of sealed store is not hex encoded", name)))
+        };
+        let file_key: [u8; 32] = crypto::decrypt(&keypair.1, &decode("file_key")?)?
+            .try_into()
+            .map_err(|_| Error::Corrupt("invalid file_key in sealed store header".to_string()))?;
+        let nonce_prefix: [u8; NONCE_PREFIX_LEN] = decode("nonce")?
+            .try_into()
+            .map_err(|_| Error::Corrupt("invalid nonce in sealed store header".to_string()))?;
+        Ok(SealedReader {
+            input,
+            cipher: ChunkCipher {
+                aead,
+                key: file_key,
+                nonce_prefix,
+                header: header.trim_end_matches(['\r', '\n']).to_string(),
+                chunk_size,
+                counter: 0,
+            },
+            buffer: vec![],
+            position: 0,
+            finished: false,
+        })
+    }
+
+    fn next_chunk(&mut self) -> Result<()> {
+        let mut sealed = vec![0u8; self.cipher.chunk_size + TAG_LEN];
+        let filled = crate::blob::read_chunk(&mut self.input, &mut sealed)?;
+        // the end of the file is only fine after the chunk flagged as the last one
+        let last = self.input.fill_buf()?.is_empty();
+        if filled == 0 {
+            return Err(Error::Corrupt("sealed store is truncated".to_string()));
+        }
+        self.buffer = self.cipher.open(&sealed[..filled], last)?;
+        self.position = 0;
+        self.finished = last;
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for SealedReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.buffer.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.next_chunk().map_err(to_io)?;
+        }
+        let count = out.len().min(self.buffer.len() - self.position);
+        out[..count].copy_from_slice(&self.buffer[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+/**
+ * The key and nonces of the chunks of one sealed write
+ */
+struct ChunkCipher {
+    aead: &'static dyn Aead,
+    key: [u8; 32],
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    // authenticated with every chunk
+    header: String,
+    chunk_size: usize,
+    counter: u32,
+}
+
+impl ChunkCipher {
+    fn nonce(&self, last: bool) -> Vec<u8> {
+        let mut nonce = self.nonce_prefix.to_vec();
+        nonce.extend_from_slice(&self.counter.to_be_bytes());
+        nonce.push(last as u8);
+        nonce
+    }
+
+    fn seal(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>> {
+        let sealed = self.aead.seal(&self.key, &self.nonce(last), self.header.as_bytes(), chunk)?;
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| Error::Crypto("store too large to seal".to_string()))?;
+        Ok(sealed)
+    }
+
+    fn open(&mut self, sealed: &[u8], last: bool) -> Result<Vec<u8>> {
+        let chunk = self
+            .aead
+            .open(&self.key, &self.nonce(last), self.header.as_bytes(), sealed)
+            .map_err(|_| {
+                Error::Crypto(format!(
+                    "chunk {} of the sealed store was modified, reordered or truncated",
+                    self.counter
+                ))
+            })?;
+        self.counter = self.counter.wrapping_add(1);
+        Ok(chunk)
+    }
+}
+
+fn to_io(e: Error) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{Database, Engine};
+    use crate::store::Store;
+    use crate::testing::TempDir;
+    use std::io::BufReader;
+
+    fn seal(data: &[u8], keypair: &KeyPair, chunk_size: usize) -> Vec<u8> {
+        let mut writer = SealedWriter::with_chunk_size(vec![], &[("fingerprint", "abc")], keypair, chunk_size).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn unseal(file: &[u8], keypair: &KeyPair) -> Result<Vec<u8>> {
+        let mut input = BufReader::new(file);
+        let mut header = String::new();
+        input.read_line(&mut header).unwrap();
+        assert!(is_sealed(header.as_bytes()));
+        let meta: HashMap<String, String> = header
+            .trim_end()
+            .split('\t')
+            .skip(1)
+            .filter_map(|field| field.split_once('='))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let mut reader = SealedReader::new(input, &header, &meta, keypair)?;
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn chunked_round_trip() {
+        let keypair = KeyPair::from(crypto::generate_key_pair().unwrap());
+        let data: Vec<u8> = (0..100u8).collect();
+        // 7 chunks of 16 bytes, the last one shorter
+        let file = seal(&data, &keypair, 16);
+        assert_eq!(unseal(&file, &keypair).unwrap(), data);
+        assert_eq!(unseal(&seal(b"", &keypair, 16), &keypair).unwrap(), b"");
+        // an exact number of chunks still ends with one flagged as the last
+        let file = seal(&data[..64], &keypair, 16);
+        assert_eq!(unseal(&file, &keypair).unwrap(), &data[..64]);
+        // dropping the last chunk, or flipping a byte, is caught
+        assert!(unseal(&file[..file.len() - 16 - TAG_LEN], &keypair).is_err());
+        let mut modified = file.clone();
+        let last = modified.len() - 1;
+        modified[last] ^= 1;
+        assert!(matches!(unseal(&modified, &keypair), Err(Error::Crypto(_))));
+        let other = KeyPair::from(crypto::generate_key_pair().unwrap());
+        assert!(unseal(&file, &other).is_err());
+    }
+
+    #[test]
+    fn sealed_store() {
+        let data_dir = TempDir::new("sealed");
+        let file = data_dir.join("data").join("sealed.db");
+        let mut store = Store::open_with(&data_dir, "sealed", Engine::File, false).unwrap();
+        store.set("prod_stripe_secret", "sk_live").unwrap();
+        store.set_with_ttl("session", "value", 3600).unwrap();
+        store.set_sealed(true);
+        store.flush().unwrap();
+        let contents = std::fs::read(&file).unwrap();
+        assert!(is_sealed(&contents));
+        assert!(!String::from_utf8_lossy(&contents).contains("prod_stripe_secret"));
+        drop(store);
+
+        // without the key only the header is there, and nothing can be written
+        let mut db = Database::open("sealed".to_string(), Store::store_path(&data_dir), false, Engine::File).unwrap();
+        assert!(db.is_sealed());
+        assert!(db.get_meta("data_key").is_some());
+        assert!(db.live_records().is_empty());
+        assert!(matches!(db.flush(), Err(Error::KeyMissing(_))));
+        drop(db);
+
+        let mut store = Store::open_with(&data_dir, "sealed", Engine::File, false).unwrap();
+        assert!(store.sealed());
+        assert_eq!(store.get("prod_stripe_secret").unwrap(), Some("sk_live".to_string()));
+        assert!(store.database().get_record("session").unwrap().expires.is_some());
+        store.set_sealed(false);
+        store.flush().unwrap();
+        drop(store);
+        assert!(std::fs::read_to_string(&file).unwrap().starts_with("#gui-kvstore\t"));
+        let store = Store::open_with(&data_dir, "sealed", Engine::File, false).unwrap();
+        assert!(!store.sealed());
+        assert_eq!(store.len(), 2);
+    }
+}
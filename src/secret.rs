@@ -0,0 +1,123 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+use zeroize::Zeroize;
+pub use zeroize::Zeroizing;
+
+/**
+ * A secret, like the key pair, kept on the heap where it can't be moved around and copied
+ * by the compiler. Its pages are locked in memory where the OS allows it, so it isn't written
+ * to swap, and it's zeroed when dropped
+ */
+pub struct Locked<T: Zeroize + Default> {
+    inner: Box<T>,
+    // mlock can fail, like when going over RLIMIT_MEMLOCK, the secret is still zeroed then
+    locked: bool,
+}
+
+impl<T: Zeroize + Default> Locked<T> {
+    /**
+     * Moves the secret in value to locked memory, leaving zeroes behind
+     */
+    pub fn take(value: &mut T) -> Locked<T> {
+        let mut inner = Box::<T>::default();
+        let locked = lock(&*inner);
+        std::mem::swap(&mut *inner, value);
+        value.zeroize();
+        Locked { inner, locked }
+    }
+
+    /**
+     * if the pages of the secret are locked in memory
+     */
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+}
+
+impl<T: Zeroize + Default> Default for Locked<T> {
+    fn default() -> Locked<T> {
+        Locked::take(&mut T::default())
+    }
+}
+
+impl<T: Zeroize + Default> Deref for Locked<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: Zeroize + Default> DerefMut for Locked<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: Zeroize + Default> fmt::Debug for Locked<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Locked(..)")
+    }
+}
+
+impl<T: Zeroize + Default> Drop for Locked<T> {
+    fn drop(&mut self) {
+        self.inner.zeroize();
+        if self.locked {
+            unlock(&*self.inner);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn lock<T>(value: &T) -> bool {
+    unsafe { libc::mlock(value as *const T as *const libc::c_void, std::mem::size_of::<T>()) == 0 }
+}
+
+#[cfg(unix)]
+fn unlock<T>(value: &T) {
+    unsafe { libc::munlock(value as *const T as *const libc::c_void, std::mem::size_of::<T>()) };
+}
+
+#[cfg(not(unix))]
+fn lock<T>(_value: &T) -> bool {
+    false
+}
+
+#[cfg(not(unix))]
+fn unlock<T>(_value: &T) {}
+
+/**
+ * Keeps the memory of this process, with the secrets in it, from being written out in a core dump.
+ * On Linux it also keeps other processes of the same user from attaching to it with ptrace.
+ * Returns false if the OS refused
+ */
+#[cfg(unix)]
+pub fn disable_core_dumps() -> bool {
+    let limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    let disabled = unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) } == 0;
+    #[cfg(target_os = "linux")]
+    let disabled = disabled && unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } == 0;
+    disabled
+}
+
+#[cfg(not(unix))]
+pub fn disable_core_dumps() -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::KeyPair;
+
+    #[test]
+    fn locked_secret() {
+        let mut keypair = KeyPair([1u8; 32], [2u8; 32]);
+        let locked = Locked::take(&mut keypair);
+        assert_eq!(keypair, KeyPair([0u8; 32], [0u8; 32]));
+        assert_eq!(locked.1, [2u8; 32]);
+        assert_eq!(format!("{:?}", locked), "Locked(..)");
+    }
+}
@@ -0,0 +1,254 @@
+use std::fmt;
+
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use crate::crypto;
+use crate::error::{Error, Result};
+use crate::keys::KeyPair;
+
+// first part of every share, with the version of its format
+static SHARE_PREFIX: &str = "kvshare1";
+
+/**
+ * One of the shares a private key is split into with Shamir's secret sharing. Any threshold of
+ * them rebuild the key, fewer reveal nothing about it. Every byte of the key is the constant term
+ * of a random polynomial of degree threshold - 1 over GF(256), and a share holds the value of
+ * those polynomials at its index.
+ *
+ * Shares are printed as one line, `kvshare1-3of5-2-FINGERPRINT-HEX-CHECKSUM`,
+ * with the fingerprint of the key and a checksum catching a mistyped character
+ */
+#[derive(Clone, PartialEq)]
+pub struct Share {
+    // x coordinate of the share, from 1
+    pub index: u8,
+    pub threshold: u8,
+    // how many shares were made
+    pub shares: u8,
+    // fingerprint of the public key of the split key
+    pub fingerprint: String,
+    bytes: [u8; 32],
+}
+
+impl Share {
+    /**
+     * Reads a share as printed. Anything before its prefix, like a label, and whitespace
+     * breaking it in groups are ignored
+     */
+    pub fn parse(text: &str) -> Result<Share> {
+        let start = text
+            .find(SHARE_PREFIX)
+            .ok_or_else(|| Error::InvalidKeyFile(format!("'{}' is not a key share", text.trim())))?;
+        let share: Zeroizing<String> = Zeroizing::new(text[start..].chars().filter(|c| !c.is_whitespace()).collect());
+        let invalid = || Error::InvalidKeyFile(format!("invalid key share '{}'", *share));
+        let (body, checksum) = share.rsplit_once('-').ok_or_else(invalid)?;
+        if crypto::checksum(body.as_bytes()) != checksum.to_lowercase() {
+            return Err(Error::InvalidKeyFile(format!(
+                "the checksum of key share '{}' doesn't match, it was mistyped",
+                *share
+            )));
+        }
+        let fields: Vec<&str> = body.split('-').collect();
+        let (threshold, shares) = match fields.get(1).and_then(|f| f.split_once("of")) {
+            Some((threshold, shares)) => (threshold.parse().ok(), shares.parse().ok()),
+            None => (None, None),
+        };
+        let bytes = fields
+            .get(4)
+            .and_then(|hex| crypto::decode_hex(hex).ok())
+            .map(Zeroizing::new)
+            .and_then(|bytes| bytes.as_slice().try_into().ok());
+        let index = fields.get(2).and_then(|index| index.parse().ok());
+        match (fields.len(), threshold, shares, index, bytes) {
+            (5, Some(threshold), Some(shares), Some(index), Some(bytes)) if index > 0 => Ok(Share {
+                index,
+                threshold,
+                shares,
+                fingerprint: fields[3].to_string(),
+                bytes,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Share {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // the share is written where the caller formats it, these copies of it are zeroed
+        let hex = Zeroizing::new(crypto::encode_hex(&self.bytes));
+        let body = Zeroizing::new(format!(
+            "{}-{}of{}-{}-{}-{}",
+            SHARE_PREFIX, self.threshold, self.shares, self.index, self.fingerprint, *hex
+        ));
+        write!(f, "{}-{}", *body, crypto::checksum(body.as_bytes()))
+    }
+}
+
+// only what identifies the share, like KeyPair, so it never ends up in a log or a panic message
+impl fmt::Debug for Share {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Share({}of{}-{}, {}, ..)", self.threshold, self.shares, self.index, self.fingerprint)
+    }
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        self.bytes.zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for Share {}
+
+/**
+ * splits the private key of keypair in shares, any threshold of them rebuilding it
+ */
+pub fn split(keypair: &KeyPair, shares: u8, threshold: u8) -> Result<Vec<Share>> {
+    if threshold < 2 || threshold > shares {
+        return Err(Error::Config(format!(
+            "the threshold must be at least 2 and at most the number of shares, got {} of {}",
+            threshold, shares
+        )));
+    }
+    // coefficients[i] holds the coefficients of degree 1 and up of the polynomial of byte i
+    let coefficients = Zeroizing::new(crypto::random_vec(32 * (threshold as usize - 1))?);
+    let coefficients: Vec<&[u8]> = coefficients.chunks(threshold as usize - 1).collect();
+    let fingerprint = crypto::fingerprint(&keypair.0);
+    Ok((1..=shares)
+        .map(|index| {
+            let mut bytes = [0u8; 32];
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                // Horner's method, from the highest degree down to the secret
+                *byte = coefficients[i]
+                    .iter()
+                    .rev()
+                    .fold(0, |y, &coefficient| mul(y, index) ^ coefficient);
+                *byte = mul(*byte, index) ^ keypair.1[i];
+            }
+            Share {
+                index,
+                threshold,
+                shares,
+                fingerprint: fingerprint.clone(),
+                bytes,
+            }
+        })
+        .collect())
+}
+
+/**
+ * rebuilds the key pair from at least threshold shares of it
+ */
+pub fn combine(shares: &[Share]) -> Result<KeyPair> {
+    let first = shares
+        .first()
+        .ok_or_else(|| Error::KeyMissing("no key shares were given".to_string()))?;
+    let mut used: Vec<&Share> = vec![];
+    for share in shares {
+        if share.fingerprint != first.fingerprint || share.threshold != first.threshold {
+            return Err(Error::InvalidKeyFile(format!(
+                "key share {} belongs to key {}, not to key {}",
+                share.index, share.fingerprint, first.fingerprint
+            )));
+        }
+        match used.iter().find(|other| other.index == share.index) {
+            Some(other) if other.bytes != share.bytes => {
+                return Err(Error::InvalidKeyFile(format!(
+                    "there are two different key shares {}",
+                    share.index
+                )))
+            }
+            Some(_) => {}
+            None => used.push(share),
+        }
+    }
+    if used.len() < first.threshold as usize {
+        return Err(Error::KeyMissing(format!(
+            "key {} needs {} shares to be rebuilt, only {} were given",
+            first.fingerprint,
+            first.threshold,
+            used.len()
+        )));
+    }
+    // Lagrange interpolation at 0
+    let mut private = [0u8; 32];
+    for share in &used {
+        let basis = used
+            .iter()
+            .filter(|other| other.index != share.index)
+            .fold(1, |basis, other| mul(basis, div(other.index, other.index ^ share.index)));
+        for (byte, &y) in private.iter_mut().zip(share.bytes.iter()) {
+            *byte ^= mul(y, basis);
+        }
+    }
+    let public = crypto::public_key(&private);
+    if crypto::fingerprint(&public) != first.fingerprint {
+        return Err(Error::InvalidKeyFile(format!(
+            "the key shares don't rebuild key {}, one of them is wrong",
+            first.fingerprint
+        )));
+    }
+    Ok(KeyPair(public, private))
+}
+
+/**
+ * product in GF(256), modulo the polynomial of AES x^8 + x^4 + x^3 + x + 1
+ */
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= a & 0u8.wrapping_sub(b & 1);
+        a = (a << 1) ^ (0x1b & 0u8.wrapping_sub(a >> 7));
+        b >>= 1;
+    }
+    product
+}
+
+/**
+ * a / b in GF(256), with the inverse of b being b^254
+ */
+fn div(a: u8, b: u8) -> u8 {
+    let mut inverse = 1;
+    for _ in 0..254 {
+        inverse = mul(inverse, b);
+    }
+    mul(a, inverse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_and_combine() {
+        let keypair = KeyPair::from(crypto::generate_key_pair().unwrap());
+        let shares = split(&keypair, 5, 3).unwrap();
+        assert_eq!(shares.len(), 5);
+        assert!(matches!(split(&keypair, 5, 6), Err(Error::Config(_))));
+        assert!(matches!(split(&keypair, 5, 1), Err(Error::Config(_))));
+
+        // any 3 shares rebuild the key, in any order, 2 don't
+        assert_eq!(combine(&shares[..3]).unwrap(), keypair);
+        assert_eq!(combine(&[shares[4].clone(), shares[1].clone(), shares[3].clone()]).unwrap(), keypair);
+        assert_eq!(combine(&shares).unwrap(), keypair);
+        assert!(matches!(combine(&shares[..2]), Err(Error::KeyMissing(_))));
+        assert!(matches!(combine(&[shares[0].clone(), shares[0].clone(), shares[1].clone()]), Err(Error::KeyMissing(_))));
+
+        // printed shares read back, and a mistyped character is caught
+        let printed = shares[2].to_string();
+        assert_eq!(Share::parse(&format!("Share 3: {}\n", printed)).unwrap(), shares[2]);
+        let position = printed.len() - 20;
+        let mut typo = printed.clone().into_bytes();
+        typo[position] = if typo[position] == b'0' { b'1' } else { b'0' };
+        assert!(matches!(Share::parse(&String::from_utf8(typo).unwrap()), Err(Error::InvalidKeyFile(_))));
+        // debug output leaves the share out
+        let hex = printed.split('-').nth(4).unwrap();
+        assert!(!format!("{:?}", shares[2]).contains(hex));
+
+        // shares of another key don't mix
+        let other = split(&KeyPair::from(crypto::generate_key_pair().unwrap()), 5, 3).unwrap();
+        assert!(matches!(
+            combine(&[shares[0].clone(), shares[1].clone(), other[2].clone()]),
+            Err(Error::InvalidKeyFile(_))
+        ));
+    }
+}
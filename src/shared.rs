@@ -0,0 +1,268 @@
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::error::{Error, Result};
+use crate::store::{Store, StoreSnapshot};
+
+/**
+ * A cloneable, thread-safe handle to a Store.
+ * Any number of readers run at the same time, writers wait for each other and for the readers.
+ * Nothing is written to disk until flush is called, or the last handle is dropped.
+ */
+#[derive(Clone)]
+pub struct SharedStore {
+    inner: Arc<RwLock<Store>>,
+}
+
+impl SharedStore {
+    pub fn new(store: Store) -> SharedStore {
+        SharedStore {
+            inner: Arc::new(RwLock::new(store)),
+        }
+    }
+
+    /**
+     * Reads and decrypts a value
+     */
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.read_lock()?.get(key)
+    }
+
+    /**
+     * Encrypts and saves a value
+     */
+    pub fn set(&self, key: &str, value: &str) -> Result<()> {
+        self.write_lock()?.set(key, value)
+    }
+
+    /**
+     * Encrypts and saves a value that expires after ttl seconds
+     */
+    pub fn set_with_ttl(&self, key: &str, value: &str, ttl: u64) -> Result<()> {
+        self.write_lock()?.set_with_ttl(key, value, ttl)
+    }
+
+    /**
+     * Removes a key, returning if it was there
+     */
+    pub fn delete(&self, key: &str) -> Result<bool> {
+        Ok(self.write_lock()?.delete(key))
+    }
+
+    /**
+     * Writes pending changes to the store file
+     */
+    pub fn flush(&self) -> Result<()> {
+        self.write_lock()?.flush()
+    }
+
+    /**
+     * Consistent view of the store that doesn't hold the lock, so long reads
+     * like iterating over every key don't keep writers waiting
+     */
+    pub fn snapshot(&self) -> Result<StoreSnapshot> {
+        Ok(self.read_lock()?.snapshot())
+    }
+
+    /**
+     * Runs f with shared access to the store, for things like iterating over it
+     */
+    pub fn read<R>(&self, f: impl FnOnce(&Store) -> R) -> Result<R> {
+        let store = self.read_lock()?;
+        Ok(f(&store))
+    }
+
+    /**
+     * Runs f with exclusive access to the store, for several changes in one go
+     */
+    pub fn write<R>(&self, f: impl FnOnce(&mut Store) -> R) -> Result<R> {
+        let mut store = self.write_lock()?;
+        Ok(f(&mut store))
+    }
+
+    fn read_lock(&self) -> Result<RwLockReadGuard<'_, Store>> {
+        self.inner
+            .read()
+            .map_err(|_| Error::Lock("a thread panicked while writing the store".to_string()))
+    }
+
+    fn write_lock(&self) -> Result<RwLockWriteGuard<'_, Store>> {
+        self.inner
+            .write()
+            .map_err(|_| Error::Lock("a thread panicked while writing the store".to_string()))
+    }
+}
+
+impl From<Store> for SharedStore {
+    fn from(store: Store) -> SharedStore {
+        SharedStore::new(store)
+    }
+}
+
+/**
+ * Async versions of the SharedStore methods. Each call runs on its own thread,
+ * so waiting for the lock or for the disk never blocks the executor.
+ * They don't depend on a particular runtime.
+ */
+#[cfg(feature = "async")]
+impl SharedStore {
+    pub fn get_async(&self, key: &str) -> blocking::Blocking<Result<Option<String>>> {
+        let (store, key) = (self.clone(), key.to_string());
+        blocking::spawn(move || store.get(&key))
+    }
+
+    pub fn set_async(&self, key: &str, value: &str) -> blocking::Blocking<Result<()>> {
+        let (store, key, value) = (self.clone(), key.to_string(), value.to_string());
+        blocking::spawn(move || store.set(&key, &value))
+    }
+
+    pub fn delete_async(&self, key: &str) -> blocking::Blocking<Result<bool>> {
+        let (store, key) = (self.clone(), key.to_string());
+        blocking::spawn(move || store.delete(&key))
+    }
+
+    pub fn flush_async(&self) -> blocking::Blocking<Result<()>> {
+        let store = self.clone();
+        blocking::spawn(move || store.flush())
+    }
+}
+
+#[cfg(feature = "async")]
+pub mod blocking {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll, Waker};
+
+    struct State<T> {
+        result: Option<T>,
+        waker: Option<Waker>,
+    }
+
+    /**
+     * Future resolving to the result of a closure running on another thread
+     */
+    pub struct Blocking<T> {
+        state: Arc<Mutex<State<T>>>,
+    }
+
+    /**
+     * Runs f on a new thread, returning a future for its result
+     */
+    pub fn spawn<T, F>(f: F) -> Blocking<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let state = Arc::new(Mutex::new(State {
+            result: None,
+            waker: None,
+        }));
+        let thread_state = state.clone();
+        std::thread::spawn(move || {
+            let result = f();
+            let mut state = thread_state.lock().unwrap_or_else(|e| e.into_inner());
+            state.result = Some(result);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        });
+        Blocking { state }
+    }
+
+    impl<T> Future for Blocking<T> {
+        type Output = T;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            match state.result.take() {
+                Some(result) => Poll::Ready(result),
+                None => {
+                    state.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn test_store(test_name: &str) -> (TempDir, SharedStore) {
+        let data_dir = TempDir::new(&format!("shared-{}", test_name));
+        let store = Store::open(&data_dir, "test").unwrap();
+        (data_dir, SharedStore::new(store))
+    }
+
+    #[test]
+    fn concurrent_access() {
+        let (data_dir, store) = test_store("threads");
+        let writers: Vec<_> = (0..4)
+            .map(|i| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    for j in 0..10 {
+                        store.set(&format!("key-{}-{}", i, j), "value").unwrap();
+                        assert_eq!(store.get(&format!("key-{}-{}", i, j)).unwrap(), Some("value".to_string()));
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(store.read(|s| s.len()).unwrap(), 40);
+        store.flush().unwrap();
+        drop(store);
+        assert_eq!(Store::open(&data_dir, "test").unwrap().len(), 40);
+    }
+
+    #[test]
+    fn snapshots_dont_block_writers() {
+        let (_data_dir, store) = test_store("snapshot");
+        store.set("key", "value").unwrap();
+        let snapshot = store.snapshot().unwrap();
+        store.set("key", "changed").unwrap();
+        store.set("other", "value").unwrap();
+        assert_eq!(snapshot.get("key").unwrap(), Some("value".to_string()));
+        assert_eq!(snapshot.into_iter().count(), 1);
+        assert_eq!(store.get("key").unwrap(), Some("changed".to_string()));
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_api() {
+        use std::future::Future;
+        use std::sync::Arc;
+        use std::task::{Context, Poll, Wake};
+
+        // minimal executor: parks the thread until the future wakes it up
+        struct ThreadWaker(std::thread::Thread);
+        impl Wake for ThreadWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+        fn block_on<F: Future>(future: F) -> F::Output {
+            let mut future = Box::pin(future);
+            let waker = Arc::new(ThreadWaker(std::thread::current())).into();
+            let mut cx = Context::from_waker(&waker);
+            loop {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                    return output;
+                }
+                std::thread::park();
+            }
+        }
+
+        let (_data_dir, store) = test_store("async");
+        block_on(async {
+            store.set_async("key", "value").await.unwrap();
+            assert_eq!(store.get_async("key").await.unwrap(), Some("value".to_string()));
+            assert!(store.delete_async("key").await.unwrap());
+            store.flush_async().await.unwrap();
+        });
+    }
+}
@@ -0,0 +1,1072 @@
+use std::collections::BTreeSet;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::blind::{EntryKeys, META_BLIND_KEYS};
+use crate::blob::{self, BlobReader, BlobStore};
+use crate::crypto;
+use crate::db::{self, Database, Engine, Record, StoreStats, META_PLAINTEXT};
+use crate::error::{Error, Result};
+use crate::integrity::Integrity;
+use crate::keys::{self, KeyPair, MasterKey};
+use crate::limits::Limits;
+use crate::mvcc::{self, Snapshot};
+use crate::recipients::{self, Recipient};
+
+// header field holding the key pair of a store, encrypted with the master key
+pub static META_DATA_KEY: &str = "data_key";
+
+/**
+ * An encrypted store: values are encrypted with the public key on the way in
+ * and decrypted with the private key on the way out.
+ * Each store has its own key pair, the data key, saved in its header encrypted with the master
+ * key pair of the data directory, and with the public key of every recipient the store is shared with.
+ * Stores written before data keys existed use the master key directly.
+ * Changes are persisted on flush, or when the Store is dropped.
+ */
+pub struct Store {
+    db: Database,
+    // data key of the store, and the key of its blind index
+    keys: EntryKeys,
+    // fingerprint of the master key that the data key is wrapped with
+    master_fingerprint: String,
+    // opened with the key of a recipient instead of the one of the owner, see the recipients module
+    recipient: bool,
+    // where values saved with put_file are kept
+    blobs: BlobStore,
+    // written before MACs existed and signed when it was opened
+    signed: bool,
+}
+
+impl Store {
+    /**
+     * Opens (or creates) the store with name in data_dir, using the key pair
+     * saved in data_dir. A key pair is generated the first time.
+     */
+    pub fn open(data_dir: &Path, name: &str) -> Result<Store> {
+        Store::open_with(data_dir, name, Engine::File, false)
+    }
+
+    /**
+     * Same as open, choosing the engine and if the db prints debug output
+     */
+    pub fn open_with(data_dir: &Path, name: &str, engine: Engine, log_debug: bool) -> Result<Store> {
+        let keypair = keys::load_or_create(data_dir)?;
+        Store::open_with_keypair(data_dir, name, engine, log_debug, &keypair)
+    }
+
+    /**
+     * Opens a store with a master key pair that was already loaded.
+     * A store without a data key gets a new one, unless it has values encrypted with the master key
+     */
+    pub fn open_with_keypair(
+        data_dir: &Path,
+        name: &str,
+        engine: Engine,
+        log_debug: bool,
+        master: &KeyPair,
+    ) -> Result<Store> {
+        Store::open_with_master(data_dir, name, engine, log_debug, master)
+    }
+
+    /**
+     * Opens a store with a master key that may be held somewhere else, like by the key agent.
+     * Only the data key of the store is unwrapped with it
+     */
+    pub fn open_with_master(
+        data_dir: &Path,
+        name: &str,
+        engine: Engine,
+        log_debug: bool,
+        master: &dyn MasterKey,
+    ) -> Result<Store> {
+        let db = Database::open(name.to_string(), Store::store_path(data_dir), log_debug, engine)?;
+        Store::load(db, data_dir, master)
+    }
+
+    /**
+     * Same as open_with_master, keeping the store locked until the Store is dropped: no other
+     * process writes it between reading it and flushing the changes made to it.
+     * Waits a few seconds for a process already writing it, then fails with a Lock error
+     */
+    pub fn open_locked(
+        data_dir: &Path,
+        name: &str,
+        engine: Engine,
+        log_debug: bool,
+        master: &dyn MasterKey,
+    ) -> Result<Store> {
+        let db = Database::open_locked(name.to_string(), Store::store_path(data_dir), log_debug, engine)?;
+        Store::load(db, data_dir, master)
+    }
+
+    /**
+     * unwraps the data key of db with master and checks the integrity of the store
+     */
+    fn load(mut db: Database, data_dir: &Path, master: &dyn MasterKey) -> Result<Store> {
+        let master_fingerprint = crypto::fingerprint(&master.public_key());
+        check_fingerprint(&db, master)?;
+        let keypair = match data_key(&db, master)? {
+            Some(keypair) => keypair,
+            // the records of sealed stores aren't loaded yet, the ones without a data key use the master key
+            None if db.live_count() == 0 && !db.is_sealed() => {
+                let keypair = KeyPair::from(crypto::generate_key_pair()?);
+                // an empty store isn't rewritten just for it, it's saved with the first write
+                db.set_meta_quietly(META_DATA_KEY, wrap_data_key(&master.public_key(), &keypair)?);
+                keypair
+            }
+            // --rotate-key moves these to a data key of their own
+            None => legacy_key(&db, master)?,
+        };
+        db.unseal(&keypair)?;
+        let signed = db.protect(Integrity::new(&keypair, Some(data_dir)))?;
+        let blobs = BlobStore::new(Store::blob_path(data_dir), &keypair);
+        let keys = EntryKeys::of(&keypair, &db);
+        // stores written before the objects they reference were listed block cleaning up
+        // the blob directory until they are listed
+        if db.engine() == Engine::File && Path::new(db.file_path()).exists() && blob::read_refs(db.file_path())?.is_none() {
+            blob::write_refs(db.file_path(), &blob_refs(&db, &keys, &blobs)?)?;
+        }
+        Ok(Store {
+            keys,
+            recipient: recipients::find(&db, &master_fingerprint)?.is_some(),
+            db,
+            master_fingerprint,
+            blobs,
+            signed,
+        })
+    }
+
+    /**
+     * Opens a store file anywhere on disk read-only, like a backup copy of a store.
+     * Its files are looked up in the blobs folder next to the folder holding it
+     */
+    pub fn open_file(path: &Path, log_debug: bool, master: &KeyPair) -> Result<Store> {
+        Store::open_file_with_master(path, log_debug, master)
+    }
+
+    /**
+     * Same as open_file, with a master key that may be held somewhere else
+     */
+    pub fn open_file_with_master(path: &Path, log_debug: bool, master: &dyn MasterKey) -> Result<Store> {
+        // a copy is older than the store by nature, only its MAC is checked
+        Store::load_read_only(Database::open_file(path, log_debug)?, None, master)
+    }
+
+    /**
+     * Opens the store with name in data_dir read-only, checking its integrity like open does
+     * without ever writing it, not even to sign a store from before MACs
+     */
+    pub fn open_read_only(data_dir: &Path, name: &str, log_debug: bool, master: &dyn MasterKey) -> Result<Store> {
+        let path = data_dir.join("data").join(format!("{}.db", name));
+        Store::load_read_only(Database::open_file(&path, log_debug)?, Some(data_dir), master)
+    }
+
+    /**
+     * unwraps the data key of db opened with open_file, checking its rollback against the
+     * stores seen in data_dir if given
+     */
+    fn load_read_only(mut db: Database, data_dir: Option<&Path>, master: &dyn MasterKey) -> Result<Store> {
+        let blob_dir = match data_dir {
+            Some(data_dir) => Store::blob_path(data_dir),
+            None => Store::blob_path(
+                Path::new(db.file_path())
+                    .parent()
+                    .and_then(|folder| folder.parent())
+                    .unwrap_or_else(|| Path::new(".")),
+            ),
+        };
+        check_fingerprint(&db, master)?;
+        let keypair = match data_key(&db, master)? {
+            Some(keypair) => keypair,
+            None => legacy_key(&db, master)?,
+        };
+        db.unseal(&keypair)?;
+        db.protect(Integrity::new(&keypair, data_dir))?;
+        let blobs = BlobStore::new(blob_dir, &keypair);
+        let master_fingerprint = crypto::fingerprint(&master.public_key());
+        Ok(Store {
+            keys: EntryKeys::of(&keypair, &db),
+            recipient: recipients::find(&db, &master_fingerprint)?.is_some(),
+            db,
+            master_fingerprint,
+            blobs,
+            signed: false,
+        })
+    }
+
+    /**
+     * Directory holding the store files inside data_dir
+     */
+    pub fn store_path(data_dir: &Path) -> String {
+        data_dir.join("data").display().to_string()
+    }
+
+    /**
+     * Directory holding the chunks of the files saved with put_file, shared by all the stores
+     */
+    pub fn blob_path(data_dir: &Path) -> PathBuf {
+        data_dir.join("blobs")
+    }
+
+    pub fn name(&self) -> &str {
+        &self.db.name
+    }
+
+    /**
+     * If the store was written before MACs existed and got its first one when it was opened:
+     * from then on, changes made to its file outside of the Store are noticed
+     */
+    pub fn was_signed(&self) -> bool {
+        self.signed
+    }
+
+    /**
+     * Reads and decrypts a value. Expired values are not returned.
+     * Keys saved with put_file return a description of the file, read it with get_file
+     */
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        let entry_key = self.keys.entry_key(key);
+        let value = self.db.read(entry_key.to_string());
+        let missing = match self.keys.is_plaintext() {
+            // an empty value is saved as it is
+            true => self.db.get_record(&entry_key).is_none_or(|record| record.is_expired(db::now())),
+            false => value.is_empty(),
+        };
+        if missing {
+            return Ok(None);
+        }
+        if let Some(size) = self.db.get_record(&entry_key).and_then(|record| record.blob) {
+            return Ok(Some(describe_file(size)));
+        }
+        self.keys.open(&entry_key, &value).map(|(_, value)| Some(value))
+    }
+
+    /**
+     * Saves everything read from reader as the value of key. The contents are split in chunks,
+     * encrypted and deduplicated in the blob directory, the store only keeps a reference to them.
+     * Returns the size of the file
+     */
+    pub fn put_file(&mut self, key: &str, reader: &mut impl Read) -> Result<u64> {
+        if self.db.engine() == Engine::Memory {
+            return Err(Error::Config("files can't be saved in a memory store".to_string()));
+        }
+        let (address, size) = self.blobs.put(reader)?;
+        let mut record = Record::new(self.keys.seal_file(key, &address)?);
+        record.blob = Some(size);
+        if let Err(e) = self.db.insert_record(self.keys.entry_key(key), record) {
+            // a file over the limits leaves nothing behind, unless a store already had its contents
+            let written = self.blobs.references(&address)?.into_iter().collect();
+            blob::release(self.blobs.dir(), &store_dir(&self.db), written)?;
+            return Err(e);
+        }
+        Ok(size)
+    }
+
+    /**
+     * Streams a file saved with put_file, decrypting one chunk at a time.
+     * None if key isn't there or isn't a file
+     */
+    pub fn get_file(&self, key: &str) -> Result<Option<BlobReader>> {
+        let entry_key = self.keys.entry_key(key);
+        match self.db.get_record(&entry_key) {
+            Some(record) if record.blob.is_some() && !record.is_expired(db::now()) => {
+                let (_, address) = self.keys.open_file(&entry_key, &record)?;
+                self.blobs.open(&address).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /**
+     * If key was saved with put_file
+     */
+    pub fn is_file(&self, key: &str) -> bool {
+        self.record(key).map(|record| record.blob.is_some()).unwrap_or(false)
+    }
+
+    /**
+     * The record of key with its attributes, like its expiry, even if it expired.
+     * Its value is as stored, encrypted
+     */
+    pub fn record(&self, key: &str) -> Option<Record> {
+        self.db.get_record(&self.keys.entry_key(key))
+    }
+
+    /**
+     * The name key is saved under in the store file: itself, or its keyed hash with blind keys
+     */
+    pub fn entry_key(&self, key: &str) -> String {
+        self.keys.entry_key(key)
+    }
+
+    /**
+     * The name key is logged under in the audit log: its keyed hash when the store hides its
+     * names, with blind keys or sealed, so the log doesn't reveal them
+     */
+    pub fn logged_key(&self, key: &str) -> String {
+        match self.keys.is_blind() || self.db.is_sealed() {
+            true => self.keys.hashed_key(key),
+            false => key.to_string(),
+        }
+    }
+
+    /**
+     * Sets the unix timestamp after which key expires
+     */
+    pub fn expire(&mut self, key: &str, expires: u64) {
+        self.db.expire(&self.keys.entry_key(key), expires);
+    }
+
+    /**
+     * Gives key the expiry and the time of the last write of record, like when it's copied from another store
+     */
+    pub fn copy_attributes(&mut self, key: &str, from: &Record) -> Result<()> {
+        let entry_key = self.keys.entry_key(key);
+        match self.db.get_record(&entry_key) {
+            Some(mut record) => {
+                record.expires = from.expires;
+                record.modified = from.modified;
+                self.db.insert_record(entry_key, record)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /**
+     * Encrypts and saves a value
+     */
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let encrypted = self.keys.seal(key, value)?;
+        self.db.insert(self.keys.entry_key(key), encrypted)?;
+        Ok(())
+    }
+
+    /**
+     * Encrypts and saves a value that expires after ttl seconds
+     */
+    pub fn set_with_ttl(&mut self, key: &str, value: &str, ttl: u64) -> Result<()> {
+        self.set(key, value)?;
+        self.expire(key, db::now() + ttl);
+        Ok(())
+    }
+
+    /**
+     * Removes a key, returning if it was there
+     */
+    pub fn delete(&mut self, key: &str) -> bool {
+        self.db.delete(&self.keys.entry_key(key))
+    }
+
+    /**
+     * Iterates over the decrypted key-value pairs sorted by key, as they were when iter was called
+     */
+    pub fn iter(&self) -> impl Iterator<Item = Result<(String, String)>> {
+        self.snapshot().into_iter()
+    }
+
+    /**
+     * Read-only view of the store as it is now. Later writes are not seen by it,
+     * and it can be read without access to the Store
+     */
+    pub fn snapshot(&self) -> StoreSnapshot {
+        StoreSnapshot {
+            snapshot: self.db.snapshot(),
+            keys: self.keys.clone(),
+        }
+    }
+
+    /**
+     * Number of keys that haven't expired
+     */
+    pub fn len(&self) -> usize {
+        self.db.live_count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /**
+     * Fingerprint of the master key the store is opened with
+     */
+    pub fn fingerprint(&self) -> String {
+        self.master_fingerprint.to_string()
+    }
+
+    /**
+     * Fingerprint of the key encrypting the values of this store, the master key for older stores
+     */
+    pub fn data_key_fingerprint(&self) -> String {
+        crypto::fingerprint(&self.keys.keypair.0)
+    }
+
+    /**
+     * If key names are saved as a keyed hash instead of as they are
+     */
+    pub fn blind_keys(&self) -> bool {
+        self.keys.is_blind()
+    }
+
+    /**
+     * Blinds the key names of the store, or saves them as they are again, rewriting every entry
+     */
+    pub fn set_blind_keys(&mut self, blind: bool) -> Result<()> {
+        if blind == self.keys.is_blind() {
+            return Ok(());
+        }
+        if self.keys.is_plaintext() {
+            return Err(Error::Config(format!(
+                "store '{}' is plaintext, its key names can't be blinded",
+                self.name()
+            )));
+        }
+        let keys = EntryKeys::new(&self.keys.keypair, blind);
+        reencrypt(&mut self.db, &self.keys, &keys, None)?;
+        match blind {
+            true => self.db.set_meta(META_BLIND_KEYS, "1".to_string()),
+            false => self.db.remove_meta(META_BLIND_KEYS),
+        }
+        self.keys = keys;
+        Ok(())
+    }
+
+    /**
+     * If values are saved as they are instead of encrypted
+     */
+    pub fn plaintext(&self) -> bool {
+        self.keys.is_plaintext()
+    }
+
+    /**
+     * Saves the values of the store as they are, or encrypts them again, rewriting every entry.
+     * Files saved with put_file stay encrypted in the blob directory, and the store keeps its MAC,
+     * so values edited outside of gui-kvstore are still caught
+     */
+    pub fn set_plaintext(&mut self, plaintext: bool) -> Result<()> {
+        if plaintext == self.keys.is_plaintext() {
+            return Ok(());
+        }
+        if self.keys.is_blind() {
+            return Err(Error::Config(format!(
+                "store '{}' has blind keys, turn them off before saving its values as plaintext",
+                self.name()
+            )));
+        }
+        let keys = self.keys.with_plaintext(plaintext);
+        reencrypt(&mut self.db, &self.keys, &keys, None)?;
+        match plaintext {
+            true => self.db.set_meta(META_PLAINTEXT, "1".to_string()),
+            false => self.db.remove_meta(META_PLAINTEXT),
+        }
+        self.keys = keys;
+        Ok(())
+    }
+
+    /**
+     * If the store file is encrypted whole, hiding the number of keys and the size of the values
+     */
+    pub fn sealed(&self) -> bool {
+        self.db.is_sealed()
+    }
+
+    /**
+     * Seals the store file with the data key from the next flush on, or writes it in plain text again
+     */
+    pub fn set_sealed(&mut self, sealed: bool) {
+        if sealed != self.db.is_sealed() {
+            self.db.set_seal(Some(&self.keys.keypair).filter(|_| sealed));
+        }
+    }
+
+    /**
+     * The people the store is shared with, besides the owner of the master key
+     */
+    pub fn recipients(&self) -> Result<Vec<Recipient>> {
+        recipients::read(&self.db)
+    }
+
+    /**
+     * Shares the store with the holder of the private key of public_key, by wrapping the data key for it
+     */
+    pub fn add_recipient(&mut self, label: &str, public_key: [u8; 32]) -> Result<Recipient> {
+        self.check_owner("add recipients to")?;
+        if self.data_key_fingerprint() == self.master_fingerprint {
+            return Err(Error::Config(format!(
+                "store '{}' is encrypted with the master key, run --rotate-key to give it a data key first",
+                self.name()
+            )));
+        }
+        let recipient = Recipient::new(label, public_key, &self.keys.keypair)?;
+        let mut all = self.recipients()?;
+        if recipient.fingerprint() == self.master_fingerprint
+            || all.iter().any(|r| r.is(label) || r.public_key == public_key)
+        {
+            return Err(Error::Config(format!(
+                "store '{}' is already shared with '{}' ({})",
+                self.name(),
+                label,
+                recipient.fingerprint()
+            )));
+        }
+        all.push(recipient.clone());
+        recipients::write(&mut self.db, &all);
+        Ok(recipient)
+    }
+
+    /**
+     * Stops sharing the store with a recipient, given by name or fingerprint. They could have kept
+     * the data key, so the store gets a new one and every value and file is re-encrypted with it.
+     * master is the key the store was opened with
+     */
+    pub fn remove_recipient(&mut self, who: &str, master: &dyn MasterKey) -> Result<Recipient> {
+        self.check_owner("remove recipients from")?;
+        let mut all = self.recipients()?;
+        let position = all.iter().position(|r| r.is(who)).ok_or_else(|| {
+            Error::Config(format!("store '{}' is not shared with '{}'", self.name(), who))
+        })?;
+        let removed = all.remove(position);
+        recipients::write(&mut self.db, &all);
+        self.rekey(master)?;
+        Ok(removed)
+    }
+
+    /**
+     * recipients can read and write the store, but only the owner can wrap the data key for its master key
+     */
+    fn check_owner(&self, action: &str) -> Result<()> {
+        if self.recipient {
+            return Err(Error::KeyMissing(format!(
+                "store '{}' belongs to key {}, only its owner can {} it",
+                self.name(),
+                self.db.get_meta("fingerprint").map(|f| f.as_str()).unwrap_or("unknown"),
+                action
+            )));
+        }
+        Ok(())
+    }
+
+    /**
+     * gives the store a new data key, re-encrypting its entries and files, wrapped for master
+     * and every recipient
+     */
+    fn rekey(&mut self, master: &dyn MasterKey) -> Result<()> {
+        let keypair = KeyPair::from(crypto::generate_key_pair()?);
+        let keys = self.keys.rekeyed(&keypair);
+        let blobs = self.blobs.with_keypair(&keypair);
+        reencrypt(&mut self.db, &self.keys, &keys, Some((&self.blobs, &blobs)))?;
+        self.db.set_meta(META_DATA_KEY, wrap_data_key(&master.public_key(), &keypair)?);
+        recipients::rewrap(&mut self.db, &keypair)?;
+        self.db.rekey_integrity(&keypair);
+        if self.db.is_sealed() {
+            self.db.set_seal(Some(&keypair));
+        }
+        self.keys = keys;
+        self.blobs = blobs;
+        Ok(())
+    }
+
+    /**
+     * Limits on the keys and sizes of the store
+     */
+    pub fn limits(&self) -> Limits {
+        self.db.limits()
+    }
+
+    /**
+     * Saves new limits in the store header. Caches evict entries right away to fit them
+     */
+    pub fn set_limits(&mut self, limits: Limits) -> Result<()> {
+        self.db.set_limits(limits)
+    }
+
+    /**
+     * Size analytics of the store, telling the values saved encrypted from the ones saved as they are,
+     * like the values of plaintext stores and the addresses of files
+     */
+    pub fn stats(&self) -> Result<StoreStats> {
+        self.db.stats(|record| self.keys.is_encrypted(record))
+    }
+
+    /**
+     * The underlying db, for store level operations like info
+     */
+    pub fn database(&self) -> &Database {
+        &self.db
+    }
+
+    #[cfg(test)]
+    pub(crate) fn database_mut(&mut self) -> &mut Database {
+        &mut self.db
+    }
+
+    /**
+     * Writes pending changes to the store file
+     */
+    pub fn flush(&mut self) -> Result<()> {
+        self.stamp();
+        flush_with_refs(&mut self.db, &self.keys, &self.blobs)
+    }
+
+    /**
+     * stamps a changed store with the master key of its data key, right before it's written.
+     * stores that are only read are left as they are. recipients leave the owner there
+     */
+    fn stamp(&mut self) {
+        if self.db.is_dirty() && !self.recipient {
+            self.db.set_meta("fingerprint", self.master_fingerprint.to_string());
+        }
+    }
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        // the db would flush itself when dropped, the objects the store stops using are released here too
+        if self.db.is_dirty() {
+            let _ = self.flush();
+        }
+        self.keys.keypair.zeroize();
+    }
+}
+
+/**
+ * A Store frozen at one point in time, see Store::snapshot
+ */
+pub struct StoreSnapshot {
+    snapshot: Snapshot,
+    keys: EntryKeys,
+}
+
+impl StoreSnapshot {
+    /**
+     * Reads and decrypts a value as it was when the snapshot was taken
+     */
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        let entry_key = self.keys.entry_key(key);
+        match self.snapshot.get(&entry_key) {
+            Some(record) if !record.is_expired(db::now()) => {
+                decrypt_record(&self.keys, &entry_key, &record).map(|(_, value)| Some(value))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /**
+     * Keys in the snapshot that haven't expired, sorted. Blinded names are decrypted,
+     * the ones that can't be are left out: try_keys returns the error instead
+     */
+    pub fn keys(&self) -> Vec<String> {
+        match self.keys.is_blind() {
+            true => self.iter_sorted().filter_map(|pair| pair.ok()).map(|(key, _)| key).collect(),
+            false => self.stored_keys(),
+        }
+    }
+
+    /**
+     * Same as keys, failing when the name of a blinded entry can't be decrypted
+     */
+    pub fn try_keys(&self) -> Result<Vec<String>> {
+        if !self.keys.is_blind() {
+            return Ok(self.stored_keys());
+        }
+        self.iter_sorted().map(|pair| pair.map(|(key, _)| key)).collect()
+    }
+
+    /**
+     * names of the entries that haven't expired, as saved
+     */
+    fn stored_keys(&self) -> Vec<String> {
+        let now = db::now();
+        self.snapshot
+            .records()
+            .into_iter()
+            .filter(|(_, record)| !record.is_expired(now))
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    /**
+     * decrypts every entry and sorts them by name, which blinded entries are not stored by.
+     * entries that fail to decrypt come last
+     */
+    fn iter_sorted(&self) -> std::vec::IntoIter<Result<(String, String)>> {
+        let now = db::now();
+        let mut pairs: Vec<Result<(String, String)>> = self
+            .snapshot
+            .records()
+            .into_iter()
+            .filter(|(_, record)| !record.is_expired(now))
+            .map(|(entry_key, record)| decrypt_record(&self.keys, &entry_key, &record))
+            .collect();
+        pairs.sort_by(|a, b| match (a, b) {
+            (Ok((a, _)), Ok((b, _))) => a.cmp(b),
+            (Ok(_), Err(_)) => std::cmp::Ordering::Less,
+            (Err(_), Ok(_)) => std::cmp::Ordering::Greater,
+            (Err(_), Err(_)) => std::cmp::Ordering::Equal,
+        });
+        pairs.into_iter()
+    }
+}
+
+impl IntoIterator for StoreSnapshot {
+    type Item = Result<(String, String)>;
+    type IntoIter = StoreSnapshotIter;
+
+    fn into_iter(self) -> StoreSnapshotIter {
+        let entries = match self.keys.is_blind() {
+            true => Entries::Sorted(self.iter_sorted()),
+            false => Entries::Stored(self.snapshot.into_iter()),
+        };
+        StoreSnapshotIter {
+            entries,
+            keys: self.keys,
+            now: db::now(),
+        }
+    }
+}
+
+/**
+ * Decrypting iterator over a StoreSnapshot
+ */
+pub struct StoreSnapshotIter {
+    entries: Entries,
+    keys: EntryKeys,
+    now: u64,
+}
+
+enum Entries {
+    // records decrypted one at a time, already sorted by name
+    Stored(mvcc::SnapshotIter),
+    // blinded entries, decrypted up front to sort them by name
+    Sorted(std::vec::IntoIter<Result<(String, String)>>),
+}
+
+impl Iterator for StoreSnapshotIter {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Result<(String, String)>> {
+        let now = self.now;
+        match &mut self.entries {
+            Entries::Stored(records) => {
+                let (key, record) = records.by_ref().find(|(_, record)| !record.is_expired(now))?;
+                Some(decrypt_record(&self.keys, &key, &record))
+            }
+            Entries::Sorted(pairs) => pairs.next(),
+        }
+    }
+}
+
+/**
+ * the data key of a store, unwrapped with the master key of its owner or of one of its recipients.
+ * None if the store doesn't have one
+ */
+pub(crate) fn data_key(db: &Database, master: &dyn MasterKey) -> Result<Option<KeyPair>> {
+    let fingerprint = crypto::fingerprint(&master.public_key());
+    let recipient = recipients::find(db, &fingerprint)?;
+    let wrapped = match (&recipient, db.get_meta(META_DATA_KEY)) {
+        (Some(recipient), _) => recipient.wrapped_key(),
+        (None, Some(wrapped)) => wrapped.as_str(),
+        (None, None) => return Ok(None),
+    };
+    let bytes = crypto::decode_hex(wrapped)
+        .map_err(|_| Error::Corrupt(format!("the data key of store '{}' is not hex encoded", db.name)))?;
+    let unwrapped = master.unwrap_key(&bytes).map_err(|e| match e {
+        Error::Crypto(_) => Error::Crypto(format!(
+            "the data key of store '{}' can't be decrypted with key {}, it was written with key {}",
+            db.name,
+            fingerprint,
+            db.get_meta("fingerprint").map(|f| f.as_str()).unwrap_or("unknown")
+        )),
+        e => e,
+    })?;
+    if unwrapped.len() != 64 {
+        return Err(Error::Corrupt(format!("the data key of store '{}' is incomplete", db.name)));
+    }
+    let mut keypair = KeyPair::default();
+    keypair.0.copy_from_slice(&unwrapped[..32]);
+    keypair.1.copy_from_slice(&unwrapped[32..]);
+    Ok(Some(keypair))
+}
+
+/**
+ * the blob objects the live entries of db reference: the manifests of its files and their chunks
+ */
+fn blob_refs(db: &Database, keys: &EntryKeys, blobs: &BlobStore) -> Result<BTreeSet<String>> {
+    let mut refs = BTreeSet::new();
+    for (entry_key, record) in db.live_records() {
+        if record.blob.is_some() {
+            let (_, address) = keys.open_file(&entry_key, &record)?;
+            refs.extend(blobs.references(&address)?);
+        }
+    }
+    Ok(refs)
+}
+
+fn store_dir(db: &Database) -> PathBuf {
+    Path::new(db.file_path()).parent().unwrap_or_else(|| Path::new(".")).to_path_buf()
+}
+
+/**
+ * flushes db and updates the list of the objects it references, deleting the ones no store uses
+ * anymore, like the files of deleted or overwritten entries, or the ones encrypted with a previous
+ * data key. the new objects are listed before the flush, so they are never left unlisted
+ */
+pub(crate) fn flush_with_refs(db: &mut Database, keys: &EntryKeys, blobs: &BlobStore) -> Result<()> {
+    if db.engine() == Engine::Memory || db.is_read_only() || !db.is_dirty() {
+        return db.flush();
+    }
+    let old = blob::read_refs(db.file_path())?;
+    let new = blob_refs(db, keys, blobs)?;
+    if old.as_ref() == Some(&new) {
+        return db.flush();
+    }
+    let old = old.unwrap_or_default();
+    blob::write_refs(db.file_path(), &old.union(&new).cloned().collect())?;
+    db.flush()?;
+    blob::write_refs(db.file_path(), &new)?;
+    blob::release(blobs.dir(), &store_dir(db), old.difference(&new).cloned().collect())
+}
+
+/**
+ * the master key itself, for stores from before data keys
+ */
+fn legacy_key(db: &Database, master: &dyn MasterKey) -> Result<KeyPair> {
+    master.keypair().cloned().ok_or_else(|| {
+        Error::KeyMissing(format!(
+            "store '{}' is encrypted with the master key itself, which the key agent doesn't give out. \
+             Run --rotate-key to give it a data key",
+            db.name
+        ))
+    })
+}
+
+/**
+ * checks that db was written with master, or is shared with it, before anything is decrypted
+ */
+fn check_fingerprint(db: &Database, master: &dyn MasterKey) -> Result<()> {
+    let fingerprint = crypto::fingerprint(&master.public_key());
+    match db.get_meta("fingerprint") {
+        Some(written_with) if *written_with != fingerprint && recipients::find(db, &fingerprint)?.is_none() => {
+            Err(Error::KeyMissing(format!(
+                "store '{}' was written with key {}, but the current key is {}. \
+                 Import that key with --key import, or use its archived copy if it was rotated",
+                db.name, written_with, fingerprint
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/**
+ * encrypts a data key with the public key of a master key, as saved in the store header
+ */
+pub(crate) fn wrap_data_key(public_key: &[u8; 32], keypair: &KeyPair) -> Result<String> {
+    let wrapped = crypto::encrypt(public_key, &Zeroizing::new([keypair.0, keypair.1].concat()))?;
+    Ok(crypto::encode_hex(&wrapped))
+}
+
+/**
+ * rewrites every live entry of db from the old keys to the new ones, moving blinded entries
+ * under their new name. with blobs, (old, new), files are saved again with the new data key
+ */
+pub(crate) fn reencrypt(
+    db: &mut Database,
+    old: &EntryKeys,
+    new: &EntryKeys,
+    blobs: Option<(&BlobStore, &BlobStore)>,
+) -> Result<()> {
+    for (entry_key, record) in db.live_records() {
+        let (key, value) = match record.blob {
+            Some(_) => {
+                let (key, address) = old.open_file(&entry_key, &record)?;
+                let address = match blobs {
+                    // chunks are named after a hash keyed by the private key, so files are saved again
+                    Some((old_blobs, new_blobs)) => new_blobs.put(&mut old_blobs.open(&address)?)?.0,
+                    None => address,
+                };
+                let sealed = new.seal_file(&key, &address)?;
+                (key, sealed)
+            }
+            None => {
+                let (key, value) = old.open(&entry_key, &record.value)?;
+                let sealed = new.seal(&key, &value)?;
+                (key, sealed)
+            }
+        };
+        db.replace_entry(&entry_key, new.entry_key(&key), value);
+    }
+    Ok(())
+}
+
+/**
+ * decrypts the name and value of a record, files are described instead
+ */
+fn decrypt_record(keys: &EntryKeys, entry_key: &str, record: &Record) -> Result<(String, String)> {
+    match record.blob {
+        Some(size) => keys.open_file(entry_key, record).map(|(key, _)| (key, describe_file(size))),
+        None => keys.open(entry_key, &record.value),
+    }
+}
+
+/**
+ * what get and iter return for files saved with put_file
+ */
+fn describe_file(size: u64) -> String {
+    format!("<file, {} bytes>", size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn store_api() {
+        let data_dir = TempDir::new("store");
+        {
+            let mut store = Store::open(&data_dir, "test").unwrap();
+            store.set("key", "value").unwrap();
+            store.set("other", "other value").unwrap();
+            assert!(store.delete("other"));
+            assert!(!store.delete("other"));
+        }
+        let store = Store::open(&data_dir, "test").unwrap();
+        assert_eq!(store.get("key").unwrap(), Some("value".to_string()));
+        assert_eq!(store.get("other").unwrap(), None);
+        let pairs: Vec<(String, String)> = store.iter().collect::<Result<_>>().unwrap();
+        assert_eq!(pairs, vec![("key".to_string(), "value".to_string())]);
+        drop(store);
+        // a snapshot keeps reading the old values while the store changes
+        let mut store = Store::open(&data_dir, "test").unwrap();
+        let snapshot = store.snapshot();
+        store.set("key", "new value").unwrap();
+        store.set("added", "value").unwrap();
+        assert_eq!(snapshot.get("key").unwrap(), Some("value".to_string()));
+        assert_eq!(snapshot.keys(), vec!["key"]);
+        assert_eq!(store.get("key").unwrap(), Some("new value".to_string()));
+        assert_eq!(store.iter().count(), 2);
+        drop(snapshot);
+        // files are streamed back, reading them as values only describes them
+        let contents = vec![7u8; 3000];
+        assert_eq!(store.put_file("file", &mut contents.as_slice()).unwrap(), 3000);
+        assert!(store.is_file("file"));
+        assert_eq!(store.get("file").unwrap(), Some("<file, 3000 bytes>".to_string()));
+        let mut read = vec![];
+        store.get_file("file").unwrap().unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, contents);
+        assert!(store.get_file("key").unwrap().is_none());
+        drop(store);
+        // every store has a data key of its own, the master key is only used to unwrap them
+        let (store, other) = (Store::open(&data_dir, "test").unwrap(), Store::open(&data_dir, "other").unwrap());
+        assert_ne!(store.data_key_fingerprint(), other.data_key_fingerprint());
+        assert_ne!(store.data_key_fingerprint(), store.fingerprint());
+        assert_eq!(store.fingerprint(), other.fingerprint());
+        assert!(matches!(
+            data_key(store.database(), &KeyPair::from(crypto::generate_key_pair().unwrap())),
+            Err(Error::Crypto(_))
+        ));
+    }
+
+    #[test]
+    fn reads_leave_the_file_alone() {
+        let data_dir = TempDir::new("store-reads");
+        let master = keys::load_or_create(&data_dir).unwrap();
+        // a store from before fingerprints and data keys, its values encrypted with the master key
+        {
+            let mut db = Database::open("legacy".to_string(), Store::store_path(&data_dir), false, Engine::File).unwrap();
+            db.insert("key".to_string(), crypto::encrypt_string(&master.0, "value".to_string()).unwrap())
+                .unwrap();
+            Database::open("empty".to_string(), Store::store_path(&data_dir), false, Engine::File).unwrap();
+        }
+        let file = |name: &str| data_dir.join("data").join(format!("{}.db", name));
+        // they get a MAC the first time they're opened, and only then
+        for name in ["legacy", "empty"] {
+            assert!(Store::open_read_only(&data_dir, name, false, &master).is_ok());
+            assert!(Store::open(&data_dir, name).unwrap().was_signed());
+            assert!(!Store::open(&data_dir, name).unwrap().was_signed());
+        }
+        let (legacy, empty) = (std::fs::read(file("legacy")).unwrap(), std::fs::read(file("empty")).unwrap());
+        let store = Store::open(&data_dir, "legacy").unwrap();
+        assert_eq!(store.get("key").unwrap(), Some("value".to_string()));
+        drop(store);
+        assert!(Store::open(&data_dir, "empty").unwrap().is_empty());
+        assert_eq!(std::fs::read(file("legacy")).unwrap(), legacy);
+        assert_eq!(std::fs::read(file("empty")).unwrap(), empty);
+        // the first write stamps them with the key that wrote them
+        let mut store = Store::open(&data_dir, "empty").unwrap();
+        store.set("key", "value").unwrap();
+        drop(store);
+        let store = Store::open(&data_dir, "empty").unwrap();
+        assert_eq!(store.database().get_meta("fingerprint"), Some(&store.fingerprint()));
+        assert_eq!(store.get("key").unwrap(), Some("value".to_string()));
+    }
+
+    #[test]
+    fn file_objects() {
+        let data_dir = TempDir::new("store-files");
+        let objects = || {
+            let mut objects = vec![];
+            for folder in std::fs::read_dir(Store::blob_path(&data_dir)).into_iter().flatten() {
+                for object in std::fs::read_dir(folder.unwrap().path()).unwrap() {
+                    objects.push(object.unwrap().path());
+                }
+            }
+            objects
+        };
+        // files count with their size against the limits, not with the address the store keeps
+        let mut store = Store::open(&data_dir, "files").unwrap();
+        let limits = Limits {
+            max_value_bytes: Some(1000),
+            ..Default::default()
+        };
+        store.set_limits(limits).unwrap();
+        assert!(matches!(store.put_file("big", &mut &[1u8; 3000][..]), Err(Error::Quota(_))));
+        assert!(objects().is_empty());
+        store.set_limits(Limits::default()).unwrap();
+        store.put_file("file", &mut &[1u8; 3000][..]).unwrap();
+        store.flush().unwrap();
+        let first = objects();
+        assert_eq!(first.len(), 2);
+        // overwriting a file deletes the objects of the old contents
+        store.put_file("file", &mut &[2u8; 3000][..]).unwrap();
+        store.flush().unwrap();
+        assert_eq!(objects().len(), 2);
+        assert!(first.iter().all(|object| !object.exists()));
+        // objects another store references are kept until it stops using them too
+        let mut other = Store::open(&data_dir, "other").unwrap();
+        other.put_file("copy", &mut &[2u8; 3000][..]).unwrap();
+        drop(other);
+        assert!(store.delete("file"));
+        drop(store);
+        assert_eq!(objects().len(), 2);
+        Database::drop_store(&Store::store_path(&data_dir), "other").unwrap();
+        assert!(objects().is_empty());
+    }
+
+    #[test]
+    fn concurrent_writers() {
+        let data_dir = TempDir::new("store-locked");
+        let master = keys::load_or_create(&data_dir).unwrap();
+        // every writer reads the counter and writes it back incremented, none of them is lost
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                let (data_dir, master) = (data_dir.to_path_buf(), master.clone());
+                std::thread::spawn(move || {
+                    for _ in 0..5 {
+                        let mut store = Store::open_locked(&data_dir, "counter", Engine::File, false, &master).unwrap();
+                        let count: u32 = store.get("count").unwrap().map(|c| c.parse().unwrap()).unwrap_or(0);
+                        store.set("count", &(count + 1).to_string()).unwrap();
+                        store.flush().unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let store = Store::open(&data_dir, "counter").unwrap();
+        assert_eq!(store.get("count").unwrap(), Some("20".to_string()));
+    }
+}
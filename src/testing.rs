@@ -0,0 +1,47 @@
+use std::fmt;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/**
+ * A folder for the files of a test, named after it and the process, deleted when it's dropped.
+ * Declared before the stores in it, it's dropped after them, and also when an assertion fails
+ */
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    /**
+     * an empty folder for the test called name. nothing is created until something is written in it
+     */
+    pub fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("gui-kvstore-{}-{}", name, std::process::id()));
+        // left by a run that was killed
+        let _ = std::fs::remove_dir_all(&path);
+        TempDir(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl fmt::Display for TempDir {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.display())
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}